@@ -75,6 +75,10 @@ class Handler:
         self.max_dist_size = config["max_dist_size"]
         self.max_num_embeds = config["max_num_embeds"]
         self.max_batch_tokens = config["max_batch_tokens"]
+        # An explicit limit wins over the model's own context window; 0 means unknown.
+        self.max_context_length = (
+            config.get("max_context_length") or self.model_info.max_context_length or 0
+        )
         self.max_num_adapters = config["max_num_adapters"]
         self.max_adapter_rank = config["max_adapter_rank"]
         self.dtype = getattr(torch, config["dtype"])
@@ -254,6 +258,7 @@ class Handler:
                 tokenizer_special_tokens=self.model_info.tokenizer.special_tokens,
                 tokenizer_split_regex=self.model_info.tokenizer.split_regex,
                 tokenizer_escape_non_printable=self.model_info.tokenizer.escape_non_printable,
+                max_context_length=self.max_context_length,
             )
             resps.append(resp)
         return resps
@@ -308,6 +313,24 @@ class Handler:
             if req.adapter_ptr in self.adapters:
                 pass
 
+    @torch.inference_mode()
+    def copy_kv_pages(self, reqs: list[message.CopyKvPagesRequest]):
+        """Copy the contents of KV pages into other pages, on every layer."""
+        for req in reqs:
+            for ptr in req.src_kv_page_ptrs + req.dst_kv_page_ptrs:
+                if ptr < 0 or ptr >= self.max_num_kv_pages:
+                    raise ValueError(
+                        f"KV page pointer {ptr} out of range [0, {self.max_num_kv_pages})."
+                    )
+            src = torch.tensor(
+                req.src_kv_page_ptrs, dtype=torch.long, device=self.device
+            )
+            dst = torch.tensor(
+                req.dst_kv_page_ptrs, dtype=torch.long, device=self.device
+            )
+            for kv_cache in self.kv_cache_at_layer:
+                kv_cache[dst] = kv_cache[src]
+
     @torch.inference_mode()
     def forward_pass(self, reqs: list[message.ForwardPassRequest]):
         """
@@ -521,6 +544,7 @@ class ForwardPassBatch:
                 params["min_p"] = sampler_config.get("min_p", 0.0)
 
             params["temperature"] = sampler_config.get("temperature", 1.0)
+            params["logits_fp32"] = sampler_config.get("logits_fp32", False)
             self.sampler_params.append(params)
 
         # Handle input tokens and positions
@@ -665,15 +689,20 @@ class ForwardPassBatch:
 
         logits = self._handler.lm.lm_head(logits_input)  # type: ignore[attr-defined, operator]
 
-        # Promote logits to handler dtype for numerically stable softmax on Metal/MPS
-        if logits.dtype != self.logits_dtype:
-            logits = logits.to(dtype=self.logits_dtype)
+        # Promote logits to handler dtype for numerically stable softmax on Metal/MPS.
+        # A single request asking for fp32 sampling math upcasts the whole batch, since
+        # the softmax and samplers below run over all rows at once.
+        sampling_dtype = self.logits_dtype
+        if any(p["logits_fp32"] for p in self.sampler_params):
+            sampling_dtype = torch.float32
+        if logits.dtype != sampling_dtype:
+            logits = logits.to(dtype=sampling_dtype)
 
         # Apply temperature scaling to all logits
         temperatures = torch.tensor(
             [p["temperature"] for p in self.sampler_params],
             device=self._handler.device,
-            dtype=self.logits_dtype,
+            dtype=sampling_dtype,
         ).unsqueeze(1)
         scaled_logits = logits / torch.clamp(temperatures, min=1e-6)
 
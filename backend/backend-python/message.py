@@ -39,6 +39,8 @@ class HandshakeResponse(msgspec.Struct, gc=False):
     tokenizer_special_tokens: dict[str, int]
     tokenizer_split_regex: str
     tokenizer_escape_non_printable: bool
+    # Maximum number of tokens a single context may hold, or 0 if unknown.
+    max_context_length: int = 0
 
 
 class QueryRequest(msgspec.Struct, gc=False):
@@ -129,6 +131,13 @@ class DownloadAdapterRequest(msgspec.Struct, gc=False):
     name: str
 
 
+class CopyKvPagesRequest(msgspec.Struct, gc=False):
+    """Request message for copying KV pages."""
+
+    src_kv_page_ptrs: list[int]
+    dst_kv_page_ptrs: list[int]
+
+
 class DownloadAdapterResponse(msgspec.Struct, gc=False):
     """Response message containing adapter data."""
 
@@ -57,6 +57,8 @@ class ModelInfo:
     template_type: str
     template_content: str
     stop_tokens: list[str]
+    # Context window the model was trained for, if the model config states it.
+    max_context_length: Optional[int] = None
 
     @staticmethod
     def load_from_file(
@@ -118,6 +120,7 @@ class ModelInfo:
             tokenizer=tokenizer,
             **metadata_dict,
             **template_dict,
+            max_context_length=arch_dict.get("max_position_embeddings"),
         )
 
 
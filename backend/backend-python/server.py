@@ -30,6 +30,7 @@ from websockets.sync.client import connect
 from profiler import initialize_memory_tracker
 
 from message import (
+    CopyKvPagesRequest,
     DownloadAdapterRequest,
     EmbedImageRequest,
     ForwardPassRequest,
@@ -56,6 +57,7 @@ class HandlerId(enum.Enum):
     UPDATE_ADAPTER = 6
     UPLOAD_HANDLER = 7
     DOWNLOAD_HANDLER = 8
+    COPY_KV_PAGES = 9
 
 
 def resolve_cache_dir(cache_dir: str | None) -> str:
@@ -365,6 +367,8 @@ def worker_thread(
                     handler.upload_handler(reqs)
                 case HandlerId.DOWNLOAD_HANDLER.value:
                     resps = handler.download_handler(reqs)
+                case HandlerId.COPY_KV_PAGES.value:
+                    handler.copy_kv_pages(reqs)
                 case HandlerId.HEARTBEAT.value:
                     raise RuntimeError(
                         "Heartbeat should not be handled by the worker thread"
@@ -425,6 +429,7 @@ def zmq_listen_thread(
         HandlerId.DOWNLOAD_HANDLER.value: msgspec.msgpack.Decoder(
             DownloadAdapterRequest
         ),
+        HandlerId.COPY_KV_PAGES.value: msgspec.msgpack.Decoder(CopyKvPagesRequest),
     }
 
     try:
@@ -496,6 +501,7 @@ def main(
     max_num_adapters: int = 48,
     max_adapter_rank: int = 8,
     max_num_kv_pages: int | None = None,
+    max_context_length: int | None = None,
     gpu_mem_headroom: float | None = None,
     device: str | None = None,
     dtype: str = "bfloat16",
@@ -520,6 +526,8 @@ def main(
         max_batch_tokens: Maximum number of tokens in a batch.
         max_num_adapters: Maximum number of adapters that can be loaded.
         max_adapter_rank: Maximum rank for any loaded adapter.
+        max_context_length: Maximum number of tokens a single context may hold.
+                            Defaults to the model's `max_position_embeddings`.
         device: The device to run the model on (e.g., 'mps', 'cuda:0', 'cpu').
                 Auto-detects to 'mps' on Apple Silicon, 'cuda:0' otherwise.
         dtype: The data type for model weights (e.g., 'bfloat16', 'float16').
@@ -554,6 +562,7 @@ def main(
         max_num_adapters=max_num_adapters,
         max_adapter_rank=max_adapter_rank,
         max_num_kv_pages=max_num_kv_pages,
+        max_context_length=max_context_length,
         gpu_mem_headroom=gpu_mem_headroom,
         device=device,
         dtype=dtype,
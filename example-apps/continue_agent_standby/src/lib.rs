@@ -1,10 +1,11 @@
 use inferlet::{
+    agent::MetaBuilder,
     forward::{Forward, KvPage},
     sampler::Sampler,
-    stop_condition::{max_len, ends_with_any, StopCondition},
-    Args, Queue, Result, Tokenizer, main, get_auto_model, store_set, store_get, Context
+    stop_condition::{max_len, StopCondition},
+    Args, Queue, Result, Tokenizer, main, get_auto_model, store_set_batch, store_get, Context
 };
-use serde::{Deserialize, Serialize};
+use serde::Deserialize;
 
 #[derive(Debug, Deserialize)]
 struct AgentInput {
@@ -13,12 +14,6 @@ struct AgentInput {
     prompt: String,
 }
 
-#[derive(Debug, Serialize, Deserialize)]
-struct AgentMeta {
-    token_ids: Vec<u32>,
-    kv_page_last_len: usize,
-}
-
 #[inferlet::main]
 async fn main(mut args: Args) -> Result<String> {
     eprintln!("[Debug] Bad Agent (Text-Only Mode) started.");
@@ -53,7 +48,7 @@ async fn main(mut args: Args) -> Result<String> {
 
     // 5. 执行推理
     let sampler = Sampler::top_k_top_p(0.6, 20, 0.95);
-    let stop_cond = max_len(1024).or(ends_with_any(model.eos_tokens()));
+    let stop_cond = model.default_stop_condition().or(max_len(1024));
     
     let generated_text = ctx.generate(sampler, stop_cond).await;
     eprintln!("[Debug] Generation complete. Length: {}", generated_text.len());
@@ -61,19 +56,22 @@ async fn main(mut args: Args) -> Result<String> {
     // 6. 保存状态
     let my_kv_key = format!("{}_kv", input.task_id);
     // 这里的 export 是安全的，因为这是 node_bad 私有的显存
-    ctx.queue().export_kv_pages(&ctx.kv_pages, &my_kv_key);
-
-    let my_meta = AgentMeta {
-        token_ids: ctx.get_token_ids().to_vec(),
-        kv_page_last_len: ctx.get_kv_page_last_len(),
-    };
-    store_set(&format!("{}_meta", input.task_id), &serde_json::to_string(&my_meta)?);
-    store_set(&format!("{}_output", input.task_id), &generated_text);
+    // 它重新计算了全部历史，因此作为一条新链的起点写入完整的 token 与 KV/元数据链
+    let my_meta_key = format!("{}_meta", input.task_id);
+    let my_meta = MetaBuilder::new()
+        .token_ids(ctx.get_token_ids().to_vec())
+        .state(ctx.state())
+        .push_kv(&my_kv_key)
+        .build(&my_meta_key)?;
+    // 导出 KV 后 ctx 的其余资源会被正常释放
+    ctx.into_exported(&my_kv_key);
+    // 元数据与输出一次性写入，避免读者看到只写了一半的任务
+    store_set_batch(&[
+        (my_meta_key, my_meta.to_json()?),
+        (format!("{}_output", input.task_id), generated_text.clone()),
+    ]);
 
     eprintln!("[Debug] State saved. Normal exit.");
-    
-    // 这里依然推荐 forget，这是良好的习惯，防止清理自己还要用的显存
-    std::mem::forget(ctx);
 
     Ok(generated_text)
 }
\ No newline at end of file
@@ -1,25 +1,22 @@
 use inferlet::{
-    forward::{Forward, KvPage},
+    agent::AgentMeta,
     sampler::Sampler,
-    stop_condition::{max_len, ends_with_any, StopCondition},
-    Args, Queue, Result, Tokenizer, main, get_auto_model, store_set, store_get, Context
+    stop_condition::{max_len, StopCondition},
+    Args, Queue, Result, Tokenizer, main, get_auto_model, get_model, store_set, store_get_many, Context
 };
-use serde::{Deserialize, Serialize};
+use serde::Deserialize;
 
 #[derive(Debug, Deserialize)]
 struct AgentInput {
     task_id: String,
     parent_task_ids: Vec<String>,
     prompt: String,
-}
-
-#[derive(Debug, Serialize, Deserialize)]
-struct AgentMeta {
-    token_ids: Vec<u32>,
-    kv_page_last_len: usize,
-    // Finale 也会读取这个链条
-    #[serde(default)] 
-    kv_chain: Vec<String>,
+    // 可选的备用模型：主模型中途失败时换到它上面继续生成
+    #[serde(default)]
+    fallback_model: Option<String>,
+    // 可选：只把最相关的若干份参考文本放进 prompt
+    #[serde(default)]
+    max_references: Option<usize>,
 }
 
 #[inferlet::main]
@@ -38,31 +35,37 @@ async fn main(mut args: Args) -> Result<String> {
     
     let base_id = &input.parent_task_ids[0];
     
-    // 1. 动态加载所有参考分支的文本
+    // 1. 动态加载所有参考分支的文本 (一次 host 调用取回全部)
+    let ref_ids = &input.parent_task_ids[1..];
+    let ref_keys: Vec<String> = ref_ids.iter().map(|id| format!("{}_output", id)).collect();
+    let ref_texts: Vec<String> = store_get_many(&ref_keys)
+        .into_iter()
+        .map(|text| text.unwrap_or_else(|| "[(Missing Data)]".to_string()))
+        .collect();
+
+    // 按与任务的相关度对参考文本排序，只保留最相关的 max_references 份
+    let model = get_auto_model();
+    let candidates: Vec<&str> = ref_texts.iter().map(String::as_str).collect();
+    let ranked = model.rerank(&input.prompt, &candidates).await;
+    let keep = input.max_references.unwrap_or(ranked.len());
+
     let mut references_text = String::new();
-    for (idx, ref_id) in input.parent_task_ids.iter().skip(1).enumerate() {
-        let ref_key = format!("{}_output", ref_id);
-        let text = store_get(&ref_key).unwrap_or_else(|| "[(Missing Data)]".to_string());
-        
+    for (idx, &(ref_idx, _)) in ranked.iter().take(keep).enumerate() {
         // 格式化拼接到 prompt 中
         use std::fmt::Write;
-        write!(references_text, "\n=== Perspective {} (Source: {}) ===\n{}\n", idx + 1, ref_id, text).ok();
+        write!(references_text, "\n=== Perspective {} (Source: {}) ===\n{}\n", idx + 1, ref_ids[ref_idx], ref_texts[ref_idx]).ok();
     }
 
     eprintln!("[Debug] Loaded {} reference texts.", input.parent_task_ids.len() - 1);
 
     // 2. 加载 Base 的元数据 (和之前一样)
     let base_meta_key = format!("{}_meta", base_id);
-    let meta_json = store_get(&base_meta_key)
-        .ok_or_else(|| anyhow::anyhow!("Base meta not found"))?;
-    let mut meta: AgentMeta = serde_json::from_str(&meta_json)?;
+    let meta = AgentMeta::load(&base_meta_key)?;
+    let base_token_ids = meta.load_token_ids(&base_meta_key)?;
 
     // 3. 重建 KV 链条 (The Chain of Memory)
-    let model = get_auto_model();
     let queue = model.create_queue();
-    
-    let mut all_kv_pages: Vec<KvPage> = Vec::new();
-    
+
     // 如果上游 Good 成功生成了 chain，我们就用 chain
     // 如果是旧代码遗留，我们做个兼容
     let mut load_list = meta.kv_chain.clone();
@@ -72,19 +75,22 @@ async fn main(mut args: Args) -> Result<String> {
     }
 
     eprintln!("[Debug] Reconstructing memory from chain: {:?}", load_list);
-    for key in &load_list {
-        let mut pages = queue.import_kv_pages(key);
-        eprintln!("[Debug]  -> Loaded {} pages from {}", pages.len(), key);
-        all_kv_pages.append(&mut pages);
-    }
-
-    // 4. 恢复上下文
-    let mut ctx = Context::from_imported_state(
+    // 4. 恢复上下文：按顺序导入整条链并拼接，缺失的 key 或页数对不上时直接报错
+    let keys: Vec<&str> = load_list.iter().map(String::as_str).collect();
+    let mut ctx = Context::from_multiple_imports(
         &model,
-        all_kv_pages,
-        meta.token_ids,
-        meta.kv_page_last_len,
-    );
+        &queue,
+        &keys,
+        base_token_ids,
+        meta.state.last_len,
+    )?;
+    // 导入的页与导出者共享显存；最后一页未满时新 token 会写进它的空位，
+    // 因此换成独立副本，避免改坏父节点导出的页
+    if meta.state.last_len < model.get_kv_page_size() as usize {
+        if let Some(last_page) = ctx.kv_pages.pop() {
+            ctx.kv_pages.push(last_page.deep_copy(&queue).await);
+        }
+    }
 
     // 5. 混合 Prompt
     let hybrid_prompt = format!(
@@ -95,11 +101,15 @@ async fn main(mut args: Args) -> Result<String> {
     ctx.fill_user(&hybrid_prompt);
 
     // 6. 生成
-    let sampler = Sampler::top_k_top_p(0.6, 20, 0.95);
-    let stop_cond = max_len(1024).or(ends_with_any(model.eos_tokens()));
+    // 丢弃归一化后概率不足 1% 的长尾 token，避免偶尔采到离谱的词把总结带偏
+    let sampler = Sampler::top_k_top_p(0.6, 20, 0.95).with_min_token_prob(0.01);
+    let stop_cond = model.default_stop_condition().or(max_len(1024));
     
     eprintln!("[Debug] Generating Finale...");
-    let generated_text = ctx.generate(sampler, stop_cond).await;
+    let generated_text = match input.fallback_model.as_deref().and_then(get_model) {
+        Some(fallback) => ctx.generate_with_fallback(&fallback, sampler, stop_cond).await,
+        None => ctx.generate(sampler, stop_cond).await,
+    };
     eprintln!("[Debug] Finale Length: {}", generated_text.len());
 
     store_set(&format!("{}_output", input.task_id), &generated_text);
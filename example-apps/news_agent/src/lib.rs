@@ -1,8 +1,9 @@
 use inferlet::{
     sampler::Sampler,
-    stop_condition::{max_len, ends_with_any, StopCondition},
-    Args, Result, main, get_auto_model, broadcast, subscribe
+    stop_condition::{max_len, StopCondition},
+    Args, Result, main, get_auto_model, broadcast_chunks, broadcast_ordered, subscribe_stream, Inbox
 };
+use futures::StreamExt;
 use serde::{Deserialize};
 use std::{thread, time::Duration};
 
@@ -14,10 +15,9 @@ struct AgentInput {
 async fn generate_text(prompt: &str, max_tokens: usize) -> Result<String> {
     let model = get_auto_model();
     let mut ctx = model.create_context();
-    ctx.fill_user(prompt);
     let sampler = Sampler::top_k_top_p(0.6, 20, 0.95);
-    let stop_cond = max_len(max_tokens).or(ends_with_any(model.eos_tokens()));
-    let output = ctx.generate(sampler, stop_cond).await;
+    let stop_cond = model.default_stop_condition().or(max_len(max_tokens));
+    let output = ctx.prompt(prompt, sampler, stop_cond).await;
     std::mem::forget(ctx);
     Ok(output)
 }
@@ -38,12 +38,14 @@ async fn main(mut args: Args) -> Result<String> {
         thread::sleep(Duration::from_secs(2));
         
         eprintln!("[Wire] Broadcasting...");
-        broadcast("topic/global_news", &news);
+        // 按段落分块发送，Desk 可以边收边处理，末尾自动附带结束标记
+        broadcast_chunks("topic/global_news", news.split_inclusive("\n\n").map(str::to_string));
         final_output = news;
 
     } else if instruction.contains("ROLE: DESK") {
         eprintln!("[Desk] Subscribing to global wire...");
-        let raw_news = subscribe("topic/global_news").await;
+        // 整个流只订阅一次，收到结束标记后拼接出完整的新闻
+        let raw_news = subscribe_stream("topic/global_news").collect::<Vec<String>>().await.concat();
         eprintln!("[Desk] Received. Processing...");
 
         let desk_prompt = format!("SOURCE:\n{}\n\nTASK:\n{}", raw_news, instruction);
@@ -58,8 +60,9 @@ async fn main(mut args: Args) -> Result<String> {
         let payload = format!("{}: {}", prefix, analysis);
         
         eprintln!("[Desk] Sending {} report to 'topic/editor_inbox'...", prefix);
-        // 所有 Desk 发送给同一个 Topic，这样 Host 会帮 Editor 缓存消息队列
-        broadcast("topic/editor_inbox", &payload);
+        // 所有 Desk 发送给同一个 Topic，Host 为每条消息分配递增的序号，Editor 据此发现丢失的消息
+        let seq = broadcast_ordered("topic/editor_inbox", &payload);
+        eprintln!("[Desk] Sent as message #{}.", seq);
         
         final_output = analysis;
 
@@ -70,21 +73,48 @@ async fn main(mut args: Args) -> Result<String> {
         let mut t_report = String::from("(Missing Tech)");
         let mut s_report = String::from("(Missing Sports)");
 
-        // === 关键修改 2：在一个循环中接收 3 条消息 ===
-        // 因为都在同一个 Topic，底层 mpsc queue 会保证消息不丢失，依次取出来
-        for i in 1..=3 {
-            eprintln!("[Editor] Fetching message {}/3...", i);
-            let msg = subscribe("topic/editor_inbox").await;
-            
-            if msg.starts_with("POLITICS:") {
-                eprintln!("[Editor] Got Politics.");
-                p_report = msg.replace("POLITICS: ", "");
-            } else if msg.starts_with("TECH:") {
-                eprintln!("[Editor] Got Tech.");
-                t_report = msg.replace("TECH: ", "");
-            } else if msg.starts_with("SPORTS:") {
-                eprintln!("[Editor] Got Sports.");
-                s_report = msg.replace("SPORTS: ", "");
+        // === 关键修改 2：用一个持续的 Inbox 收齐 3 条消息 ===
+        // Host 按收到的顺序向订阅者投递同一 Topic 的消息，但订阅之前发布的消息不会补发；
+        // 每次至少等到一条消息，再一次性取走所有已到达的消息，直到收满 3 条。
+        // 序号不连续说明中间有消息丢失
+        let inbox = Inbox::new("topic/editor_inbox");
+        let mut received = 0;
+        let mut last_seq: Option<u64> = None;
+        while received < 3 {
+            inbox.wait().await;
+            for (seq, msg) in inbox.drain_ordered()? {
+                received += 1;
+                eprintln!("[Editor] Fetched message {}/3.", received);
+                if let Some(last) = last_seq {
+                    if seq != last + 1 {
+                        eprintln!("[Editor] Missed messages #{}..#{}.", last + 1, seq - 1);
+                    }
+                }
+                last_seq = Some(seq);
+
+                if msg.starts_with("POLITICS:") {
+                    eprintln!("[Editor] Got Politics.");
+                    p_report = msg.replace("POLITICS: ", "");
+                } else if msg.starts_with("TECH:") {
+                    eprintln!("[Editor] Got Tech.");
+                    t_report = msg.replace("TECH: ", "");
+                } else if msg.starts_with("SPORTS:") {
+                    eprintln!("[Editor] Got Sports.");
+                    s_report = msg.replace("SPORTS: ", "");
+                }
+            }
+        }
+
+        // 汇总前先给每份报告打分，丢弃与新闻无关的低质量报告
+        let model = get_auto_model();
+        for (name, report) in [("Politics", &mut p_report), ("Tech", &mut t_report), ("Sports", &mut s_report)] {
+            if report.starts_with("(Missing") {
+                continue;
+            }
+            let scores = model.score(report, &["relevant news report", "irrelevant or low quality"]).await;
+            if scores[1] > scores[0] {
+                eprintln!("[Editor] Dropping low-quality {} report.", name);
+                *report = format!("(Dropped {})", name);
             }
         }
 
@@ -1,10 +1,9 @@
 use inferlet::{
-    forward::{Forward, KvPage},
     sampler::Sampler,
-    stop_condition::{max_len, ends_with_any, StopCondition},
-    Args, Queue, Result, Tokenizer, main, get_auto_model, store_set, Resource
+    stop_condition::{max_len, StopCondition},
+    Args, Queue, Result, Tokenizer, main, get_auto_model, Resource
 };
-use serde::{Deserialize, Serialize};
+use serde::Deserialize;
 
 #[derive(Debug, Deserialize)]
 struct AgentInput {
@@ -14,14 +13,6 @@ struct AgentInput {
     prompt: String,
 }
 
-#[derive(Debug, Serialize)]
-struct AgentMeta {
-    token_ids: Vec<u32>,
-    kv_page_last_len: usize,
-    // 【新增】KV 依赖链。作为起始节点，这是链条的第一环。
-    kv_chain: Vec<String>,
-}
-
 #[inferlet::main]
 async fn main(mut args: Args) -> Result<String> {
     eprintln!("[Debug] Intro Agent (Chain Root) started.");
@@ -32,8 +23,9 @@ async fn main(mut args: Args) -> Result<String> {
         .map_err(|e| anyhow::anyhow!("Failed to parse input JSON: {}", e))?;
 
     // 2. 初始化
+    // 作为链条的起点，ctx 离开作用域时由 AutoExport 导出 KV、写入元数据与输出，无需手动 forget
     let model = get_auto_model();
-    let mut ctx = model.create_context();
+    let mut ctx = model.create_context().auto_export(&input.task_id);
 
     // 3. 注入 Prompt
     ctx.fill_system("You are a fantasy novel writer.");
@@ -41,36 +33,17 @@ async fn main(mut args: Args) -> Result<String> {
 
     // 4. 推理
     let sampler = Sampler::top_k_top_p(0.6, 20, 0.95);
-    let stop_cond = max_len(1024).or(ends_with_any(model.eos_tokens()));
+    let stop_cond = model.default_stop_condition().or(max_len(1024));
 
     let generated_text = ctx.generate(sampler, stop_cond).await;
     eprintln!("[Debug] Intro generation complete.");
 
     // 5. 状态保存
-    let kv_resource_name = format!("{}_kv", input.task_id);
-    
-    // 导出显存 (Intro 产生的是链条的基础部分)
-    ctx.queue().export_kv_pages(&ctx.kv_pages, &kv_resource_name);
-
-    // 【新增】构建 KV 链
-    // 因为是起始节点，链条里只有我自己产生的这一份 KV
-    let my_chain = vec![kv_resource_name.clone()];
-
-    // 导出元数据
-    let meta = AgentMeta {
-        token_ids: ctx.get_token_ids().to_vec(),
-        kv_page_last_len: ctx.get_kv_page_last_len(),
-        kv_chain: my_chain, // 存入链条
-    };
-    
-    let meta_json = serde_json::to_string(&meta)?;
-    store_set(&format!("{}_meta", input.task_id), &meta_json);
-    store_set(&format!("{}_output", input.task_id), &generated_text);
-
-    eprintln!("[Debug] Intro state saved with Chain initialized. Leaking context...");
+    // 链条里只有我自己产生的这一份 KV；元数据与输出一次性写入，避免读者看到只写了一半的任务
+    ctx.set_output(&generated_text);
+    let meta = ctx.finish()?;
 
-    // 6. 遗忘 ctx，防止显存被释放
-    std::mem::forget(ctx);
+    eprintln!("[Debug] Intro state saved with Chain initialized ({:?}).", meta.kv_chain);
 
     Ok(generated_text)
 }
\ No newline at end of file
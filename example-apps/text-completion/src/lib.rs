@@ -1,4 +1,4 @@
-use inferlet::stop_condition::{StopCondition, ends_with_any, max_len};
+use inferlet::stop_condition::{StopCondition, max_len};
 use inferlet::{Args, Result, Sampler};
 use std::time::Instant;
 
@@ -16,7 +16,7 @@ async fn main(mut args: Args) -> Result<String> {
     ctx.fill_user(&prompt);
 
     let sampler = Sampler::top_p(0.6, 0.95);
-    let stop_cond = max_len(max_num_outputs).or(ends_with_any(model.eos_tokens()));
+    let stop_cond = model.default_stop_condition().or(max_len(max_num_outputs));
 
     let final_text = ctx.generate(sampler, stop_cond).await;
 
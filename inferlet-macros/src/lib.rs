@@ -38,7 +38,7 @@ pub fn main(_attr: TokenStream, item: TokenStream) -> TokenStream {
                         .collect(),
                 );
 
-                let result = inferlet::wstd::runtime::block_on(async { #inner_fn_name(args).await });
+                let result = inferlet::block_on(async { #inner_fn_name(args).await });
 
                 match result {
                     Ok(r) => {
@@ -0,0 +1,273 @@
+use crate::context::ContextState;
+use crate::error::Error;
+use crate::forward::ExportMode;
+use crate::{Context, Result, store_get, store_get_many, store_set_batch};
+use serde::{Deserialize, Serialize};
+use std::mem;
+use std::ops::{Deref, DerefMut};
+
+/// The metadata a chained agent stores next to its KV export.
+///
+/// An agent that continues another one imports its parent's KV pages instead of
+/// recomputing them, so its KV cache is spread over the exports of all its ancestors
+/// (the KV chain) and its token history over their metadata (the meta chain), where
+/// each node only records the tokens it added itself. Build it with [`MetaBuilder`].
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct AgentMeta {
+    /// The tokens this node added on top of its parent, or the whole history of a root.
+    pub token_ids: Vec<u32>,
+    /// The layout of the node's KV cache over the whole chain.
+    pub state: ContextState,
+    /// The KV exports that, imported in order, make up the node's KV cache.
+    #[serde(default)]
+    pub kv_chain: Vec<String>,
+    /// The store keys of the metadata whose `token_ids`, concatenated in order, form
+    /// the node's token history. Metadata written before this field existed holds the
+    /// full history in its own `token_ids`.
+    #[serde(default)]
+    pub meta_chain: Vec<String>,
+}
+
+impl AgentMeta {
+    /// Reads the metadata stored under `key`.
+    ///
+    /// Fails with [`Error::StoreMissing`] if nothing is stored under `key`, e.g. because
+    /// the agent that writes it has not finished yet.
+    pub fn load(key: &str) -> Result<AgentMeta, Error> {
+        let json = store_get(key).ok_or_else(|| Error::StoreMissing(key.to_string()))?;
+        Ok(serde_json::from_str(&json)?)
+    }
+
+    /// Serializes the metadata for [`store_set`](crate::store_set).
+    pub fn to_json(&self) -> Result<String, Error> {
+        Ok(serde_json::to_string(self)?)
+    }
+
+    /// Returns the meta chain, treating metadata without one, stored under `key`, as
+    /// a chain of its own.
+    pub fn meta_chain_or(&self, key: &str) -> Vec<String> {
+        if self.meta_chain.is_empty() {
+            vec![key.to_string()]
+        } else {
+            self.meta_chain.clone()
+        }
+    }
+
+    /// Reassembles the full token history of this metadata, stored under `key`, by
+    /// reading every node of its meta chain.
+    pub fn load_token_ids(&self, key: &str) -> Result<Vec<u32>, Error> {
+        let meta_chain = self.meta_chain_or(key);
+        let mut token_ids = Vec::new();
+        for (key, json) in meta_chain.iter().zip(store_get_many(&meta_chain)) {
+            let json = json.ok_or_else(|| Error::StoreMissing(key.clone()))?;
+            let meta: AgentMeta = serde_json::from_str(&json)?;
+            token_ids.extend(meta.token_ids);
+        }
+        Ok(token_ids)
+    }
+}
+
+/// Returns how many leading KV exports two chains (see [`AgentMeta::kv_chain`]) share.
+///
+/// Branches that continue the same ancestor share its exports, so a merge of them only
+/// has to import that prefix once. Returns 0 when the chains diverge at the root.
+pub fn common_prefix(chain_a: &[String], chain_b: &[String]) -> usize {
+    chain_a
+        .iter()
+        .zip(chain_b)
+        .take_while(|(a, b)| a == b)
+        .count()
+}
+
+/// Builds [`AgentMeta`] so that the KV and meta chains are always carried over.
+///
+/// Start a chain root with [`MetaBuilder::new`] and a node that continues another one
+/// with [`MetaBuilder::from_parent`], then add this node's KV export with
+/// [`MetaBuilder::push_kv`].
+#[derive(Debug, Clone, Default)]
+pub struct MetaBuilder {
+    token_ids: Vec<u32>,
+    state: Option<ContextState>,
+    kv_chain: Vec<String>,
+    meta_chain: Vec<String>,
+}
+
+impl MetaBuilder {
+    /// Starts the metadata of a chain root, which owns its whole history.
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Starts the metadata of a node that continues `parent`, stored under
+    /// `parent_key`, inheriting both of its chains.
+    pub fn from_parent(parent: &AgentMeta, parent_key: &str) -> Self {
+        MetaBuilder {
+            token_ids: Vec::new(),
+            state: None,
+            kv_chain: parent.kv_chain.clone(),
+            meta_chain: parent.meta_chain_or(parent_key),
+        }
+    }
+
+    /// Sets the tokens this node added (all of them for a root).
+    pub fn token_ids(mut self, token_ids: Vec<u32>) -> Self {
+        self.token_ids = token_ids;
+        self
+    }
+
+    /// Sets the layout of the node's KV cache, see [`Context::state`](crate::Context::state).
+    pub fn state(mut self, state: ContextState) -> Self {
+        self.state = Some(state);
+        self
+    }
+
+    /// Appends the KV export of this node to the KV chain.
+    pub fn push_kv(mut self, key: &str) -> Self {
+        self.kv_chain.push(key.to_string());
+        self
+    }
+
+    /// Builds the metadata that will be stored under `key`, which is appended to the
+    /// meta chain.
+    ///
+    /// Fails if the state was not set or the KV chain is empty.
+    pub fn build(mut self, key: &str) -> Result<AgentMeta, Error> {
+        let state = self.state.ok_or_else(|| {
+            Error::InvalidArgument(format!("Agent meta '{}' has no context state", key))
+        })?;
+        if self.kv_chain.is_empty() {
+            return Err(Error::InvalidArgument(format!(
+                "Agent meta '{}' has an empty KV chain",
+                key
+            )));
+        }
+        self.meta_chain.push(key.to_string());
+
+        Ok(AgentMeta {
+            token_ids: self.token_ids,
+            state,
+            kv_chain: self.kv_chain,
+            meta_chain: self.meta_chain,
+        })
+    }
+}
+
+/// Persists an agent's context when it goes out of scope, see [`Context::auto_export`].
+///
+/// On drop it exports the KV pages the agent added under `{task_id}_kv`, stores the
+/// [`AgentMeta`] under `{task_id}_meta` together with the output set through
+/// [`AutoExport::set_output`] under `{task_id}_output`, and detaches the pages so they
+/// outlive the instance. This replaces the export, `store_set` and `mem::forget`
+/// sequence at the end of every agent. The guard dereferences to the [`Context`], so
+/// generation works on it directly.
+///
+/// Errors during the export can only be printed on drop; call [`AutoExport::finish`]
+/// to handle them instead, or [`AutoExport::cancel`] to persist nothing.
+#[derive(Debug)]
+pub struct AutoExport {
+    ctx: Option<Context>,
+    task_id: String,
+    meta: MetaBuilder,
+    mark: ContextState,
+    output: Option<String>,
+}
+
+impl AutoExport {
+    pub(crate) fn new(ctx: Context, task_id: &str) -> Self {
+        AutoExport {
+            ctx: Some(ctx),
+            task_id: task_id.to_string(),
+            meta: MetaBuilder::new(),
+            mark: ContextState {
+                page_count: 0,
+                last_len: 0,
+                token_count: 0,
+            },
+            output: None,
+        }
+    }
+
+    /// Makes this agent a continuation of `parent`, stored under `parent_key`.
+    ///
+    /// Call it right after importing the parent's KV: everything the context holds at
+    /// this point is treated as the parent's, so only the pages and tokens added later
+    /// are exported and recorded.
+    pub fn with_parent(mut self, parent: &AgentMeta, parent_key: &str) -> Self {
+        self.meta = MetaBuilder::from_parent(parent, parent_key);
+        self.mark = self.state();
+        self
+    }
+
+    /// Sets the text stored under `{task_id}_output`; nothing is stored if it is unset.
+    pub fn set_output(&mut self, output: &str) {
+        self.output = Some(output.to_string());
+    }
+
+    /// Drops the context without exporting or storing anything, e.g. on an error path.
+    pub fn cancel(mut self) {
+        self.ctx = None;
+    }
+
+    /// Persists the context now and returns the stored metadata, reporting failures
+    /// that dropping the guard could only print.
+    pub fn finish(mut self) -> Result<AgentMeta, Error> {
+        self.persist()
+    }
+
+    fn persist(&mut self) -> Result<AgentMeta, Error> {
+        let mut ctx = self.ctx.take().ok_or_else(|| {
+            Error::InvalidArgument(format!("Agent '{}' was already persisted", self.task_id))
+        })?;
+        // The last sampled token was never forwarded, so it has no KV to export.
+        ctx.restore_state(&ctx.state());
+
+        let kv_key = format!("{}_kv", self.task_id);
+        let meta_key = format!("{}_meta", self.task_id);
+        ctx.export_page_range_with_mode(
+            &kv_key,
+            self.mark.page_count,
+            ctx.kv_pages.len(),
+            ExportMode::Strict,
+        )?;
+        let meta = mem::take(&mut self.meta)
+            .token_ids(ctx.tokens_since(&self.mark).to_vec())
+            .state(ctx.state())
+            .push_kv(&kv_key)
+            .build(&meta_key)?;
+
+        let mut entries = vec![(meta_key, meta.to_json()?)];
+        if let Some(output) = self.output.take() {
+            entries.push((format!("{}_output", self.task_id), output));
+        }
+        store_set_batch(&entries);
+
+        // The exports (ours and the imported ones) keep the pages alive; detach them
+        // from the context instead of handing them back to the pool.
+        mem::forget(mem::take(&mut ctx.kv_pages));
+        Ok(meta)
+    }
+}
+
+impl Deref for AutoExport {
+    type Target = Context;
+
+    fn deref(&self) -> &Context {
+        self.ctx.as_ref().expect("AutoExport used after persisting")
+    }
+}
+
+impl DerefMut for AutoExport {
+    fn deref_mut(&mut self) -> &mut Context {
+        self.ctx.as_mut().expect("AutoExport used after persisting")
+    }
+}
+
+impl Drop for AutoExport {
+    fn drop(&mut self) {
+        if self.ctx.is_some()
+            && let Err(e) = self.persist()
+        {
+            eprintln!("Failed to export agent '{}': {}", self.task_id, e);
+        }
+    }
+}
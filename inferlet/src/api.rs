@@ -1,13 +1,10 @@
 pub use crate::api::exports::inferlet::core::run::Guest;
 pub use crate::api::inferlet::adapter;
 pub use crate::api::inferlet::core::common::{
-    Blob, BlobResult, DebugQueryResult, Model, Queue, SynchronizationResult, Priority,
-    allocate_resources,
-    deallocate_resources,
-    export_resources,
-    import_resources,
-    get_all_exported_resources,
-    release_exported_resources
+    Blob, BlobResult, CacheStats, DebugQueryResult, ExportMode, Model, Priority, Queue,
+    SynchronizationResult, allocate_resources, deallocate_resources, export_resources,
+    export_resources_with_mode, get_all_exported_resources, get_available_resources,
+    import_resources, release_exported_resources,
 };
 pub use crate::api::inferlet::core::forward;
 pub use crate::api::inferlet::core::kvs;
@@ -24,6 +24,64 @@ struct Message {
     tool_calls: Option<Vec<ToolCall>>,
 }
 
+/// A role-tagged message for APIs that take a whole conversation at once.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum ChatMessage {
+    System(String),
+    User(String),
+    Assistant(String),
+    Tool(String),
+}
+
+/// The text a chat template wraps around the content of a message of one role, see
+/// [`Model::role_template`](crate::Model::role_template).
+///
+/// Both parts are empty for a model without a chat template (a base model), so
+/// [`RoleTemplate::wrap`] leaves the content unchanged.
+#[derive(Clone, Debug, Default, PartialEq, Eq)]
+pub struct RoleTemplate {
+    /// The role marker the template puts before the content.
+    pub prefix: String,
+    /// What the template puts after the content, usually the end-of-turn marker.
+    pub suffix: String,
+}
+
+/// Stands in for the content when extracting a [`RoleTemplate`]. A private-use
+/// character, so no template treats it specially and trimming keeps it.
+const CONTENT_PLACEHOLDER: &str = "\u{E000}";
+
+impl RoleTemplate {
+    /// Renders a single message of `role` with `template` and splits the result at its
+    /// content. Templates that reject such a message or drop its content yield an
+    /// empty template.
+    pub(crate) fn from_template(template: &str, role: &str) -> Self {
+        let formatter = ChatFormatter {
+            messages: vec![Message {
+                role: role.to_string(),
+                content: CONTENT_PLACEHOLDER.to_string(),
+                reasoning_content: None,
+                tool_calls: None,
+            }],
+        };
+        formatter
+            .try_render(template, false, false)
+            .ok()
+            .and_then(|rendered| {
+                let (prefix, suffix) = rendered.split_once(CONTENT_PLACEHOLDER)?;
+                Some(RoleTemplate {
+                    prefix: prefix.to_string(),
+                    suffix: suffix.to_string(),
+                })
+            })
+            .unwrap_or_default()
+    }
+
+    /// Wraps `content` in the role's markers.
+    pub fn wrap(&self, content: &str) -> String {
+        format!("{}{}{}", self.prefix, content, self.suffix)
+    }
+}
+
 // --- API Implementation ---
 
 #[derive(Debug, Clone)]
@@ -93,6 +151,16 @@ impl ChatFormatter {
         });
     }
 
+    /// Adds a role-tagged message to the conversation.
+    pub fn message(&mut self, message: &ChatMessage) {
+        match message {
+            ChatMessage::System(content) => self.system(content),
+            ChatMessage::User(content) => self.user(content),
+            ChatMessage::Assistant(content) => self.assistant(content),
+            ChatMessage::Tool(content) => self.tool(content),
+        }
+    }
+
     pub fn has_messages(&self) -> bool {
         !self.messages.is_empty()
     }
@@ -115,4 +183,22 @@ impl ChatFormatter {
             begin_of_sequence => begin_of_sequence,
         )
     }
+
+    /// Like [`ChatFormatter::render`], returning the template's errors instead of
+    /// panicking on them.
+    fn try_render(
+        &self,
+        template: &str,
+        add_generation_prompt: bool,
+        begin_of_sequence: bool,
+    ) -> Result<String, minijinja::Error> {
+        minijinja::Environment::new().render_str(
+            template,
+            minijinja::context! {
+                messages => self.messages,
+                add_generation_prompt => add_generation_prompt,
+                begin_of_sequence => begin_of_sequence,
+            },
+        )
+    }
 }
@@ -1,14 +1,30 @@
 use crate::adapter::SetAdapter;
+use crate::agent::AutoExport;
 use crate::brle::Brle;
 use crate::drafter::Drafter;
-use crate::forward::{Distribution, Forward, KvPage};
-use crate::sampler::Sample;
-use crate::stop_condition::StopCondition;
+use crate::error::Error;
+use crate::forward::{Distribution, ExportMode, Forward, KvPage, LogitPrecision};
+use crate::sampler::{LogitProcessor, OverriddenSampler, Sample, SamplerOverrides};
+use crate::stop_condition::{self, StopCondition};
+use crate::stream::{TTS_CLAUSE_BOUNDARIES, clause_end, sentence_end};
 use crate::zo::SetAdapterSeed;
-use crate::{ChatFormatter, Model, Queue, Sampler, Tokenizer};
+use crate::{
+    CancelToken, ChatFormatter, ChatMessage, DecodeOptions, Model, Queue, Sampler, StreamDecoder,
+    Tokenizer,
+};
 use futures::future::join_all;
+use futures::{Stream, stream};
+use serde::de::DeserializeOwned;
+use serde::{Deserialize, Serialize};
+use std::cell::{Cell, RefCell};
 use std::cmp::Ordering;
+use std::fmt;
 use std::mem;
+use std::ops::{Deref, DerefMut, Range};
+use std::pin::Pin;
+use std::rc::Rc;
+use std::task::{self, Poll};
+use std::time::{Duration, Instant};
 
 #[derive(Debug)]
 pub struct Context {
@@ -19,6 +35,7 @@ pub struct Context {
 
     pub token_ids: Vec<u32>,
     pub token_ids_pending: Vec<u32>,
+    pub token_ids_forced: Vec<u32>,
 
     pub token_mask_pending: Vec<Brle>,
     pub token_mask_current: Brle,
@@ -28,11 +45,271 @@ pub struct Context {
     pub kv_pages: Vec<KvPage>,
     pub kv_page_last_len: usize,
     pub kv_page_size: usize,
+    /// Pages kept allocated by [`Context::reset`] and reused before new ones are
+    /// allocated.
+    pub spare_kv_pages: Vec<KvPage>,
 
     pub adapter_ptr: Option<u32>,
     pub adapter_random_seed: Option<i64>,
 
     pub begin_of_sequence: bool,
+
+    /// Token range of the last message filled with [`Context::fill_system`].
+    pub system_range: Option<Range<usize>>,
+
+    pub finish_reason: Option<FinishReason>,
+
+    /// Keep the stop sequence that ended a generation in the context and the returned
+    /// text instead of trimming it with [`Context::trim_trailing_stop`]. Off by default.
+    pub keep_stop_tokens: bool,
+    /// The end-of-turn sequence trimmed by [`Context::trim_trailing_stop`], filled
+    /// again before the next chat message so the assistant turn stays closed.
+    trimmed_stop: Option<Vec<u32>>,
+    /// The callback set with [`Context::on_page_alloc`].
+    on_page_alloc: Option<PageAllocHook>,
+    /// Where the output of the last generation starts in the token history, and its
+    /// tokens, for [`Context::save_output`].
+    last_output: Option<(usize, Vec<u32>)>,
+    /// Number of decode steps between yields to the executor, see
+    /// [`Context::set_yield_interval`]. Zero never yields.
+    yield_interval: usize,
+}
+
+/// A callback fired by [`Context::on_page_alloc`] with the number of KV pages just
+/// allocated and the number the context holds afterwards.
+pub type PageAllocFn = dyn Fn(usize, usize);
+
+#[derive(Clone)]
+struct PageAllocHook(Rc<PageAllocFn>);
+
+impl fmt::Debug for PageAllocHook {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str("PageAllocHook(..)")
+    }
+}
+
+/// Reports `allocated` new KV pages to `hook`, if set, for a context that holds `held`
+/// pages afterwards.
+fn report_page_alloc(hook: &Option<PageAllocHook>, allocated: usize, held: usize) {
+    if let Some(hook) = hook {
+        (hook.0)(allocated, held);
+    }
+}
+
+/// Describes why the most recent generation call ended.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum FinishReason {
+    /// The stop condition was satisfied, carrying the stop sequence the output ends
+    /// with if the condition matched one (see [`StopCondition::matched_sequence`]).
+    Stop(Option<Vec<u32>>),
+    /// The next token would not fit in the model's context window.
+    ContextFull,
+    /// The call's [`CancelToken`] or the [`shutdown_token`](crate::shutdown_token) was
+    /// triggered.
+    Cancelled,
+    /// The deadline of [`Context::generate_timeout`] passed.
+    Timeout,
+    /// The next token would exceed the [`Quota`](crate::Quota) of the context's model.
+    QuotaExceeded,
+    /// The next token needs a KV page beyond the limit set with
+    /// [`set_memory_limit`](crate::set_memory_limit).
+    OutOfMemory,
+    /// The shared token pool of [`Context::generate_within_budget`] is empty.
+    BudgetExhausted,
+}
+
+impl FinishReason {
+    /// Returns the name of the reason in snake case, e.g. `"context_full"`, as stored
+    /// by [`Context::save_output`].
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            FinishReason::Stop(_) => "stop",
+            FinishReason::ContextFull => "context_full",
+            FinishReason::Cancelled => "cancelled",
+            FinishReason::Timeout => "timeout",
+            FinishReason::QuotaExceeded => "quota_exceeded",
+            FinishReason::OutOfMemory => "out_of_memory",
+            FinishReason::BudgetExhausted => "budget_exhausted",
+        }
+    }
+}
+
+/// How [`Context::save_output`] stores the output of a generation.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum OutputFormat {
+    /// The decoded text alone, as agents store it with
+    /// `store_set(&format!("{}_output", id), &text)`.
+    Text,
+    /// A JSON object holding the text along with its tokens, see [`SavedOutput`].
+    Json,
+}
+
+/// The structured output stored by [`Context::save_output`] with
+/// [`OutputFormat::Json`]:
+///
+/// ```json
+/// {
+///   "text": "The answer is 42.",
+///   "token_ids": [791, 4320, 374, 220, 2983, 13],
+///   "logprobs": [-0.12, -0.53, -0.01, -1.9, -0.44, -0.08],
+///   "finish_reason": "stop"
+/// }
+/// ```
+///
+/// `logprobs` has one natural-log probability per entry of `token_ids`, and
+/// `finish_reason` is a [`FinishReason::as_str`] name or `null`.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct SavedOutput {
+    pub text: String,
+    pub token_ids: Vec<u32>,
+    pub logprobs: Vec<f32>,
+    pub finish_reason: Option<String>,
+}
+
+impl SavedOutput {
+    /// Reads an output saved with [`OutputFormat::Json`] from the store.
+    ///
+    /// Fails with [`Error::StoreMissing`] if nothing is stored under `key`, and with
+    /// [`Error::ParseInput`] if the value is not such an object, e.g. because it was
+    /// saved as [`OutputFormat::Text`].
+    pub fn load(key: &str) -> Result<Self, Error> {
+        let json = crate::store_get(key).ok_or_else(|| Error::StoreMissing(key.to_string()))?;
+        Ok(serde_json::from_str(&json)?)
+    }
+}
+
+/// The outcome of [`Context::generate_with_tools`].
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum GenerationStep {
+    /// The generation ended without a tool call, holding the generated text; see
+    /// [`Context::finish_reason`] for why it ended.
+    Done(String),
+    /// The output contains the tool call marker `marker`. `text` is the output so far,
+    /// the marker and the call before it included, for the caller to parse.
+    ToolCall { marker: String, text: String },
+}
+
+/// Conditions besides the stop condition that end a generation loop early.
+#[derive(Default)]
+struct Interrupts<'a> {
+    cancel: Option<&'a CancelToken>,
+    deadline: Option<Instant>,
+    /// The store key of a token pool each decode step takes one token from.
+    budget_key: Option<String>,
+}
+
+/// How many candidates per position [`Context::continuation_log_prob`] requests.
+const CONTINUATION_TOP_K: u32 = 256;
+
+/// What [`Context::export_page_range`] exported.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct PageRangeExport {
+    /// The number of KV pages exported.
+    pub pages: usize,
+    /// The committed tokens stored in pages after the range, which an importer of the
+    /// export does not get.
+    pub tokens_left_out: usize,
+}
+
+/// A snapshot of the committed KV-cache layout of a [`Context`].
+///
+/// The three fields are always captured together, so a stored state can never
+/// mix a page count from one point in time with a token count from another.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+pub struct ContextState {
+    /// The number of KV pages backing the committed tokens.
+    pub page_count: usize,
+    /// The number of tokens stored in the last KV page.
+    pub last_len: usize,
+    /// The number of committed (already forwarded) tokens.
+    pub token_count: usize,
+}
+
+impl ContextState {
+    /// Returns the state of a context that holds `token_count` committed tokens in
+    /// pages of `page_size` tokens.
+    ///
+    /// Every page but the last is full, and the last holds between one and
+    /// `page_size` tokens, or none if there are no tokens at all.
+    pub(crate) fn for_tokens(token_count: usize, page_size: usize) -> Self {
+        let page_count = crate::pages_for_tokens(token_count, page_size);
+        ContextState {
+            page_count,
+            last_len: token_count - page_count.saturating_sub(1) * page_size,
+            token_count,
+        }
+    }
+}
+
+/// A handle to the KV cache of a context exported with [`Context::into_exported`].
+///
+/// It carries everything needed to rebuild the context later (the export name, the
+/// committed tokens and their KV layout) and can be serialized into agent metadata.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct ExportHandle {
+    name: String,
+    token_ids: Vec<u32>,
+    state: ContextState,
+}
+
+impl ExportHandle {
+    /// The name the KV pages were exported under.
+    pub fn name(&self) -> &str {
+        &self.name
+    }
+
+    /// The committed tokens whose KV entries were exported.
+    pub fn token_ids(&self) -> &[u32] {
+        &self.token_ids
+    }
+
+    /// The KV layout of the exported pages.
+    pub fn state(&self) -> ContextState {
+        self.state
+    }
+
+    /// Rebuilds a context on `model` from the exported KV pages.
+    ///
+    /// The export stays registered, so several contexts can be rebuilt from it.
+    pub fn import(&self, model: &Model) -> Context {
+        let queue = model.create_queue();
+        let kv_pages = queue.import_kv_pages(&self.name);
+        Context::from_imported_state(model, kv_pages, self.token_ids.clone(), self.state.last_len)
+    }
+
+    /// Releases the exported KV pages (see [`Forward::release_exported_kv_pages`]).
+    pub fn release(self, queue: &Queue) {
+        queue.release_exported_kv_pages(&self.name);
+    }
+}
+
+/// The next-token distribution of a [`Context`] at a branch point, captured with
+/// [`Context::checkpoint_logits`] so that tokens can be sampled from it repeatedly
+/// with [`Context::sample_from`] instead of re-running the forward pass.
+///
+/// It holds one token ID and one `f32` per candidate, i.e. 8 bytes per token of the
+/// vocabulary when the backend returns all of them (about 1.2 MB for a 150k-token
+/// vocabulary), so keep only as many checkpoints as a search actually revisits.
+#[derive(Debug, Clone, PartialEq)]
+pub struct LogitsCheckpoint {
+    /// The number of committed tokens of the context the distribution follows.
+    token_count: usize,
+    /// The candidate token IDs, sorted by descending probability.
+    ids: Vec<u32>,
+    /// The temperature 1.0 log-probabilities of `ids` (the raw logits up to a constant).
+    logits: Vec<f32>,
+}
+
+impl LogitsCheckpoint {
+    /// The candidate token IDs, sorted by descending probability.
+    pub fn ids(&self) -> &[u32] {
+        &self.ids
+    }
+
+    /// The temperature 1.0 log-probabilities of [`LogitsCheckpoint::ids`].
+    pub fn logits(&self) -> &[f32] {
+        &self.logits
+    }
 }
 
 impl Context {
@@ -47,18 +324,52 @@ impl Context {
             formatter: ChatFormatter::new(),
             token_ids: Vec::new(),
             token_ids_pending: Vec::new(),
+            token_ids_forced: Vec::new(),
             token_mask_pending: Vec::new(),
             token_mask_current: Brle::new(0),
             position_ids: Vec::new(),
             kv_pages: Vec::new(),
             kv_page_last_len: 0,
             kv_page_size,
+            spare_kv_pages: Vec::new(),
             adapter_ptr: None,
             adapter_random_seed: None,
             begin_of_sequence: true,
+            system_range: None,
+            finish_reason: None,
+            keep_stop_tokens: false,
+            trimmed_stop: None,
+            on_page_alloc: None,
+            last_output: None,
+            yield_interval: 1,
         }
     }
 
+    /// Calls `callback` whenever the context allocates new KV pages to grow its cache,
+    /// with the number of pages allocated and the number it holds afterwards, e.g. for
+    /// a monitor to follow the memory growth of a long generation as it happens.
+    ///
+    /// The private copy of a shared last page that a forked context makes before writing
+    /// into it counts as an allocation too. Pages reused from those kept by
+    /// [`Context::reset`] are not reported, since they take no new memory. The callback
+    /// replaces any previous one and is inherited by [`Context::fork`]. Without a
+    /// callback, allocation costs nothing extra.
+    pub fn on_page_alloc(&mut self, callback: impl Fn(usize, usize) + 'static) {
+        self.on_page_alloc = Some(PageAllocHook(Rc::new(callback)));
+    }
+
+    /// Sets how many decode steps a generation runs before yielding to the executor,
+    /// 1 (every step) by default; 0 never yields.
+    ///
+    /// Agents that generate concurrently within one inferlet, e.g. joined futures,
+    /// take turns at the yields: without them, a generation whose forward passes
+    /// resolve immediately would run to completion before the others continue. A
+    /// yield only reschedules the task, so larger intervals merely trade fairness for
+    /// fewer trips through the executor.
+    pub fn set_yield_interval(&mut self, steps: usize) {
+        self.yield_interval = steps;
+    }
+
     pub fn set_adapter(&mut self, adapter_ptr: u32) {
         self.adapter_ptr = Some(adapter_ptr);
     }
@@ -99,16 +410,198 @@ impl Context {
             formatter: ChatFormatter::new(),
             token_ids: prefix_tokens,
             token_ids_pending: Vec::new(),
+            token_ids_forced: Vec::new(),
             token_mask_pending: Vec::new(),
             token_mask_current: Brle::new(num_tokens),
             position_ids: (0..num_tokens as u32).collect(),
             kv_pages,
             kv_page_last_len,
             kv_page_size,
+            spare_kv_pages: Vec::new(),
             adapter_ptr: None,
             adapter_random_seed: None,
             begin_of_sequence: false,
+            system_range: None,
+            finish_reason: None,
+            keep_stop_tokens: false,
+            trimmed_stop: None,
+            on_page_alloc: None,
+            last_output: None,
+            yield_interval: 1,
+        }
+    }
+
+    /// Creates a new Context from the KV pages exported under each of `keys`, imported
+    /// on `queue` and concatenated in order.
+    ///
+    /// The keys must form one contiguous KV chain (e.g. [`AgentMeta::kv_chain`]): every
+    /// export continues at the position where the previous one ended, because cached
+    /// keys carry their positions and cannot be rebased, so the restored context simply
+    /// numbers `token_ids` from 0 across the branch boundaries. `token_ids` is the full
+    /// history covered by the chain and `last_len` the fill of its final page. The pages
+    /// are shared with the exporters, see [`Forward::import_kv_pages_shared`].
+    ///
+    /// Importing the whole chain up front costs no device memory: an import only maps
+    /// the pages the exporters already hold into `queue`. Nor could pages be imported
+    /// lazily as generation reaches them, since every forward pass attends over the
+    /// entire KV cache, so the first one already needs all of them. A chain too deep
+    /// for memory has to be shortened instead, e.g. by summarizing early branches.
+    ///
+    /// Fails if a key is not exported or the combined page count does not match the
+    /// number of tokens and `last_len`.
+    ///
+    /// [`AgentMeta::kv_chain`]: crate::agent::AgentMeta::kv_chain
+    pub fn from_multiple_imports(
+        model: &Model,
+        queue: &Queue,
+        keys: &[&str],
+        token_ids: Vec<u32>,
+        last_len: usize,
+    ) -> Result<Self, Error> {
+        let kv_page_size = model.get_kv_page_size() as usize;
+        if last_len == 0 || last_len > kv_page_size || last_len > token_ids.len() {
+            return Err(Error::InvalidArgument(format!(
+                "Last page length {} is invalid for {} tokens and page size {}",
+                last_len,
+                token_ids.len(),
+                kv_page_size
+            )));
+        }
+
+        let mut kv_pages = Vec::new();
+        for key in keys {
+            kv_pages.extend(queue.try_import_kv_pages(key)?);
+        }
+        let expected = ContextState::for_tokens(token_ids.len(), kv_page_size);
+        if kv_pages.len() != expected.page_count || last_len != expected.last_len {
+            return Err(Error::InvalidArgument(format!(
+                "KV chain {:?} has {} pages, which does not hold {} tokens with {} in the last page",
+                keys,
+                kv_pages.len(),
+                token_ids.len(),
+                last_len
+            )));
+        }
+
+        Ok(Self::from_imported_state(
+            model, kv_pages, token_ids, last_len,
+        ))
+    }
+
+    /// Saves the tokens of the context, pending ones included, to the store under `key`
+    /// so [`Context::load`] can rebuild it, e.g. in a later agent of a chain.
+    ///
+    /// Unlike [`Context::into_exported`], this holds no KV pages: the host does not hand
+    /// KV cache contents to inferlets, so only the token ids (a few bytes of JSON per
+    /// token) are stored and `load` recomputes the cache with one prefill. That is far
+    /// cheaper to keep than device-resident pages, which pin GPU memory until released,
+    /// but costs a forward pass over the whole history on every load. Masks, buffered
+    /// chat messages and the system prompt boundary are not saved.
+    pub fn save(&self, key: &str) -> Result<(), Error> {
+        let json = encode_saved_tokens(&self.token_ids, &self.token_ids_pending)?;
+        crate::store_set(key, &json);
+        Ok(())
+    }
+
+    /// Rebuilds a context saved with [`Context::save`] and prefills all but its last
+    /// token, which is left pending as the seed of the next generation step.
+    ///
+    /// Fails with [`Error::StoreMissing`] if nothing is stored under `key`.
+    pub async fn load(model: &Model, key: &str) -> Result<Self, Error> {
+        let json = crate::store_get(key).ok_or_else(|| Error::StoreMissing(key.to_string()))?;
+        let token_ids = decode_saved_tokens(&json)?;
+        let mut ctx = Self::new(model);
+        ctx.fill_tokens(token_ids);
+        ctx.flush_prefix().await;
+        Ok(ctx)
+    }
+
+    /// Stores the output of the last generation under `key` in `format`, e.g. for a
+    /// downstream agent that needs more than the text.
+    ///
+    /// The output is what the last call driven by [`Context::generate`] or one of its
+    /// `generate_*` variants returned; beam search, drafting and sentence streams do
+    /// not record one. [`OutputFormat::Text`] stores the decoded text, special tokens
+    /// skipped. [`OutputFormat::Json`] stores a [`SavedOutput`], whose log-probabilities
+    /// are computed under the model's untempered distribution by forwarding the output
+    /// again on a [`Context::fork`], which costs one forward pass over it. Like in
+    /// [`Context::continuation_log_prob`], a token outside of the candidates the
+    /// backend reports is assigned the smallest reported probability.
+    ///
+    /// Fails with [`Error::InvalidArgument`] if nothing was generated yet or the context
+    /// no longer holds the output, e.g. after a [`Context::restore_state`], and for
+    /// [`OutputFormat::Json`] if the output starts the context, e.g. a forced prefix
+    /// filled into an empty one.
+    pub async fn save_output(&self, key: &str, format: OutputFormat) -> Result<(), Error> {
+        let Some((start, token_ids)) = &self.last_output else {
+            return Err(Error::InvalidArgument(
+                "The context has not generated an output to save".to_string(),
+            ));
+        };
+        let end = start + token_ids.len();
+        let held = self
+            .token_ids
+            .iter()
+            .chain(&self.token_ids_pending)
+            .skip(*start)
+            .take(token_ids.len());
+        if self.token_ids.len() + self.token_ids_pending.len() < end || !held.eq(token_ids) {
+            return Err(Error::InvalidArgument(
+                "The context no longer holds its last output".to_string(),
+            ));
+        }
+        if *start == 0 && format == OutputFormat::Json {
+            return Err(Error::InvalidArgument(
+                "The output starts the context, so no token predicts its first one".to_string(),
+            ));
+        }
+
+        let text = self
+            .tokenizer
+            .detokenize_with(token_ids, &DecodeOptions::default());
+        let value = match format {
+            OutputFormat::Text => text,
+            OutputFormat::Json => {
+                let logprobs = self.output_log_probs(*start, token_ids).await;
+                serde_json::to_string(&SavedOutput {
+                    text,
+                    token_ids: token_ids.clone(),
+                    logprobs,
+                    finish_reason: self
+                        .finish_reason
+                        .as_ref()
+                        .map(|reason| reason.as_str().to_string()),
+                })?
+            }
+        };
+        crate::store_set(key, &value);
+        Ok(())
+    }
+
+    /// Returns the log-probability of each of `token_ids`, which the token history holds
+    /// from `start` on, given the tokens before them.
+    async fn output_log_probs(&self, start: usize, token_ids: &[u32]) -> Vec<f32> {
+        if token_ids.is_empty() {
+            return Vec::new();
         }
+        // The token before the output is forwarded again to predict its first token.
+        let seed = start - 1;
+        let history: Vec<u32> = self
+            .token_ids
+            .iter()
+            .chain(&self.token_ids_pending)
+            .copied()
+            .collect();
+        let mut probe = self.fork();
+        let committed = seed.min(probe.token_ids.len());
+        probe.restore_state(&probe.state_at(committed));
+        probe.fill_tokens(history[committed..start + token_ids.len()].to_vec());
+        let first_idx = (seed - committed) as u32;
+        let output_indices = (first_idx..first_idx + token_ids.len() as u32).collect::<Vec<_>>();
+        let dists = probe
+            .forward_pending_dists(&output_indices, 1.0, Some(CONTINUATION_TOP_K))
+            .await;
+        token_log_probs(token_ids, &dists)
     }
 
     pub fn model(&self) -> &Model {
@@ -119,6 +612,73 @@ impl Context {
         &self.queue
     }
 
+    /// Returns the number of forward passes this context has executed.
+    ///
+    /// Each context owns its queue, so this is the count of its queue. Prefill
+    /// (`flush`) and every decode step each count as one pass.
+    pub fn forward_pass_count(&self) -> usize {
+        self.queue.forward_pass_count()
+    }
+
+    /// Returns the number of additional tokens that fit in the model's context window.
+    ///
+    /// Both committed and pending tokens count towards the window.
+    pub fn remaining_context(&self) -> usize {
+        let used = self.token_ids.len() + self.token_ids_pending.len();
+        (self.model.get_max_context_length() as usize).saturating_sub(used)
+    }
+
+    /// Returns how many more tokens the [`Quota`](crate::Quota) of the context's model
+    /// allows it to hold, or `None` if the quota sets no limit.
+    ///
+    /// Both committed and pending tokens count, and a page limit allows as many tokens
+    /// as fit in that many pages.
+    pub fn remaining_quota(&self) -> Option<usize> {
+        let quota = self.model.quota();
+        let page_tokens = quota.max_pages.map(|pages| pages * self.kv_page_size);
+        let limit = match (quota.max_tokens, page_tokens) {
+            (Some(tokens), Some(page_tokens)) => Some(tokens.min(page_tokens)),
+            (tokens, page_tokens) => tokens.or(page_tokens),
+        }?;
+        let used = self.token_ids.len() + self.token_ids_pending.len();
+        Some(limit.saturating_sub(used))
+    }
+
+    /// Whether the limit set with [`set_memory_limit`](crate::set_memory_limit) allows
+    /// the pages the next forward pass has to allocate for the pending tokens, after
+    /// reusing spare pages, plus the private copy of a shared last page it writes into.
+    fn kv_pages_within_limit(&self) -> bool {
+        let Some(budget) = crate::forward::kv_page_budget() else {
+            return true;
+        };
+        let held = self.kv_pages.len() + self.spare_kv_pages.len();
+        pages_to_allocate(self.pages_needed(), held, self.last_kv_page_needs_copy()) <= budget
+    }
+
+    /// Whether the last KV page is partially filled and also held by another context,
+    /// so the next forward pass has to copy it first, see `unshare_last_kv_page`.
+    fn last_kv_page_needs_copy(&self) -> bool {
+        last_page_needs_copy(
+            &self.kv_pages,
+            self.kv_page_last_len,
+            self.kv_page_size,
+            KvPage::is_shared,
+        )
+    }
+
+    /// Returns the number of KV pages needed to hold every committed and pending token.
+    ///
+    /// Once the pending tokens are forwarded, `kv_pages` holds exactly this many pages.
+    pub fn pages_needed(&self) -> usize {
+        self.model
+            .pages_for_tokens(self.token_ids.len() + self.token_ids_pending.len())
+    }
+
+    /// Returns why the most recent call to [`Context::generate`] ended, if any.
+    pub fn finish_reason(&self) -> Option<&FinishReason> {
+        self.finish_reason.as_ref()
+    }
+
     pub fn get_token_ids(&self) -> &[u32] {
         &self.token_ids
     }
@@ -137,90 +697,391 @@ impl Context {
         self.kv_page_last_len
     }
 
-    /// Creates a safe, copy-on-write fork of the context.
+    /// Returns a consistent snapshot of the committed KV-cache layout.
+    ///
+    /// Pending (not yet forwarded) tokens are not part of the state.
+    pub fn state(&self) -> ContextState {
+        ContextState {
+            page_count: self.kv_pages.len(),
+            last_len: self.kv_page_last_len,
+            token_count: self.token_ids.len(),
+        }
+    }
+
+    /// Returns a checkpoint for [`Context::tokens_since`].
     ///
-    /// This method creates a new context that shares the immutable history of the current
-    /// one. If the last KV-cache page is not full, its tokens are moved to the
-    /// `token_ids_pending` buffer of the new context to be recomputed, ensuring state isolation.
+    /// The checkpoint is the current [`ContextState`], so besides the token count it
+    /// records the page count and the fill level of the last (possibly partial) page.
+    /// Agents that persist only the tokens added after the checkpoint can rebuild the
+    /// full history by concatenating the deltas of every ancestor, and the result is
+    /// consistent with the state stored alongside the final delta.
+    pub fn mark(&self) -> ContextState {
+        self.state()
+    }
+
+    /// Returns a fingerprint of the first `len` tokens (committed, then pending), e.g.
+    /// to derive the key of a prefix cache or to deduplicate KV exports by content.
     ///
-    /// This function will flush any pending tokens in the current context before forking.
-    pub fn fork(&self) -> Self {
-        let (
-            new_tokens,
-            new_pending,
-            new_kv_page_ptrs,
-            new_kv_page_last_len,
-            new_pos_ids,
-            new_mask_pending,
-        ) = if self.kv_page_last_len == self.kv_page_size {
-            // Easy case: the last page is full, we can share everything.
-            (
-                self.token_ids.clone(),
-                self.token_ids_pending.clone(),
-                self.kv_pages.clone(),
-                self.kv_page_last_len,
-                self.position_ids.clone(), // Clone position_ids
-                self.token_mask_pending.clone(),
-            )
-        } else {
-            // Hard case: the last page is partially full and must be recomputed.
-            let kept_kv_page_len = self.kv_pages.len().saturating_sub(1);
-            let kept_tokens_len = kept_kv_page_len * self.kv_page_size;
+    /// The hash only depends on the token IDs (it is FNV-1a over their little-endian
+    /// bytes), so it is stable across runs, builds and platforms. It is not
+    /// collision-resistant against adversarial inputs.
+    ///
+    /// # Panics
+    ///
+    /// Panics if the context holds fewer than `len` tokens.
+    pub fn prefix_hash(&self, len: usize) -> u64 {
+        let total = self.token_ids.len() + self.token_ids_pending.len();
+        assert!(
+            len <= total,
+            "Prefix of {} tokens exceeds the context's {} tokens",
+            len,
+            total
+        );
+        fnv1a(
+            self.token_ids
+                .iter()
+                .chain(&self.token_ids_pending)
+                .take(len),
+        )
+    }
 
-            let forked_token_ids = self.token_ids[..kept_tokens_len].to_vec();
-            let forked_kv_page_ptrs = self.kv_pages[..kept_kv_page_len].to_vec();
-            let forked_pos_ids = self.position_ids[..kept_tokens_len].to_vec();
+    /// Returns the tokens committed since `mark` was taken.
+    ///
+    /// # Panics
+    ///
+    /// Panics if the context was rolled back to fewer tokens than `mark` records.
+    pub fn tokens_since(&self, mark: &ContextState) -> &[u32] {
+        &self.token_ids[mark.token_count..]
+    }
 
-            let forked_pending_token_ids = [
-                &self.token_ids[kept_tokens_len..],
-                &self.token_ids_pending[..],
-            ]
-            .concat();
+    /// Exports the committed KV pages under `name` and drops the rest of the context.
+    ///
+    /// This replaces leaking the whole context with `std::mem::forget` to keep its KV
+    /// cache alive: exported pages are owned by the export rather than by this
+    /// instance, so the queue, tokenizer and remaining buffers are freed normally.
+    /// Pending tokens are not part of the export.
+    pub fn into_exported(self, name: &str) -> ExportHandle {
+        self.queue.export_kv_pages(&self.kv_pages, name);
+        ExportHandle {
+            name: name.to_string(),
+            token_ids: self.token_ids.clone(),
+            state: self.state(),
+        }
+    }
 
-            let forked_last_kv_page_len = if !forked_kv_page_ptrs.is_empty() {
-                self.kv_page_size
-            } else {
-                0
-            };
+    /// Wraps the context of the agent running `task_id` in a guard that exports its KV
+    /// cache and stores its metadata and output when dropped, see [`AutoExport`].
+    ///
+    /// The context is treated as a chain root; use [`AutoExport::with_parent`] for an
+    /// agent that continues another one.
+    pub fn auto_export(self, task_id: &str) -> AutoExport {
+        AutoExport::new(self, task_id)
+    }
 
-            let mut mask_builder = self.token_mask_current.clone();
-            let parent_total_mask_len = self.token_ids.len() + self.token_ids_pending.len();
-            mask_builder.remove_range(kept_tokens_len, parent_total_mask_len);
-
-            // 2. Iteratively build the pending masks, appending `false` for each new
-            // pending token, which mimics the `fill_tokens` behavior.
-            let mut forked_mask_pending = Vec::with_capacity(forked_pending_token_ids.len());
-            for _ in 0..forked_pending_token_ids.len() {
-                mask_builder.append(false);
-                forked_mask_pending.push(mask_builder.clone());
-            }
-
-            (
-                forked_token_ids,
-                forked_pending_token_ids,
-                forked_kv_page_ptrs,
-                forked_last_kv_page_len,
-                forked_pos_ids,
-                forked_mask_pending,
-            )
+    /// Exports the KV pages `start..end` under `name` and reports what was exported.
+    ///
+    /// This is the checked form of exporting `&ctx.kv_pages[start..end]`, e.g. the pages
+    /// added on top of an imported prefix. Fails if the range is reversed or extends
+    /// past the context's pages, or if it includes a partially filled last page while
+    /// tokens are still pending: the next forward pass would write those tokens into
+    /// the exported page, splitting it between the export and this context. Like
+    /// [`ExportMode::FailIfExists`], it also fails if `name` is already exported.
+    pub fn export_page_range(
+        &self,
+        name: &str,
+        start: usize,
+        end: usize,
+    ) -> Result<PageRangeExport, Error> {
+        self.export_page_range_with_mode(name, start, end, ExportMode::FailIfExists)
+    }
+
+    /// Like [`Context::export_page_range`], with an explicit policy for a name that is
+    /// already taken.
+    ///
+    /// Committed tokens stored in pages after `end` are not part of the export, which
+    /// usually means a partially filled last page was left out and its tokens are lost
+    /// to whoever imports the export. [`ExportMode::Strict`] turns this into an error
+    /// (and otherwise behaves like [`ExportMode::FailIfExists`]); the other modes report
+    /// them in [`PageRangeExport::tokens_left_out`].
+    pub fn export_page_range_with_mode(
+        &self,
+        name: &str,
+        start: usize,
+        end: usize,
+        mode: ExportMode,
+    ) -> Result<PageRangeExport, Error> {
+        if start > end {
+            return Err(Error::InvalidArgument(format!(
+                "Invalid page range {}..{}: start is after end",
+                start, end
+            )));
+        }
+        if end > self.kv_pages.len() {
+            return Err(Error::InvalidArgument(format!(
+                "Invalid page range {}..{}: the context only has {} pages",
+                start,
+                end,
+                self.kv_pages.len()
+            )));
+        }
+        if start != end
+            && end == self.kv_pages.len()
+            && self.kv_page_last_len != self.kv_page_size
+            && !self.token_ids_pending.is_empty()
+        {
+            return Err(Error::InvalidArgument(format!(
+                "Page range {}..{} includes the partial last page, which pending tokens would \
+                 still be written into; flush the context first",
+                start, end
+            )));
+        }
+
+        let tokens_left_out = tokens_after_page(self.token_ids.len(), end, self.kv_page_size);
+        if tokens_left_out > 0 && mode == ExportMode::Strict {
+            return Err(Error::ExportFailed(format!(
+                "Exporting pages {}..{} under '{}' leaves out the committed tokens of pages \
+                 {}..{}",
+                start,
+                end,
+                name,
+                end,
+                self.state().page_count
+            )));
+        }
+
+        let pages = &self.kv_pages[start..end];
+        self.queue.export_kv_pages_with_mode(pages, name, mode)?;
+        Ok(PageRangeExport {
+            pages: pages.len(),
+            tokens_left_out,
+        })
+    }
+
+    /// Rolls the context back to a state previously captured with [`Context::state`].
+    ///
+    /// Committed tokens beyond `state.token_count` are discarded along with any pending
+    /// tokens, and KV pages that are no longer needed are released.
+    ///
+    /// # Panics
+    ///
+    /// Panics if `state` is internally inconsistent or describes more tokens than the
+    /// context currently holds.
+    pub fn restore_state(&mut self, state: &ContextState) {
+        self.roll_back(state, false);
+    }
+
+    /// Recomputes the KV cache of the committed tokens from `token_index` on, keeping
+    /// the pages before it, and returns how many tokens were recomputed.
+    ///
+    /// This is the targeted version of rebuilding the whole context: the tokens before
+    /// `token_index` stay valid, so only the rest is prefilled again, in one forward
+    /// pass. The recomputed tokens get consecutive positions after the kept ones, and
+    /// token masks set from `token_index` on are cleared. Pending tokens stay pending.
+    ///
+    /// # Panics
+    ///
+    /// Panics if `token_index` is past the committed tokens.
+    pub async fn recompute_from(&mut self, token_index: usize) -> usize {
+        assert!(
+            token_index <= self.token_ids.len(),
+            "Cannot recompute from token {}: only {} tokens are committed",
+            token_index,
+            self.token_ids.len()
+        );
+
+        let (kept, recomputed) = recompute_plan(&self.token_ids, token_index, self.kv_page_size);
+        if recomputed.is_empty() {
+            return 0;
+        }
+        let recomputed = recomputed.to_vec();
+        let pending = self.token_ids_pending.clone();
+        let system_range = self.system_range.clone();
+        let trimmed_stop = self.trimmed_stop.take();
+
+        self.restore_state(&kept);
+        let count = recomputed.len();
+        self.fill_tokens(recomputed);
+        self.flush().await;
+        self.fill_tokens(pending);
+
+        self.system_range = system_range;
+        self.trimmed_stop = trimmed_stop;
+        count
+    }
+
+    /// Returns the state the context had when it held `token_count` committed tokens.
+    fn state_at(&self, token_count: usize) -> ContextState {
+        ContextState::for_tokens(token_count, self.kv_page_size)
+    }
+
+    /// Returns the token history, committed tokens followed by pending ones, for
+    /// editing, e.g. for a supervisor to delete a bad paragraph before regenerating.
+    ///
+    /// When the returned guard is dropped, the edited history is compared with the
+    /// cached one. The context is rolled back to the first position where they differ
+    /// and everything from there on is filled again as pending tokens, so the next
+    /// [`Context::generate`] or [`Context::flush`] recomputes their KV cache. Editing
+    /// before the current position is therefore expensive: every committed token
+    /// after the first change is prefilled again, not just the changed ones. The token
+    /// masks from that position on are cleared, and a system prompt that overlaps it
+    /// is forgotten. Dropping the guard without changes costs nothing beyond the copy
+    /// of the history it holds.
+    pub fn token_history_mut(&mut self) -> TokenHistoryMut<'_> {
+        let tokens = self
+            .token_ids
+            .iter()
+            .chain(&self.token_ids_pending)
+            .copied()
+            .collect();
+        TokenHistoryMut { ctx: self, tokens }
+    }
+
+    /// Applies an edited token history, see [`Context::token_history_mut`].
+    fn apply_token_history(&mut self, tokens: Vec<u32>) {
+        let cached_len = self.token_ids.len() + self.token_ids_pending.len();
+        let diverge = self
+            .token_ids
+            .iter()
+            .chain(&self.token_ids_pending)
+            .zip(&tokens)
+            .take_while(|(a, b)| a == b)
+            .count();
+        if diverge == cached_len && diverge == tokens.len() {
+            return;
+        }
+
+        let system_range = self.system_range.take();
+        let keep = diverge.min(self.token_ids.len());
+        self.restore_state(&self.state_at(keep));
+        self.fill_tokens(tokens[keep..].to_vec());
+        self.system_range = system_range.filter(|range| range.end <= diverge);
+    }
+
+    /// Empties the context so it can serve an unrelated prompt, keeping its KV pages
+    /// allocated.
+    ///
+    /// All tokens, masks, buffered chat messages and the system prompt are dropped, but
+    /// instead of returning the pages to the host they are parked in `spare_kv_pages`
+    /// and handed out again as the next prompt is filled. A worker loop that resets one
+    /// context per prompt thus skips the page allocations that a fresh [`Context::new`]
+    /// (which also creates a new queue) performs every time. Pages that are shared with
+    /// a fork are released rather than reused, since overwriting them would corrupt the
+    /// fork. Do not reset a context whose pages were exported: the export aliases them
+    /// and would be overwritten as well.
+    ///
+    /// See [`Context::reset_keeping_system`] to keep the system prompt.
+    pub fn reset(&mut self) {
+        self.roll_back(
+            &ContextState {
+                page_count: 0,
+                last_len: 0,
+                token_count: 0,
+            },
+            true,
+        );
+        self.formatter.clear();
+        self.begin_of_sequence = true;
+        self.finish_reason = None;
+    }
+
+    /// Like [`Context::reset`], but keeps everything up to the end of the system prompt
+    /// filled with [`Context::fill_system`], so its KV cache does not have to be
+    /// recomputed for the next prompt.
+    ///
+    /// Resets the whole context if there is no system prompt.
+    pub fn reset_keeping_system(&mut self) {
+        let Some(range) = self.system_range.clone() else {
+            return self.reset();
         };
 
+        let all_token_ids = [&self.token_ids[..], &self.token_ids_pending[..]].concat();
+        let kept = range.end.min(self.token_ids.len());
+        let kept_pending = all_token_ids[kept..range.end].to_vec();
+
+        self.roll_back(&self.state_at(kept), true);
+        self.fill_tokens(kept_pending);
+        self.formatter.clear();
+        self.system_range = Some(range);
+        self.finish_reason = None;
+    }
+
+    /// Rolls the context back to `state`, either releasing the KV pages that are no
+    /// longer needed or, with `keep_pages`, parking them in `spare_kv_pages`.
+    fn roll_back(&mut self, state: &ContextState, keep_pages: bool) {
+        assert_eq!(
+            *state,
+            self.state_at(state.token_count),
+            "Inconsistent context state"
+        );
+        assert!(
+            state.token_count <= self.token_ids.len(),
+            "Cannot restore to a state with more tokens than the context holds"
+        );
+
+        self.trimmed_stop = None;
+        let committed = self.token_ids.len();
+        let total = committed + self.token_ids_pending.len();
+
+        self.token_ids.truncate(state.token_count);
+        self.position_ids.truncate(state.token_count);
+        if self
+            .system_range
+            .as_ref()
+            .is_some_and(|range| range.end > state.token_count)
+        {
+            self.system_range = None;
+        }
+        self.token_ids_pending.clear();
+        self.token_ids_forced.clear();
+        self.token_mask_pending.clear();
+        self.token_mask_current
+            .remove_range(state.token_count, total);
+
+        if keep_pages {
+            let surplus = self.kv_pages.split_off(state.page_count);
+            self.spare_kv_pages
+                .extend(surplus.into_iter().filter(|page| !page.is_shared()));
+            self.kv_page_last_len = state.last_len;
+        } else {
+            self.shrink_kv_pages(committed - state.token_count);
+        }
+    }
+
+    /// Creates a copy-on-write fork of the context.
+    ///
+    /// The fork shares every KV page of the current context, including a partially
+    /// filled last page, so nothing is recomputed and the parent stays usable: both
+    /// contexts can be extended and generated from independently, and each releases its
+    /// handles to the shared pages when dropped, without `mem::forget`. Before a forward
+    /// pass writes into a partially filled page that is still shared, the writing
+    /// context replaces it with a [`KvPage::deep_copy`], so the other one never sees
+    /// the new tokens. Pending tokens and chat messages are copied as they are.
+    pub fn fork(&self) -> Self {
         Context {
             queue: self.model.create_queue(),
             model: self.model.clone(),
             tokenizer: self.tokenizer.clone(),
             formatter: self.formatter.clone(),
-            token_ids: new_tokens,
-            token_ids_pending: new_pending,
-            token_mask_pending: new_mask_pending,
+            token_ids: self.token_ids.clone(),
+            token_ids_pending: self.token_ids_pending.clone(),
+            token_ids_forced: self.token_ids_forced.clone(),
+            token_mask_pending: self.token_mask_pending.clone(),
             token_mask_current: self.token_mask_current.clone(),
-            position_ids: new_pos_ids,
-            kv_pages: new_kv_page_ptrs,
-            kv_page_last_len: new_kv_page_last_len,
+            position_ids: self.position_ids.clone(),
+            kv_pages: self.kv_pages.clone(),
+            kv_page_last_len: self.kv_page_last_len,
             kv_page_size: self.kv_page_size,
+            spare_kv_pages: Vec::new(),
             adapter_ptr: self.adapter_ptr,
             adapter_random_seed: self.adapter_random_seed,
             begin_of_sequence: self.begin_of_sequence,
+            system_range: self.system_range.clone(),
+            finish_reason: None,
+            keep_stop_tokens: self.keep_stop_tokens,
+            trimmed_stop: self.trimmed_stop.clone(),
+            on_page_alloc: self.on_page_alloc.clone(),
+            last_output: self.last_output.clone(),
+            yield_interval: self.yield_interval,
         }
     }
 
@@ -231,6 +1092,7 @@ impl Context {
 
     pub fn fill_tokens(&mut self, new_token_ids: Vec<u32>) {
         let n = new_token_ids.len();
+        self.trimmed_stop = None;
         self.token_ids_pending.extend(new_token_ids);
 
         for _ in 0..n {
@@ -243,6 +1105,7 @@ impl Context {
     }
 
     pub fn fill_token(&mut self, new_token_id: u32) {
+        self.trimmed_stop = None;
         self.token_ids_pending.push(new_token_id);
         self.token_mask_current.append(false);
         self.token_mask_pending
@@ -251,8 +1114,49 @@ impl Context {
     }
 
     pub fn fill_system(&mut self, text: &str) {
+        let start = self.token_ids.len() + self.token_ids_pending.len();
         self.formatter.system(text);
         self.flush_chat_messages2(false);
+        self.system_range = Some(start..self.token_ids.len() + self.token_ids_pending.len());
+    }
+
+    /// Replaces the system prompt filled with [`Context::fill_system`], keeping the
+    /// rest of the conversation.
+    ///
+    /// Every token after a changed token has to be recomputed, so the cost is a
+    /// prefill from the start of the system prompt to the end of the context: the KV
+    /// cache is rolled back to that boundary and the new system prompt, followed by
+    /// the tokens that came after the old one, is filled as pending. Positions after
+    /// the system prompt shift by the difference in its length, and token masks set
+    /// after its start are cleared.
+    ///
+    /// Fails if no system prompt was filled, or if it was rolled back (e.g. with
+    /// [`Context::restore_state`]) since.
+    pub fn replace_system(&mut self, new_system: &str) -> Result<(), Error> {
+        let Some(range) = self.system_range.clone() else {
+            return Err(Error::InvalidArgument(
+                "No system prompt to replace".to_string(),
+            ));
+        };
+
+        let all_token_ids = [&self.token_ids[..], &self.token_ids_pending[..]].concat();
+        let kept = range.start.min(self.token_ids.len());
+        let kept_pending = all_token_ids[kept..range.start].to_vec();
+        let rest = all_token_ids[range.end..].to_vec();
+
+        self.formatter.system(new_system);
+        let rendered =
+            self.formatter
+                .render(&self.model.get_prompt_template(), false, range.start == 0);
+        self.formatter.clear();
+
+        self.restore_state(&self.state_at(kept));
+        self.fill_tokens(kept_pending);
+        self.fill(&rendered);
+        let end = self.token_ids.len() + self.token_ids_pending.len();
+        self.fill_tokens(rest);
+        self.system_range = Some(range.start..end);
+        Ok(())
     }
 
     pub fn fill_user(&mut self, text: &str) {
@@ -260,6 +1164,107 @@ impl Context {
         self.flush_chat_messages2(true);
     }
 
+    /// Fills a user message like [`Context::fill_user`], healing the token boundary
+    /// with what the context already holds.
+    ///
+    /// See [`Context::fill_healed`].
+    pub fn fill_user_healed(&mut self, text: &str) {
+        self.formatter.user(text);
+        if let Some(p) = self.render_chat_messages(true) {
+            self.fill_healed(&p);
+        }
+    }
+
+    /// Fills `text` with token healing.
+    ///
+    /// Appending text that was tokenized on its own can split a word at a boundary
+    /// the tokenizer would never produce (e.g. `"hel"` + `"lo"` instead of `"hello"`),
+    /// which hurts generation quality. To avoid this, the last token of the context
+    /// is trimmed and its text is re-tokenized jointly with `text`.
+    ///
+    /// If the trimmed token was already forwarded, the context is rolled back by one
+    /// position and the healed tokens are forwarded from there, overwriting its KV
+    /// entry. Either way, the tokens after the boundary are placed relative to the
+    /// trimmed token, so they shift by the difference between the joint and separate
+    /// token counts (usually zero or one position earlier).
+    ///
+    /// A last token holding only part of a multi-byte character cannot be rebuilt from
+    /// its text, so in that case `text` is filled without healing.
+    pub fn fill_healed(&mut self, text: &str) {
+        let boundary = self
+            .token_ids_pending
+            .last()
+            .or(self.token_ids.last())
+            .map(|&id| self.tokenizer.detokenize(&[id]));
+        match healed_text(boundary.as_deref(), text) {
+            Some(healed) => {
+                self.trim_last_token();
+                self.fill(&healed);
+            }
+            None => self.fill(text),
+        }
+    }
+
+    /// Removes the last token of the context, rolling the KV cache back if the token
+    /// was already forwarded.
+    fn trim_last_token(&mut self) {
+        if self.token_ids_pending.pop().is_some() {
+            self.token_mask_pending.pop();
+            let len = self.token_ids.len() + self.token_ids_pending.len();
+            self.token_mask_current.remove_range(len, len + 1);
+            if self
+                .system_range
+                .as_ref()
+                .is_some_and(|range| range.end > len)
+            {
+                self.system_range = None;
+            }
+            return;
+        }
+        if self.token_ids.is_empty() {
+            return;
+        }
+
+        self.restore_state(&self.state_at(self.token_ids.len() - 1));
+    }
+
+    /// Removes the stop sequence that ended the last generation from the end of the
+    /// context and returns how many tokens were removed.
+    ///
+    /// The `generate*` methods call this when their stop condition matches a sequence,
+    /// unless [`Context::keep_stop_tokens`] is set, so neither the returned text nor the
+    /// token IDs (e.g. the ones stored in [`AgentMeta`](crate::agent::AgentMeta) for
+    /// a continuation to prefill) end with the consumed EOS. Committed stop tokens are
+    /// rolled back like in [`Context::restore_state`]. If the sequence is one of the
+    /// model's EOS sequences, it is filled again before the next chat message, so a
+    /// conversation continued in this context still closes the assistant turn.
+    ///
+    /// Does nothing if the last generation did not end on a stop sequence, if the
+    /// context no longer ends with it, or if it was already trimmed.
+    pub fn trim_trailing_stop(&mut self) -> usize {
+        let Some(FinishReason::Stop(Some(sequence))) = &self.finish_reason else {
+            return 0;
+        };
+        if self.trimmed_stop.is_some() {
+            return 0;
+        }
+        let sequence = sequence.clone();
+        let ends_with_stop = [&self.token_ids[..], &self.token_ids_pending[..]]
+            .concat()
+            .ends_with(&sequence);
+        if sequence.is_empty() || !ends_with_stop {
+            return 0;
+        }
+
+        for _ in 0..sequence.len() {
+            self.trim_last_token();
+        }
+        if self.model.eos_tokens().contains(&sequence) {
+            self.trimmed_stop = Some(sequence.clone());
+        }
+        sequence.len()
+    }
+
     pub fn fill_user_only(&mut self, text: &str) {
         self.formatter.user(text);
         self.flush_chat_messages2(false);
@@ -270,6 +1275,59 @@ impl Context {
         self.flush_chat_messages2(false);
     }
 
+    /// Fills a whole conversation, rendering it through the chat template in one go.
+    ///
+    /// Like the other `fill_*` methods, this only queues the tokens: everything pending
+    /// is prefilled in a single forward pass by the next [`Context::flush`] or
+    /// generation, so a few-shot prompt costs one prefill however many turns it has.
+    /// The tokens can differ from separate [`Context::fill_user`] and
+    /// [`Context::fill_assistant`] calls, which render each turn on its own (the former
+    /// appending a generation prompt every time); rendering the conversation at once
+    /// yields exactly what the template produces for it.
+    ///
+    /// A generation prompt is appended when the last message is from the user.
+    pub fn fill_messages(&mut self, messages: &[ChatMessage]) {
+        for message in messages {
+            self.formatter.message(message);
+        }
+        let add_generation_prompt = matches!(messages.last(), Some(ChatMessage::User(_)));
+        self.flush_chat_messages2(add_generation_prompt);
+    }
+
+    /// Forces the model's response to begin with `text`.
+    ///
+    /// The prefix tokens are appended to the context (and committed to the KV cache on
+    /// the next forward pass) as if the model had generated them. Unlike
+    /// [`Context::fill_assistant`], they are counted as generated output: the next call
+    /// to [`Context::generate`] includes them in its returned text and in the token
+    /// sequence passed to its stop condition.
+    ///
+    /// If the prefix contains one of the model's EOS sequences, it is cut right after the
+    /// first such sequence. A subsequent `generate` whose stop condition matches the EOS
+    /// then returns the prefix without decoding any further tokens.
+    pub fn force_prefix(&mut self, text: &str) {
+        let mut token_ids = self.tokenizer.tokenize(text);
+
+        let eos_end = self
+            .model
+            .eos_tokens()
+            .iter()
+            .filter(|eos| !eos.is_empty())
+            .filter_map(|eos| {
+                token_ids
+                    .windows(eos.len())
+                    .position(|w| w == eos.as_slice())
+                    .map(|start| start + eos.len())
+            })
+            .min();
+        if let Some(end) = eos_end {
+            token_ids.truncate(end);
+        }
+
+        self.token_ids_forced.extend(&token_ids);
+        self.fill_tokens(token_ids);
+    }
+
     pub fn mask_tokens(&mut self, indices: &[usize], mask: bool) {
         self.token_mask_current.mask(indices, mask)
     }
@@ -282,11 +1340,85 @@ impl Context {
         self.token_mask_current.mask(&[index], mask)
     }
 
-    /// Drops fully masked KV pages to save memory, supporting non-contiguous
-    /// dropping for optimizations like attention sink.
+    /// Appends the KV `pages` of another context (e.g. an imported reference branch)
+    /// and attends to them with roughly `weight` times their normal influence.
     ///
-    /// The function iterates through all committed pages and checks if the tokens
-    /// corresponding to a page are all masked as `true`. If so, it deallocates
+    /// `token_ids` are the tokens the pages hold and must fill them completely, and the
+    /// context must have no pending tokens and a full (or no) last page, so the pages
+    /// are appended whole and never written to. Returns the token range they occupy.
+    ///
+    /// The weight is applied by masking, not by scaling attention scores, which the
+    /// backend cannot do: a `1 - weight` fraction of the appended tokens, spread evenly
+    /// over them, is masked, which shrinks the share of attention the pages can take. A
+    /// weight of 0 masks every appended token and is equivalent to not attending to the
+    /// pages at all, and a weight of 1 attends to them like to any other tokens.
+    ///
+    /// The appended tokens are given the positions right after the context's last
+    /// token, and the following tokens continue after them. The cached keys in the
+    /// pages are not recomputed, so they keep the positional encoding of wherever they
+    /// were originally computed.
+    #[cfg(feature = "experimental")]
+    pub fn attend_to(
+        &mut self,
+        pages: &[KvPage],
+        token_ids: &[u32],
+        weight: f32,
+    ) -> Result<Range<usize>, Error> {
+        if !(0.0..=1.0).contains(&weight) {
+            return Err(Error::InvalidArgument(format!(
+                "Attention weight must be within [0, 1], got {}",
+                weight
+            )));
+        }
+        if token_ids.len() != pages.len() * self.kv_page_size {
+            return Err(Error::InvalidArgument(format!(
+                "{} tokens do not fill {} KV pages of {} tokens",
+                token_ids.len(),
+                pages.len(),
+                self.kv_page_size
+            )));
+        }
+        if !self.token_ids_pending.is_empty() {
+            return Err(Error::InvalidArgument(
+                "Cannot attend to KV pages while tokens are pending".to_string(),
+            ));
+        }
+        if !self.kv_pages.is_empty() && self.kv_page_last_len != self.kv_page_size {
+            return Err(Error::InvalidArgument(
+                "Cannot attend to KV pages after a partially filled page".to_string(),
+            ));
+        }
+        if pages.is_empty() {
+            let end = self.token_ids.len();
+            return Ok(end..end);
+        }
+
+        let start = self.token_ids.len();
+        let next_pos = self.position_ids.last().map(|&p| p + 1).unwrap_or(0);
+        self.token_ids.extend_from_slice(token_ids);
+        self.position_ids
+            .extend(next_pos..next_pos + token_ids.len() as u32);
+        self.kv_pages.extend_from_slice(pages);
+        self.kv_page_last_len = self.kv_page_size;
+        for _ in 0..token_ids.len() {
+            self.token_mask_current.append(false);
+        }
+
+        let count = token_ids.len();
+        let indices: Vec<usize> = weight_masked_offsets(count, weight)
+            .into_iter()
+            .map(|i| start + i)
+            .collect();
+        self.mask_tokens(&indices, true);
+
+        Ok(start..start + count)
+    }
+
+    /// Drops fully masked KV pages to save memory, supporting non-contiguous
+    /// dropping for optimizations like attention sink.
+    ///
+    /// The function iterates through all committed pages and checks if the tokens
+    /// corresponding to a page are all masked as `true`. If so, it deallocates
     /// the page and removes the corresponding token ranges from the context's state.
     ///
     /// # Warning
@@ -329,6 +1461,15 @@ impl Context {
                 for mask in &mut self.token_mask_pending {
                     mask.remove_range(page_start_token_idx, page_end_token_idx);
                 }
+
+                // 5. Keep the system prompt's range pointing at the same tokens.
+                self.system_range = match self.system_range.take() {
+                    Some(range) if range.start >= page_end_token_idx => {
+                        Some(range.start - self.kv_page_size..range.end - self.kv_page_size)
+                    }
+                    Some(range) if range.end <= page_start_token_idx => Some(range),
+                    _ => None,
+                };
             }
         }
 
@@ -354,8 +1495,15 @@ impl Context {
     /// * `num_tokens`: The number of tokens to add or remove. A positive value
     ///   grows the KV cache, while a negative value shrinks it.
     fn adjust_kv_pages(&mut self, num_tokens: isize) {
+        self.try_adjust_kv_pages(num_tokens)
+            .unwrap_or_else(|e| panic!("{}", e))
+    }
+
+    /// Like `adjust_kv_pages`, but fails with [`Error::QuotaExceeded`] and leaves the
+    /// KV cache unchanged if growing it would exceed the quota of the context's model.
+    fn try_adjust_kv_pages(&mut self, num_tokens: isize) -> Result<(), Error> {
         if num_tokens == 0 {
-            return;
+            return Ok(());
         }
 
         let current_tokens = if self.kv_pages.is_empty() {
@@ -375,10 +1523,22 @@ impl Context {
 
         match required_pages.cmp(&current_pages) {
             Ordering::Greater => {
-                // Grow: Allocate new pages if more are needed.
+                self.model.quota().check_pages(required_pages)?;
+                // Grow: Reuse spare pages first, then allocate new pages if more are needed.
                 let new_pages_needed = required_pages - current_pages;
-                let new_kv_page_ids = self.queue.new_kv_pages(new_pages_needed);
-                self.kv_pages.extend(new_kv_page_ids);
+                let reused = new_pages_needed.min(self.spare_kv_pages.len());
+                let spare_start = self.spare_kv_pages.len() - reused;
+                self.kv_pages
+                    .extend(self.spare_kv_pages.drain(spare_start..));
+                if new_pages_needed > reused {
+                    let new_kv_page_ids = self.queue.new_kv_pages(new_pages_needed - reused);
+                    self.kv_pages.extend(new_kv_page_ids);
+                    report_page_alloc(
+                        &self.on_page_alloc,
+                        new_pages_needed - reused,
+                        self.kv_pages.len(),
+                    );
+                }
             }
             Ordering::Less => {
                 // Shrink: Deallocate pages that are no longer needed.
@@ -396,35 +1556,76 @@ impl Context {
         } else {
             last_page_len
         };
+        Ok(())
+    }
+
+    /// Replaces a partially filled last KV page that another context also holds with a
+    /// private copy, so the next forward pass does not write into the other context's
+    /// cache. Full pages are never written again and stay shared.
+    async fn unshare_last_kv_page(&mut self) {
+        if self.last_kv_page_needs_copy() {
+            let copy = self.kv_pages.last().unwrap().deep_copy(&self.queue).await;
+            *self.kv_pages.last_mut().unwrap() = copy;
+            report_page_alloc(&self.on_page_alloc, 1, self.kv_pages.len());
+        }
     }
 
     pub fn grow_kv_pages(&mut self, num_tokens: usize) {
         self.adjust_kv_pages(num_tokens as isize);
     }
 
+    /// Like [`Context::grow_kv_pages`], but fails with [`Error::QuotaExceeded`] instead
+    /// of panicking if the context would hold more KV pages than its quota allows.
+    pub fn try_grow_kv_pages(&mut self, num_tokens: usize) -> Result<(), Error> {
+        self.try_adjust_kv_pages(num_tokens as isize)
+    }
+
     pub fn shrink_kv_pages(&mut self, num_tokens: usize) {
         // Convert the number of tokens to a negative adjustment for shrinking.
         self.adjust_kv_pages(-(num_tokens as isize));
     }
 
     fn flush_chat_messages2(&mut self, add_generation_prompt: bool) {
-        if self.formatter.has_messages() {
-            let p = self.formatter.render(
-                &self.model.get_prompt_template(),
-                add_generation_prompt,
-                self.begin_of_sequence,
-            );
-            self.begin_of_sequence = false;
-            self.formatter.clear();
+        if let Some(p) = self.render_chat_messages(add_generation_prompt) {
             self.fill(&p);
         }
     }
 
+    fn render_chat_messages(&mut self, add_generation_prompt: bool) -> Option<String> {
+        if !self.formatter.has_messages() {
+            return None;
+        }
+        if let Some(stop) = self.trimmed_stop.take() {
+            self.fill_tokens(stop);
+        }
+        let p = self.formatter.render(
+            &self.model.get_prompt_template(),
+            add_generation_prompt,
+            self.begin_of_sequence,
+        );
+        self.begin_of_sequence = false;
+        self.formatter.clear();
+        Some(p)
+    }
+
     /// Processes a batch of pending tokens to update the model's internal state.
+    ///
+    /// # Panics
+    ///
+    /// Panics if the pending tokens do not fit in the KV page quota of the context's
+    /// model, see [`Context::try_flush`].
     pub async fn flush(&mut self) {
+        self.try_flush().await.unwrap_or_else(|e| panic!("{}", e))
+    }
+
+    /// Like [`Context::flush`], but fails with [`Error::QuotaExceeded`] and keeps the
+    /// tokens pending if forwarding them would exceed the KV page quota of the
+    /// context's model.
+    pub async fn try_flush(&mut self) -> Result<(), Error> {
         if self.token_ids_pending.is_empty() {
-            return;
+            return Ok(());
         }
+        self.model.quota().check_pages(self.pages_needed())?;
         let process_count = self.token_ids_pending.len();
 
         // Process all but the last pending token, leaving it for the next generation step.
@@ -443,6 +1644,7 @@ impl Context {
         let position_ids =
             (last_pos..(last_pos + pending_token_ids.len() as u32)).collect::<Vec<u32>>();
 
+        self.unshare_last_kv_page().await;
         self.grow_kv_pages(pending_token_ids.len());
 
         // println!("pending token ids: {:?}", &pending_token_ids);
@@ -459,6 +1661,34 @@ impl Context {
         self.token_ids.extend(pending_token_ids);
         self.position_ids.extend(&position_ids);
         // self.queue.deallocate_embeds(&embed_ids);
+        Ok(())
+    }
+
+    /// Commits all pending tokens except the last one, which is kept to seed the next
+    /// decode step.
+    ///
+    /// Useful before scoring or generating from the same prefix several times, so the
+    /// prefix is forwarded once instead of once per fork.
+    pub async fn flush_prefix(&mut self) {
+        if self.token_ids_pending.len() < 2 {
+            return;
+        }
+        let last_token_id = self.token_ids_pending.pop().unwrap();
+        let last_mask = self.token_mask_pending.pop().unwrap();
+        self.flush().await;
+        self.token_ids_pending.push(last_token_id);
+        self.token_mask_pending.push(last_mask);
+    }
+
+    /// Populates the KV cache for the filled prompt without generating anything.
+    ///
+    /// Warm a shared prefix once, then [`Context::fork`] it: the forks share the
+    /// prefilled pages, and their `generate` only forwards what was filled after the
+    /// prefill. The last token stays pending as the seed of the next decode step, which
+    /// is all a subsequent `generate` processes of the prefilled prompt. Does nothing
+    /// if fewer than two tokens are pending.
+    pub async fn prefill(&mut self) {
+        self.flush_prefix().await;
     }
 
     /// Performs a single, atomic autoregressive decoding step.
@@ -476,6 +1706,26 @@ impl Context {
     /// A `Result` containing the `Distribution` over the next possible tokens,
     /// or an error if the generation step could not be performed.
     pub async fn decode_step(&mut self, sampler: &Sampler) -> u32 {
+        let sampler = OverriddenSampler::new(sampler, &SamplerOverrides::default());
+        self.decode_step_observed(&sampler, false, None)
+            .await
+            .expect("Forward pass produced no output")
+            .0
+    }
+
+    /// Like [`Context::decode_step`], additionally returning the temperature 1.0
+    /// distribution the token was sampled from when `with_distribution` is set, holding
+    /// the `distribution_top_k` most likely tokens (the backend's default if `None`).
+    ///
+    /// Returns `None` if the backend produced no output for the pass. The pending
+    /// tokens are recorded as committed either way, so after a failure the context's
+    /// tokens no longer match its KV cache and it should only be used to rebuild from.
+    async fn decode_step_observed(
+        &mut self,
+        sampler: &OverriddenSampler<'_>,
+        with_distribution: bool,
+        distribution_top_k: Option<u32>,
+    ) -> Option<(u32, Option<Distribution>)> {
         assert!(
             !self.token_ids_pending.is_empty(),
             "Must have at least one seed token"
@@ -486,6 +1736,7 @@ impl Context {
         let position_ids =
             (last_pos_id..(last_pos_id + pending_token_ids.len() as u32)).collect::<Vec<u32>>();
 
+        self.unshare_last_kv_page().await;
         self.grow_kv_pages(pending_token_ids.len());
 
         // println!("next token id: {}", next_token_id);
@@ -513,57 +1764,64 @@ impl Context {
         p.input_tokens(&pending_token_ids, &position_ids);
         p.kv_cache(&self.kv_pages, self.kv_page_last_len);
         p.attention_mask(&mask);
+        if sampler.logit_precision() == LogitPrecision::Fp32 {
+            p.set_logit_precision(LogitPrecision::Fp32);
+        }
 
         let output_idx = pending_token_ids.len() as u32 - 1;
-        match sampler {
-            Sampler::Custom {
-                temperature,
-                sampler: _sampler,
-            } => {
-                p.output_distributions(&[output_idx], *temperature, None);
+        match sampler.native() {
+            None => {
+                p.output_distributions(&[output_idx], sampler.distribution_temperature(), None);
             }
-            Sampler::Multinomial { temperature } => {
+            Some(Sampler::Custom { .. }) => unreachable!("Custom samplers always sample locally"),
+            Some(Sampler::Multinomial { temperature }) => {
                 p.output_tokens(&[output_idx], *temperature);
             }
-            Sampler::TopP { temperature, top_p } => {
+            Some(Sampler::TopP { temperature, top_p }) => {
                 p.output_tokens_top_p(&[output_idx], *temperature, *top_p);
             }
-            Sampler::TopK { temperature, top_k } => {
+            Some(Sampler::TopK { temperature, top_k }) => {
                 p.output_tokens_top_k(&[output_idx], *temperature, *top_k);
             }
-            Sampler::MinP { temperature, min_p } => {
+            Some(Sampler::MinP { temperature, min_p }) => {
                 p.output_tokens_min_p(&[output_idx], *temperature, *min_p);
             }
-            Sampler::TopKTopP {
+            Some(Sampler::TopKTopP {
                 temperature,
                 top_k,
                 top_p,
-            } => {
+            }) => {
                 p.output_tokens_top_k_top_p(&[output_idx], *temperature, *top_k, *top_p);
             }
         }
+        if with_distribution {
+            // Requested after the sampler's own output, so it is always the last one.
+            p.output_distributions(&[output_idx], 1.0, distribution_top_k);
+        }
 
         let res = p.execute().await;
+        let mut distributions = res.distributions.unwrap_or_default();
+        let observed = if with_distribution {
+            distributions.pop()
+        } else {
+            None
+        };
 
-        let sampled = match sampler {
-            Sampler::Custom {
-                temperature: _temperature,
-                sampler,
-            } => {
-                let dist = res.distributions.unwrap().into_iter().next().unwrap();
-                let sampled = sampler.sample(&dist.ids, &dist.probs);
-                sampled
-            }
-            _ => {
-                let sampled = res.tokens.unwrap().into_iter().next().unwrap();
-                sampled
-            }
+        let sampled = match sampler.native() {
+            None => distributions
+                .into_iter()
+                .next()
+                .map(|dist| sampler.sample(&dist.ids, &dist.probs)),
+            Some(_) => res.tokens.and_then(|tokens| tokens.into_iter().next()),
         };
 
         self.token_ids.extend(pending_token_ids);
         self.position_ids.extend(position_ids);
 
-        sampled
+        if with_distribution && observed.is_none() {
+            return None;
+        }
+        sampled.map(|sampled| (sampled, observed))
     }
 
     /// Performs a single, atomic autoregressive decoding step.
@@ -581,6 +1839,77 @@ impl Context {
     /// A `Result` containing the `Distribution` over the next possible tokens,
     /// or an error if the generation step could not be performed.
     pub async fn decode_step_dist(&mut self) -> Distribution {
+        self.decode_step_dist_with_temperature(1.0).await
+    }
+
+    /// Forwards the pending tokens and captures the distribution over the next token,
+    /// e.g. to expand several children of a search tree from one forward pass.
+    ///
+    /// The pending tokens are committed like in [`Context::decode_step_dist`], so the
+    /// context is left without a seed token until one is drawn with
+    /// [`Context::sample_from`]. To branch, [`Context::fork`] the context once per
+    /// child and sample each child from the same checkpoint.
+    ///
+    /// The backend is asked for the whole vocabulary instead of its top 32, so the
+    /// checkpoint serves any sampler, but it caps distributions at its `max_dist_size`
+    /// (64 by default). See [`LogitsCheckpoint`] for the memory a checkpoint takes.
+    pub async fn checkpoint_logits(&mut self) -> LogitsCheckpoint {
+        let (vocab_ids, _) = self.tokenizer.get_vocabs();
+        let output_idx = self.token_ids_pending.len().saturating_sub(1) as u32;
+        let dist = self
+            .forward_pending_dists(&[output_idx], 1.0, Some(vocab_ids.len() as u32))
+            .await
+            .into_iter()
+            .next()
+            .expect("Forward pass produced no output");
+        LogitsCheckpoint {
+            token_count: self.token_ids.len(),
+            ids: dist.ids,
+            logits: dist.probs.iter().map(|p| p.ln()).collect(),
+        }
+    }
+
+    /// Draws the next token from `checkpoint` with `sampler`, without a forward pass,
+    /// and fills it as the seed token of the next decode step.
+    ///
+    /// Sampling happens on the client with the same rules as [`Sampler::sample`], over
+    /// the candidates the checkpoint holds.
+    ///
+    /// # Panics
+    ///
+    /// Panics if the context has pending tokens or committed tokens beyond the point the
+    /// checkpoint was taken at, i.e. it does not continue from `checkpoint`.
+    pub fn sample_from(&mut self, checkpoint: &LogitsCheckpoint, sampler: &Sampler) -> u32 {
+        assert!(
+            self.token_ids_pending.is_empty() && self.token_ids.len() == checkpoint.token_count,
+            "The context does not continue from the checkpoint ({} committed and {} pending \
+             tokens, checkpoint taken after {})",
+            self.token_ids.len(),
+            self.token_ids_pending.len(),
+            checkpoint.token_count
+        );
+        let token_id = sampler.sample_sparse(&checkpoint.ids, &checkpoint.logits);
+        self.fill_token(token_id);
+        token_id
+    }
+
+    async fn decode_step_dist_with_temperature(&mut self, temperature: f32) -> Distribution {
+        let output_idx = self.token_ids_pending.len().saturating_sub(1) as u32;
+        self.forward_pending_dists(&[output_idx], temperature, None)
+            .await
+            .into_iter()
+            .next()
+            .unwrap()
+    }
+
+    /// Commits all pending tokens in one forward pass and returns the distributions at
+    /// `output_indices`, which are offsets into the pending tokens.
+    async fn forward_pending_dists(
+        &mut self,
+        output_indices: &[u32],
+        temperature: f32,
+        top_k: Option<u32>,
+    ) -> Vec<Distribution> {
         assert!(
             !self.token_ids_pending.is_empty(),
             "Must have at least one seed token"
@@ -591,6 +1920,7 @@ impl Context {
         let position_ids =
             (last_pos_id..(last_pos_id + pending_token_ids.len() as u32)).collect::<Vec<u32>>();
 
+        self.unshare_last_kv_page().await;
         self.grow_kv_pages(pending_token_ids.len());
 
         // println!("next token id: {}", next_token_id);
@@ -600,78 +1930,949 @@ impl Context {
         // println!("token ids: {:?}", &self.token_ids);
         // println!("token ids pending: {:?}", &self.token_ids_pending);
 
-        let mask = mem::take(&mut self.token_mask_pending)
-            .into_iter()
-            .map(|brie| brie.buffer)
-            .collect::<Vec<Vec<u32>>>();
+        let mask = mem::take(&mut self.token_mask_pending)
+            .into_iter()
+            .map(|brie| brie.buffer)
+            .collect::<Vec<Vec<u32>>>();
+
+        let p = self.queue.create_forward_pass();
+
+        if let Some(adapter_ptr) = self.adapter_ptr {
+            p.set_adapter(adapter_ptr);
+
+            if let Some(adapter_random_seed) = self.adapter_random_seed {
+                p.set_adapter_seed(adapter_random_seed);
+            }
+        }
+
+        p.input_tokens(&pending_token_ids, &position_ids);
+        p.kv_cache(&self.kv_pages, self.kv_page_last_len);
+        p.attention_mask(&mask);
+
+        p.output_distributions(output_indices, temperature, top_k);
+
+        let res = p.execute().await;
+
+        let dists = res.distributions.unwrap();
+
+        self.token_ids.extend(pending_token_ids);
+        self.position_ids.extend(position_ids);
+
+        dists
+    }
+
+    /// Returns the log-probability of the model continuing the context with
+    /// `continuation`, without committing anything to the context.
+    ///
+    /// The pending tokens and `continuation` are forwarded together on a
+    /// [`Context::fork`], and the log-probabilities of the continuation tokens are
+    /// summed. The backend only reports the most likely candidates at each position;
+    /// a continuation token outside of them is assigned the smallest reported
+    /// probability, which overestimates its true probability.
+    pub async fn continuation_log_prob(&self, continuation: &[u32]) -> f32 {
+        assert!(
+            !self.token_ids_pending.is_empty(),
+            "Must have at least one seed token"
+        );
+        if continuation.is_empty() {
+            return 0.0;
+        }
+
+        let mut probe = self.fork();
+        let first_idx = probe.token_ids_pending.len() as u32 - 1;
+        probe.fill_tokens(continuation.to_vec());
+        let output_indices = (first_idx..first_idx + continuation.len() as u32).collect::<Vec<_>>();
+
+        let dists = probe
+            .forward_pending_dists(&output_indices, 1.0, Some(CONTINUATION_TOP_K))
+            .await;
+
+        token_log_probs(continuation, &dists).into_iter().sum()
+    }
+
+    /// Returns the `top_k` most likely next tokens under `sampler`, without committing
+    /// anything to the context.
+    ///
+    /// The pending tokens are forwarded on a [`Context::fork`], and the resulting
+    /// distribution is scaled by the sampler's temperature and truncated by its
+    /// top-k / top-p / min-p rules before being renormalized, so the probabilities
+    /// reflect what the sampler actually draws from. A temperature of zero yields the
+    /// single greedy token with probability one.
+    ///
+    /// This is a debugging aid: every call costs a full forward pass over the
+    /// pending tokens (and a copy of the last partial KV page, which the fork shares).
+    pub async fn peek_next_distribution(&self, sampler: &Sampler, top_k: usize) -> Vec<(u32, f32)> {
+        assert!(
+            !self.token_ids_pending.is_empty(),
+            "Must have at least one seed token"
+        );
+
+        let mut probe = self.fork();
+        let temperature = sampler.temperature();
+        let dist = if temperature > 0.0 {
+            probe.decode_step_dist_with_temperature(temperature).await
+        } else {
+            probe.decode_step_dist().await
+        };
+
+        let mut entries = if temperature > 0.0 {
+            sampler.filter(&dist.ids, &dist.probs)
+        } else {
+            dist.argmax().map(|id| (id, 1.0)).into_iter().collect()
+        };
+        entries.truncate(top_k);
+        entries
+    }
+
+    /// Generates text autoregressively until a stop condition is met.
+    ///
+    /// This function drives the text generation loop. In each iteration, it calls
+    /// `decode_step()` to get a probability distribution, uses the provided `sampler`
+    /// to choose the next token, and adds it to the context. The loop continues
+    /// until the `stop_condition` signals that generation should end, or until the
+    /// next token would no longer fit in the model's context window. The cause is
+    /// available afterwards through [`Context::finish_reason`].
+    ///
+    /// # Arguments
+    ///
+    /// * `sampler`: A mutable reference to a struct implementing the `Sampler` trait,
+    ///   which will be used to sample a token from the model's output distribution at each step.
+    /// * `stop_condition`: A mutable reference to a struct implementing the `StopCondition`
+    ///   trait, which determines when to halt the generation process.
+    ///
+    /// # Returns
+    ///
+    /// A `Result` containing the generated `String` upon successful completion. Special
+    /// tokens are left out of it, see [`Context::generate_with_decode_options`].
+    pub async fn generate<S: StopCondition>(
+        &mut self,
+        sampler: Sampler,
+        stop_condition: S,
+    ) -> String {
+        self.generate_with_overrides(&sampler, stop_condition, SamplerOverrides::default())
+            .await
+    }
+
+    /// Generates text like [`Context::generate`], driving the generation loop to
+    /// completion on the current thread, e.g. to time it in a benchmark or a simple
+    /// synchronous script.
+    ///
+    /// This starts its own event loop with [`crate::block_on`], so it must not be called
+    /// from async code, including the body of an `#[inferlet::main]` function: nested
+    /// event loops are not supported and the call panics (with a dedicated assertion in
+    /// debug builds). The context is moved into the loop for its duration, which is why
+    /// the stop condition has to be `'static`.
+    pub fn generate_blocking<S: StopCondition + 'static>(
+        &mut self,
+        sampler: Sampler,
+        stop_condition: S,
+    ) -> String {
+        debug_assert!(
+            !crate::in_event_loop(),
+            "Context::generate_blocking must not be called from async code"
+        );
+        let mut ctx = mem::replace(self, self.empty_shell());
+        let (ctx, text) = crate::block_on(async move {
+            let text = ctx.generate(sampler, stop_condition).await;
+            (ctx, text)
+        });
+        *self = ctx;
+        text
+    }
+
+    /// Returns a context on the same queue that holds nothing, used as a stand-in while
+    /// the real one is moved out of `self`. Unlike [`Context::new`], it makes no host
+    /// calls.
+    fn empty_shell(&self) -> Context {
+        Context {
+            queue: self.queue.clone(),
+            model: self.model.clone(),
+            tokenizer: self.tokenizer.clone(),
+            formatter: ChatFormatter::new(),
+            token_ids: Vec::new(),
+            token_ids_pending: Vec::new(),
+            token_ids_forced: Vec::new(),
+            token_mask_pending: Vec::new(),
+            token_mask_current: Brle::new(0),
+            position_ids: Vec::new(),
+            kv_pages: Vec::new(),
+            kv_page_last_len: 0,
+            kv_page_size: self.kv_page_size,
+            spare_kv_pages: Vec::new(),
+            adapter_ptr: None,
+            adapter_random_seed: None,
+            begin_of_sequence: true,
+            system_range: None,
+            finish_reason: None,
+            keep_stop_tokens: false,
+            trimmed_stop: None,
+            on_page_alloc: None,
+            last_output: None,
+            yield_interval: 1,
+        }
+    }
+
+    /// Generates a reply like [`Context::generate`], running `processors` over the logits
+    /// of every decode step, in order, before `sampler` selects the token.
+    ///
+    /// See [`Sampler::with_processors`] for how the processors see the candidates.
+    pub async fn generate_with_processors<S: StopCondition>(
+        &mut self,
+        sampler: Sampler,
+        stop_condition: S,
+        processors: Vec<Box<dyn LogitProcessor>>,
+    ) -> String {
+        self.generate(sampler.with_processors(processors), stop_condition)
+            .await
+    }
+
+    /// Generates text like [`Context::generate`], passing the logits of every decode
+    /// step to `observer` along with the step index, e.g. to plot the entropy of a
+    /// generation.
+    ///
+    /// The logits are the temperature 1.0 log-probabilities (the raw logits up to a
+    /// constant per step), indexed by token ID, with `f32::NEG_INFINITY` for tokens the
+    /// backend left out. The observer only inspects them: the token is sampled from the
+    /// same forward pass, so use [`Context::generate_with_processors`] to change it.
+    /// Tokens forced with [`Context::force_prefix`] are not decoded and not observed.
+    ///
+    /// Exposing the full logits is expensive: every step asks the backend for the
+    /// probabilities of the whole vocabulary instead of its top 32, which costs a
+    /// vocabulary-sized transfer and an extra `f32` copy per step (about 600 KB for a
+    /// 150k-token vocabulary), on top of one read of the vocabulary when the call starts.
+    /// The backend caps distributions at its `max_dist_size` (64 by default), so it must
+    /// be raised to the vocabulary size for the logits to actually cover every token.
+    pub async fn generate_observed<S, F>(
+        &mut self,
+        sampler: Sampler,
+        stop_condition: S,
+        observer: F,
+    ) -> String
+    where
+        S: StopCondition,
+        F: FnMut(usize, &[f32]),
+    {
+        let (vocab_ids, _) = self.tokenizer.get_vocabs();
+        let observed = Observed {
+            inner: stop_condition,
+            observer: RefCell::new(observer),
+            top_k: vocab_ids.len() as u32,
+            vocab_size: vocab_ids.iter().max().map_or(0, |&id| id as usize + 1),
+            step: Cell::new(0),
+        };
+        self.generate(sampler, observed).await
+    }
+
+    /// Answers the reply generated last with `feedback` as a new user turn and generates
+    /// a revision, e.g. for a critique-then-revise loop.
+    ///
+    /// The previous reply already is the assistant turn of the context and its KV cache
+    /// is kept, so only the feedback (and the reply's last token, which was sampled but
+    /// not yet forwarded) is prefilled. If that generation did not end on one of the
+    /// model's stop sequences, e.g. because it hit a length limit, the first of them is
+    /// filled to close the assistant turn before the feedback.
+    pub async fn continue_with_feedback<S: StopCondition>(
+        &mut self,
+        feedback: &str,
+        sampler: Sampler,
+        stop_condition: S,
+    ) -> String {
+        self.close_open_turn();
+        self.fill_user(feedback);
+        self.generate(sampler, stop_condition).await
+    }
+
+    /// Generates like [`Context::generate`], but pauses as soon as the decoded output
+    /// contains one of `tool_markers`, e.g. the tag a model closes a tool call with.
+    ///
+    /// On a [`GenerationStep::ToolCall`], run the tool and hand its output to
+    /// [`Context::resume_with_tool_result`], then call this again to let the model
+    /// continue with the result at hand.
+    pub async fn generate_with_tools<S: StopCondition>(
+        &mut self,
+        sampler: Sampler,
+        stop_condition: S,
+        tool_markers: &[&str],
+    ) -> GenerationStep {
+        let markers = stop_condition::contains_any(self.tokenizer.clone(), tool_markers);
+        let text = self.generate(sampler, (&markers).or(stop_condition)).await;
+        match markers.matched_index() {
+            Some(i) => GenerationStep::ToolCall {
+                marker: tool_markers[i].to_string(),
+                text,
+            },
+            None => GenerationStep::Done(text),
+        }
+    }
+
+    /// Fills `result` as the tool turn answering the call [`Context::generate_with_tools`]
+    /// paused at, followed by the generation prompt, so the next generation continues
+    /// the assistant's reply.
+    ///
+    /// The assistant turn holding the call is closed with the model's first stop
+    /// sequence first, as in [`Context::continue_with_feedback`].
+    pub fn resume_with_tool_result(&mut self, result: &str) {
+        self.close_open_turn();
+        self.formatter.tool(result);
+        self.flush_chat_messages2(true);
+    }
+
+    /// Fills the first of the model's stop sequences if the last generation did not end
+    /// on one of them, e.g. because it hit a length limit, closing its assistant turn.
+    fn close_open_turn(&mut self) {
+        let eos_tokens = self.model.eos_tokens();
+        let turn_open = match &self.finish_reason {
+            None => false,
+            Some(FinishReason::Stop(Some(matched))) => !eos_tokens.contains(matched),
+            Some(_) => true,
+        };
+        if turn_open && let Some(eos) = eos_tokens.into_iter().find(|eos| !eos.is_empty()) {
+            self.fill_tokens(eos);
+        }
+    }
+
+    /// Fills a user message and generates the reply, the common single-turn case.
+    ///
+    /// Shorthand for `fill_user(user)` followed by `generate(sampler, stop_condition)`;
+    /// multi-turn conversations can keep calling those separately.
+    pub async fn prompt<S: StopCondition>(
+        &mut self,
+        user: &str,
+        sampler: Sampler,
+        stop_condition: S,
+    ) -> String {
+        self.fill_user(user);
+        self.generate(sampler, stop_condition).await
+    }
+
+    /// Generates text like [`Context::generate`], adjusting the sampler for this call only.
+    ///
+    /// Fields left unset in `overrides` inherit from `sampler`, which is borrowed and
+    /// left untouched, so one sampler can be reused across calls that vary e.g. the
+    /// temperature per request.
+    pub async fn generate_with_overrides<S: StopCondition>(
+        &mut self,
+        sampler: &Sampler,
+        stop_condition: S,
+        overrides: SamplerOverrides,
+    ) -> String {
+        self.generate_decoded(
+            sampler,
+            stop_condition,
+            overrides,
+            &DecodeOptions::default(),
+        )
+        .await
+    }
+
+    /// Generates text like [`Context::generate`], decoding the generated tokens into the
+    /// returned text according to `options`.
+    ///
+    /// [`Context::generate`] uses [`DecodeOptions::default`], which leaves special
+    /// tokens (e.g. the end-of-turn marker that stopped generation) out of the text;
+    /// pass `skip_special: false` to keep them. The context's token IDs always include
+    /// them, so they can still be stored alongside the text.
+    pub async fn generate_with_decode_options<S: StopCondition>(
+        &mut self,
+        sampler: Sampler,
+        stop_condition: S,
+        options: DecodeOptions,
+    ) -> String {
+        self.generate_decoded(
+            &sampler,
+            stop_condition,
+            SamplerOverrides::default(),
+            &options,
+        )
+        .await
+    }
+
+    async fn generate_decoded<S: StopCondition>(
+        &mut self,
+        sampler: &Sampler,
+        stop_condition: S,
+        overrides: SamplerOverrides,
+        options: &DecodeOptions,
+    ) -> String {
+        let sampler = OverriddenSampler::new(sampler, &overrides);
+        let generated_token_ids = self
+            .generate_tokens(&sampler, &stop_condition, &Interrupts::default(), |_| {})
+            .await
+            .expect("Forward pass produced no output");
+        self.tokenizer
+            .detokenize_with(&generated_token_ids, options)
+    }
+
+    /// Generates text like [`Context::generate`], also returning the wall time of every
+    /// decode step, e.g. to see how the per-token cost grows with the KV cache.
+    ///
+    /// The returned vector has one entry per generated token. Each entry is the time
+    /// since the previous token (or since the call started) was produced. Tokens forced
+    /// with [`Context::force_prefix`] are not decoded and take next to no time.
+    pub async fn generate_with_token_times<S: StopCondition>(
+        &mut self,
+        sampler: Sampler,
+        stop_condition: S,
+    ) -> (String, Vec<Duration>) {
+        let sampler = OverriddenSampler::new(&sampler, &SamplerOverrides::default());
+        let mut token_times = Vec::new();
+        let mut last = Instant::now();
+        let generated_token_ids = self
+            .generate_tokens(
+                &sampler,
+                &stop_condition,
+                &Interrupts::default(),
+                |token_ids| {
+                    let now = Instant::now();
+                    let new_tokens = token_ids.len() - token_times.len();
+                    token_times.extend(std::iter::repeat_n(Duration::ZERO, new_tokens - 1));
+                    token_times.push(now - last);
+                    last = now;
+                },
+            )
+            .await
+            .expect("Forward pass produced no output");
+        (self.tokenizer.detokenize(&generated_token_ids), token_times)
+    }
+
+    /// Generates text like [`Context::generate`], leaving the stop sequence that ended
+    /// generation out of the returned text.
+    ///
+    /// Unlike [`Context::trim_trailing_stop`], this keeps the stop tokens in the context
+    /// when [`Context::keep_stop_tokens`] is set and only trims the returned text; without
+    /// it, this is the same as [`Context::generate`]. The matched sequence is available
+    /// through [`Context::finish_reason`].
+    pub async fn generate_trimmed<S: StopCondition>(
+        &mut self,
+        sampler: Sampler,
+        stop_condition: S,
+    ) -> String {
+        let sampler = OverriddenSampler::new(&sampler, &SamplerOverrides::default());
+        let mut generated_token_ids = self
+            .generate_tokens(&sampler, &stop_condition, &Interrupts::default(), |_| {})
+            .await
+            .expect("Forward pass produced no output");
+        if self.keep_stop_tokens
+            && let Some(FinishReason::Stop(Some(sequence))) = &self.finish_reason
+        {
+            generated_token_ids.truncate(generated_token_ids.len() - sequence.len());
+        }
+        self.tokenizer.detokenize(&generated_token_ids)
+    }
+
+    /// Generates text like [`Context::generate`], stopping before the next step once
+    /// `cancel` is triggered.
+    ///
+    /// A cancelled call returns the text generated so far and sets
+    /// [`Context::finish_reason`] to [`FinishReason::Cancelled`]. Every generation also
+    /// stops on the instance-wide [`shutdown_token`](crate::shutdown_token).
+    pub async fn generate_cancellable<S: StopCondition>(
+        &mut self,
+        sampler: Sampler,
+        stop_condition: S,
+        cancel: &CancelToken,
+    ) -> String {
+        let sampler = OverriddenSampler::new(&sampler, &SamplerOverrides::default());
+        let generated_token_ids = self
+            .generate_tokens(
+                &sampler,
+                &stop_condition,
+                &Interrupts {
+                    cancel: Some(cancel),
+                    ..Default::default()
+                },
+                |_| {},
+            )
+            .await
+            .expect("Forward pass produced no output");
+        self.tokenizer.detokenize(&generated_token_ids)
+    }
+
+    /// Generates text like [`Context::generate`], drawing every generated token from the
+    /// pool [`run_budget`](crate::run_budget) created for `run_id`.
+    ///
+    /// Each decode step takes one token from the pool before it runs, so all agents of
+    /// the run together generate at most the pool's size. Once it is empty, the call
+    /// returns the text generated so far and sets [`Context::finish_reason`] to
+    /// [`FinishReason::BudgetExhausted`]; without a pool for `run_id`, it ends that way
+    /// right away. Tokens forced with [`Context::force_prefix`] are not decoded and
+    /// take nothing from the pool.
+    pub async fn generate_within_budget<S: StopCondition>(
+        &mut self,
+        sampler: Sampler,
+        stop_condition: S,
+        run_id: &str,
+    ) -> String {
+        let sampler = OverriddenSampler::new(&sampler, &SamplerOverrides::default());
+        let interrupts = Interrupts {
+            budget_key: Some(crate::run_budget_key(run_id)),
+            ..Default::default()
+        };
+        let generated_token_ids = self
+            .generate_tokens(&sampler, &stop_condition, &interrupts, |_| {})
+            .await
+            .expect("Forward pass produced no output");
+        self.tokenizer.detokenize(&generated_token_ids)
+    }
+
+    /// Generates text until one of the model's EOS sequences or until `timeout` has
+    /// elapsed, returning the text produced so far and why generation ended.
+    ///
+    /// The deadline is checked before each step, so a forward pass in flight when it
+    /// passes still completes and the call may overrun `timeout` by one step. Ending
+    /// on the deadline leaves the context as consistent as any other finish: every
+    /// committed token is in the KV cache, so [`Context::state`] and the pages can be
+    /// exported as usual, while the last sampled token stays pending.
+    pub async fn generate_timeout(
+        &mut self,
+        sampler: Sampler,
+        timeout: Duration,
+    ) -> (String, FinishReason) {
+        let sampler = OverriddenSampler::new(&sampler, &SamplerOverrides::default());
+        let stop_condition = self.model.default_stop_condition();
+        let interrupts = Interrupts {
+            deadline: Some(Instant::now() + timeout),
+            ..Default::default()
+        };
+        let generated_token_ids = self
+            .generate_tokens(&sampler, &stop_condition, &interrupts, |_| {})
+            .await
+            .expect("Forward pass produced no output");
+        let finish_reason = self
+            .finish_reason
+            .clone()
+            .unwrap_or(FinishReason::Stop(None));
+        (
+            self.tokenizer.detokenize(&generated_token_ids),
+            finish_reason,
+        )
+    }
+
+    /// Generates text like [`Context::generate`], moving to `fallback` if the primary
+    /// model fails mid-generation.
+    ///
+    /// A failure is recoverable when the backend answers a forward pass without any
+    /// output, which is how a backend that ran out of memory for the batch responds.
+    /// In that case `self` is replaced by a new context on `fallback`, filled with the
+    /// same token ids (prompt plus the tokens generated so far), and generation
+    /// continues there; the tokens generated on the primary model still count towards
+    /// the stop condition and are part of the returned text. Any other failure, such as
+    /// the instance being terminated by the OOM killer, is not recoverable from inside
+    /// the inferlet.
+    ///
+    /// Because token ids are reused as-is, `fallback` must share the primary model's
+    /// tokenizer (e.g. a smaller model of the same family).
+    pub async fn generate_with_fallback<S: StopCondition>(
+        &mut self,
+        fallback: &Model,
+        sampler: Sampler,
+        stop_condition: S,
+    ) -> String {
+        let sampler = OverriddenSampler::new(&sampler, &SamplerOverrides::default());
+        let generated_token_ids = match self
+            .generate_tokens(&sampler, &stop_condition, &Interrupts::default(), |_| {})
+            .await
+        {
+            Ok(generated_token_ids) => generated_token_ids,
+            Err(partial_token_ids) => {
+                let history = [&self.token_ids[..], &self.token_ids_pending[..]].concat();
+
+                let mut rebuilt = Context::new(fallback);
+                rebuilt.formatter = self.formatter.clone();
+                rebuilt.system_range = self.system_range.clone();
+                rebuilt.keep_stop_tokens = self.keep_stop_tokens;
+                rebuilt.fill_tokens(history);
+                rebuilt.token_ids_forced = partial_token_ids;
+                *self = rebuilt;
+
+                self.generate_tokens(&sampler, &stop_condition, &Interrupts::default(), |_| {})
+                    .await
+                    .expect("Forward pass on the fallback model produced no output")
+            }
+        };
+        self.tokenizer.detokenize(&generated_token_ids)
+    }
+
+    /// Drives the autoregressive loop shared by the `generate*` methods.
+    ///
+    /// `on_token` is invoked with the full generated sequence every time it grows,
+    /// including once up front for any tokens forced via [`Context::force_prefix`].
+    /// If a forward pass produces no output, the tokens generated up to that point are
+    /// returned as the error.
+    async fn generate_tokens<S, F>(
+        &mut self,
+        sampler: &OverriddenSampler<'_>,
+        stop_condition: &S,
+        interrupts: &Interrupts<'_>,
+        mut on_token: F,
+    ) -> Result<Vec<u32>, Vec<u32>>
+    where
+        S: StopCondition,
+        F: FnMut(&[u32]),
+    {
+        self.last_output = None;
+        sampler.start(&[&self.token_ids[..], &self.token_ids_pending[..]].concat());
+        // Tokens forced via `force_prefix` count as already generated.
+        let mut generated_token_ids = mem::take(&mut self.token_ids_forced);
+        if !generated_token_ids.is_empty() {
+            on_token(&generated_token_ids);
+            if stop_condition.check(&generated_token_ids) {
+                self.finish_on_stop(stop_condition, &mut generated_token_ids);
+                self.record_output(&generated_token_ids);
+                return Ok(generated_token_ids);
+            }
+        }
+
+        let mut placeholder = self
+            .model
+            .is_dry_run()
+            .then(|| self.dry_run_placeholder().into_iter());
+
+        // The autoregressive generation loop
+        let mut steps = 0;
+        loop {
+            // Stop before the forward pass would overflow the context window.
+            if self.remaining_context() < 1 {
+                self.finish_reason = Some(FinishReason::ContextFull);
+                break;
+            }
+            if self.remaining_quota() == Some(0) {
+                self.finish_reason = Some(FinishReason::QuotaExceeded);
+                break;
+            }
+            if !self.kv_pages_within_limit() {
+                self.finish_reason = Some(FinishReason::OutOfMemory);
+                break;
+            }
+            if crate::shutdown_token().is_cancelled()
+                || interrupts.cancel.is_some_and(CancelToken::is_cancelled)
+            {
+                self.finish_reason = Some(FinishReason::Cancelled);
+                break;
+            }
+            if interrupts
+                .deadline
+                .is_some_and(|deadline| Instant::now() >= deadline)
+            {
+                self.finish_reason = Some(FinishReason::Timeout);
+                break;
+            }
+            if let Some(key) = &interrupts.budget_key
+                && crate::store_take(key, 1) == 0
+            {
+                self.finish_reason = Some(FinishReason::BudgetExhausted);
+                break;
+            }
+
+            // start time
+            //let start_time = Instant::now();
+            let (next_token_id, dist) = match &mut placeholder {
+                Some(placeholder) => {
+                    let Some(token_id) = placeholder.next() else {
+                        self.finish_reason = Some(FinishReason::Stop(None));
+                        break;
+                    };
+                    (token_id, None)
+                }
+                None => {
+                    let Some(step) = self
+                        .decode_step_observed(
+                            sampler,
+                            stop_condition.needs_distribution(),
+                            stop_condition.distribution_top_k(),
+                        )
+                        .await
+                    else {
+                        return Err(generated_token_ids);
+                    };
+                    step
+                }
+            };
+            if let Some(dist) = &dist {
+                stop_condition.observe(dist);
+            }
 
-        let p = self.queue.create_forward_pass();
+            self.fill_token(next_token_id);
 
-        if let Some(adapter_ptr) = self.adapter_ptr {
-            p.set_adapter(adapter_ptr);
+            generated_token_ids.push(next_token_id);
+            on_token(&generated_token_ids);
 
-            if let Some(adapter_random_seed) = self.adapter_random_seed {
-                p.set_adapter_seed(adapter_random_seed);
+            if stop_condition.check(&generated_token_ids) {
+                self.finish_on_stop(stop_condition, &mut generated_token_ids);
+                break;
             }
+
+            yield_after_step(&mut steps, self.yield_interval).await;
         }
 
-        p.input_tokens(&pending_token_ids, &position_ids);
-        p.kv_cache(&self.kv_pages, self.kv_page_last_len);
-        p.attention_mask(&mask);
+        self.record_output(&generated_token_ids);
+        Ok(generated_token_ids)
+    }
 
-        let output_idx = pending_token_ids.len() as u32 - 1;
-        p.output_distributions(&[output_idx], 1.0, None);
+    /// Remembers `generated_token_ids`, which end the token history, for
+    /// [`Context::save_output`].
+    fn record_output(&mut self, generated_token_ids: &[u32]) {
+        let total = self.token_ids.len() + self.token_ids_pending.len();
+        self.last_output = Some((
+            total - generated_token_ids.len(),
+            generated_token_ids.to_vec(),
+        ));
+    }
 
-        let res = p.execute().await;
+    /// Records that `stop_condition` ended the generation and, unless
+    /// [`Context::keep_stop_tokens`] is set, trims the matched stop sequence off the
+    /// context and `generated_token_ids`.
+    fn finish_on_stop<S: StopCondition>(
+        &mut self,
+        stop_condition: &S,
+        generated_token_ids: &mut Vec<u32>,
+    ) {
+        self.finish_reason = Some(FinishReason::Stop(
+            stop_condition
+                .matched_sequence(generated_token_ids)
+                .map(<[u32]>::to_vec),
+        ));
+        if !self.keep_stop_tokens {
+            let trimmed = self.trim_trailing_stop();
+            generated_token_ids.truncate(generated_token_ids.len() - trimmed);
+        }
+    }
 
-        let dist = res.distributions.unwrap().into_iter().next().unwrap();
+    /// Returns the tokens a [`Model::dry_run`] model generates in place of a reply.
+    fn dry_run_placeholder(&self) -> Vec<u32> {
+        let hash = fnv1a(self.token_ids.iter().chain(&self.token_ids_pending));
+        self.tokenizer.tokenize(&format!("[dry run {:016x}]", hash))
+    }
 
-        self.token_ids.extend(pending_token_ids);
-        self.position_ids.extend(position_ids);
+    /// Generates text like [`Context::generate`] while broadcasting progress to `topic`.
+    ///
+    /// Every time the decoded output grows, the newly decoded text is published as a
+    /// JSON message `{"node_id": ..., "chunk": ..., "done": false}`, so a monitor
+    /// subscribed to several agents' topics can attribute each chunk. Chunks are
+    /// decoded with a [`StreamDecoder`], so they only end on complete characters;
+    /// text still held back when generation stops is published as a last chunk. A
+    /// final message with an empty `chunk` and `"done": true` marks the end of the
+    /// generation.
+    pub async fn generate_publishing<S: StopCondition>(
+        &mut self,
+        sampler: Sampler,
+        stop_condition: S,
+        topic: &str,
+        node_id: &str,
+    ) -> String {
+        let sampler = OverriddenSampler::new(&sampler, &SamplerOverrides::default());
+        let mut decoder = StreamDecoder::new(self.tokenizer.clone());
+        let mut decoded_len = 0;
+        let publish = |chunk: &str| {
+            let message = serde_json::json!({
+                "node_id": node_id,
+                "chunk": chunk,
+                "done": false,
+            });
+            crate::broadcast(topic, &message.to_string());
+        };
+
+        let generated_token_ids = self
+            .generate_tokens(
+                &sampler,
+                &stop_condition,
+                &Interrupts::default(),
+                |token_ids| {
+                    if let Some(chunk) = decoder.push(&token_ids[decoded_len..]) {
+                        publish(&chunk);
+                    }
+                    decoded_len = token_ids.len();
+                },
+            )
+            .await
+            .expect("Forward pass produced no output");
 
-        dist
+        let rest = decoder.finish();
+        if !rest.is_empty() {
+            publish(&rest);
+        }
+        let message = serde_json::json!({
+            "node_id": node_id,
+            "chunk": "",
+            "done": true,
+        });
+        crate::broadcast(topic, &message.to_string());
+
+        self.tokenizer.detokenize(&generated_token_ids)
     }
 
-    /// Generates text autoregressively until a stop condition is met.
+    /// Generates a reply like [`Context::generate`], yielding it one sentence at a time.
     ///
-    /// This function drives the text generation loop. In each iteration, it calls
-    /// `decode_step()` to get a probability distribution, uses the provided `sampler`
-    /// to choose the next token, and adds it to the context. The loop continues
-    /// until the `stop_condition` signals that generation should end.
-    ///
-    /// # Arguments
+    /// Tokens are accumulated until the decoded text holds a complete sentence (see the
+    /// punctuation rules below), which is then yielded with its trailing closing quotes
+    /// or brackets; whitespace between sentences starts the next item, so the items
+    /// concatenate to the full reply. Whatever follows the last sentence is yielded when
+    /// generation ends. [`Context::finish_reason`] is set once the stream is exhausted.
+    /// As with [`Context::generate`], a matched stop sequence is trimmed off the context
+    /// and the last item unless [`Context::keep_stop_tokens`] is set.
     ///
-    /// * `sampler`: A mutable reference to a struct implementing the `Sampler` trait,
-    ///   which will be used to sample a token from the model's output distribution at each step.
-    /// * `stop_condition`: A mutable reference to a struct implementing the `StopCondition`
-    ///   trait, which determines when to halt the generation process.
+    /// `。！？` end a sentence immediately, whereas `.!?…` need following whitespace, and
+    /// a period after a single letter or a common abbreviation such as "Dr" or "e.g" is
+    /// not treated as a sentence end.
+    pub fn generate_sentences<S: StopCondition>(
+        &mut self,
+        sampler: Sampler,
+        stop_condition: S,
+    ) -> impl Stream<Item = String> {
+        self.generate_chunked(sampler, stop_condition, None)
+    }
+
+    /// Generates a reply like [`Context::generate_sentences`], but also ends a chunk at
+    /// commas, semicolons and colons (see [`TTS_CLAUSE_BOUNDARIES`]), so a downstream
+    /// text-to-speech agent receives short pieces it can read aloud with natural pauses
+    /// while the rest is still being generated.
     ///
-    /// # Returns
+    /// ASCII clause punctuation only ends a chunk once whitespace follows, so "1,000"
+    /// or "3:45" stay whole, while full-width punctuation such as `，` ends one right
+    /// away. Use [`Context::generate_for_tts_with_boundaries`] for another set.
     ///
-    /// A `Result` containing the generated `String` upon successful completion.
-    pub async fn generate<S: StopCondition>(
+    /// [`TTS_CLAUSE_BOUNDARIES`]: crate::stream::TTS_CLAUSE_BOUNDARIES
+    pub fn generate_for_tts<S: StopCondition>(
         &mut self,
         sampler: Sampler,
         stop_condition: S,
-    ) -> String {
-        let mut generated_token_ids = Vec::new();
-
-        // The autoregressive generation loop
-        loop {
-            // start time
-            //let start_time = Instant::now();
-            let next_token_id = self.decode_step(&sampler).await;
-
-            self.fill_token(next_token_id);
+    ) -> impl Stream<Item = String> {
+        self.generate_for_tts_with_boundaries(
+            sampler,
+            stop_condition,
+            TTS_CLAUSE_BOUNDARIES.to_vec(),
+        )
+    }
 
-            generated_token_ids.push(next_token_id);
+    /// Generates a reply like [`Context::generate_for_tts`], ending chunks at sentence
+    /// ends and at the punctuation in `boundaries` instead of the default set.
+    pub fn generate_for_tts_with_boundaries<S: StopCondition>(
+        &mut self,
+        sampler: Sampler,
+        stop_condition: S,
+        boundaries: Vec<char>,
+    ) -> impl Stream<Item = String> {
+        self.generate_chunked(sampler, stop_condition, Some(boundaries))
+    }
 
-            if stop_condition.check(&generated_token_ids) {
-                break;
+    /// Streams the reply in sentences, or in clauses ending at `clause_boundaries`.
+    fn generate_chunked<S: StopCondition>(
+        &mut self,
+        sampler: Sampler,
+        stop_condition: S,
+        clause_boundaries: Option<Vec<char>>,
+    ) -> impl Stream<Item = String> {
+        sampler.start(&[&self.token_ids[..], &self.token_ids_pending[..]].concat());
+        let mut sentences = SentenceStream {
+            decoder: StreamDecoder::new(self.tokenizer.clone()),
+            // Tokens forced via `force_prefix` count as already generated.
+            generated_token_ids: mem::take(&mut self.token_ids_forced),
+            placeholder: self
+                .model
+                .is_dry_run()
+                .then(|| self.dry_run_placeholder().into_iter()),
+            pending_text: String::new(),
+            yielded_len: 0,
+            clause_boundaries,
+            steps: 0,
+            done: false,
+            ctx: self,
+            sampler,
+            stop_condition,
+        };
+        if !sentences.generated_token_ids.is_empty() {
+            if let Some(chunk) = sentences.decoder.push(&sentences.generated_token_ids) {
+                sentences.pending_text.push_str(&chunk);
             }
+            sentences.check_stop();
         }
 
-        self.tokenizer.detokenize(&generated_token_ids)
+        stream::unfold(sentences, |mut sentences| async move {
+            let sentence = sentences.next_sentence().await?;
+            Some((sentence, sentences))
+        })
+    }
+
+    /// Generates a reply like [`Context::generate`], borrowing the sampler and stop
+    /// condition so that several contexts can share them.
+    ///
+    /// Fails instead of panicking if a forward pass produces no output.
+    pub(crate) async fn generate_shared<S: StopCondition>(
+        &mut self,
+        sampler: &Sampler,
+        stop_condition: &S,
+    ) -> Result<String, Error> {
+        let sampler = OverriddenSampler::new(sampler, &SamplerOverrides::default());
+        let generated_token_ids = self
+            .generate_tokens(&sampler, stop_condition, &Interrupts::default(), |_| {})
+            .await
+            .map_err(|_| Error::Backend("Forward pass produced no output".to_string()))?;
+        Ok(self.tokenizer.detokenize(&generated_token_ids))
+    }
+
+    /// Generates at most `max_new_tokens` tokens, stopping early at any of the
+    /// model's EOS sequences.
+    ///
+    /// This is a shorthand for
+    /// `generate(sampler, max_len(max_new_tokens).or(model.default_stop_condition()))`
+    /// for callers that only need to vary the token budget per call.
+    pub async fn generate_n(&mut self, sampler: Sampler, max_new_tokens: usize) -> String {
+        let stop_condition =
+            stop_condition::max_len(max_new_tokens).or(self.model.default_stop_condition());
+        self.generate(sampler, stop_condition).await
+    }
+
+    /// Generates at most `max_new_tokens` tokens, stopping early at any of the model's
+    /// EOS sequences or at any of `extra_stops`.
+    ///
+    /// This is a shorthand for
+    /// `generate(sampler, model.stop_condition_with(extra_stops).or(max_len(max_new_tokens)))`.
+    /// Each stop sequence is matched as a whole, so a multi-token delimiter only stops
+    /// generation once all of its tokens were produced.
+    pub async fn generate_until(
+        &mut self,
+        sampler: Sampler,
+        extra_stops: &[&[u32]],
+        max_new_tokens: usize,
+    ) -> String {
+        let stop_condition = self
+            .model
+            .stop_condition_with(extra_stops)
+            .or(stop_condition::max_len(max_new_tokens));
+        self.generate(sampler, stop_condition).await
+    }
+
+    /// Generates at most `max_new_tokens` tokens (stopping early at the model's EOS
+    /// sequences) and deserializes the output as JSON into `T`.
+    ///
+    /// Models sometimes emit almost-valid JSON, so if the output does not parse as is,
+    /// these repairs are applied before parsing again:
+    ///
+    /// - Text before the first `{` or `[` (e.g. a Markdown code fence) is dropped, as is
+    ///   text after the bracket that closes it.
+    /// - Commas before a closing bracket (trailing commas) are removed.
+    /// - A closing bracket of the wrong kind is replaced by the one that matches.
+    /// - If the output was cut off, an unterminated string is closed, a dangling comma
+    ///   is removed, a key left without a value gets `null`, and the brackets that are
+    ///   still open are closed.
+    ///
+    /// Fails with [`Error::ParseInput`], carrying the raw output, if even the repaired
+    /// text does not deserialize into `T`.
+    pub async fn generate_json<T: DeserializeOwned>(
+        &mut self,
+        sampler: Sampler,
+        max_new_tokens: usize,
+    ) -> Result<T, Error> {
+        let text = self.generate_n(sampler, max_new_tokens).await;
+        if let Ok(value) = serde_json::from_str(text.trim()) {
+            return Ok(value);
+        }
+        serde_json::from_str(&repair_json(&text)).map_err(|e| {
+            Error::ParseInput(format!(
+                "Generated text is not valid JSON ({}): {}",
+                e, text
+            ))
+        })
     }
 
     /// Generates text using beam search decoding until a stop condition is met.
@@ -758,7 +2959,7 @@ impl Context {
                 // Expand this beam with the top candidates.
                 for i in 0..beam_size.min(next_dist.ids.len()) {
                     let mut next_beam = beam.fork();
-                    // We assume the distribution is sorted by probability in descending order.
+                    // Distributions are sorted by descending probability, with ties broken by token ID.
                     next_beam.fill_token(next_dist.ids[i]);
 
                     let mut next_generated = generated.clone();
@@ -817,6 +3018,7 @@ impl Context {
     ) -> String {
         // Synchronize the drafter with the main model's history before starting.
         drafter.update(&self.token_ids);
+        sampler.start(&[&self.token_ids[..], &self.token_ids_pending[..]].concat());
 
         let mut all_generated_tokens = Vec::new();
 
@@ -857,6 +3059,7 @@ impl Context {
             //println!("verification batch positions: {:?}", &batch_positions);
 
             // Allocate resources and expand the KV cache to accommodate the entire batch.
+            self.unshare_last_kv_page().await;
             self.grow_kv_pages(batch_tokens.len());
 
             let out_range = token_ids_pending.len() - 1..batch_tokens.len();
@@ -950,3 +3153,582 @@ impl Context {
         self.tokenizer.detokenize(&all_generated_tokens)
     }
 }
+
+/// Applies the repairs described in [`Context::generate_json`] to `text`.
+fn repair_json(text: &str) -> String {
+    let start = text.find(['{', '[']).unwrap_or(0);
+    let mut out = String::with_capacity(text.len() - start);
+    let mut closers = Vec::new();
+    let mut in_string = false;
+    let mut escaped = false;
+
+    for c in text[start..].chars() {
+        if in_string {
+            out.push(c);
+            if escaped {
+                escaped = false;
+            } else if c == '\\' {
+                escaped = true;
+            } else if c == '"' {
+                in_string = false;
+            }
+            continue;
+        }
+        match c {
+            '"' => {
+                in_string = true;
+                out.push(c);
+            }
+            '{' => {
+                closers.push('}');
+                out.push(c);
+            }
+            '[' => {
+                closers.push(']');
+                out.push(c);
+            }
+            '}' | ']' => {
+                let Some(closer) = closers.pop() else {
+                    break;
+                };
+                strip_trailing_comma(&mut out);
+                out.push(closer);
+                if closers.is_empty() {
+                    break;
+                }
+            }
+            _ => out.push(c),
+        }
+    }
+
+    if in_string {
+        if escaped {
+            out.pop();
+        }
+        out.push('"');
+    }
+    if !closers.is_empty() {
+        strip_trailing_comma(&mut out);
+        if out.trim_end().ends_with(':') {
+            out.push_str("null");
+        }
+    }
+    while let Some(closer) = closers.pop() {
+        out.push(closer);
+    }
+    out
+}
+
+/// Removes a comma (and the whitespace after it) from the end of `json`.
+fn strip_trailing_comma(json: &mut String) {
+    let end = json.trim_end().len();
+    if json[..end].ends_with(',') {
+        json.truncate(end - 1);
+    }
+}
+
+/// Returns the part of `text`, the whole output of a [`SentenceStream`], that follows
+/// the `yielded` bytes already handed out, or nothing if those cover all of it.
+fn unyielded_text(text: &str, yielded: usize) -> &str {
+    let start = (yielded..text.len())
+        .find(|&i| text.is_char_boundary(i))
+        .unwrap_or(text.len());
+    &text[start..]
+}
+
+/// Counts a finished decode step and yields to the executor every `interval` steps, as
+/// configured with [`Context::set_yield_interval`].
+async fn yield_after_step(steps: &mut usize, interval: usize) {
+    *steps += 1;
+    if interval > 0 && steps.is_multiple_of(interval) {
+        YieldNow::default().await;
+    }
+}
+
+/// A future that is pending once, waking its task right away, so the executor runs the
+/// other ready tasks before the awaiting one continues.
+#[derive(Default)]
+struct YieldNow {
+    yielded: bool,
+}
+
+impl Future for YieldNow {
+    type Output = ();
+
+    fn poll(mut self: Pin<&mut Self>, cx: &mut task::Context<'_>) -> Poll<()> {
+        if self.yielded {
+            return Poll::Ready(());
+        }
+        self.yielded = true;
+        cx.waker().wake_by_ref();
+        Poll::Pending
+    }
+}
+
+/// Splits the committed `token_ids` for [`Context::recompute_from`]: the state the
+/// context rolls back to, and the tokens it then forwards again in a single pass.
+fn recompute_plan(
+    token_ids: &[u32],
+    token_index: usize,
+    page_size: usize,
+) -> (ContextState, &[u32]) {
+    (
+        ContextState::for_tokens(token_index, page_size),
+        &token_ids[token_index..],
+    )
+}
+
+/// Encodes the committed and pending tokens of a context for [`Context::save`].
+fn encode_saved_tokens(committed: &[u32], pending: &[u32]) -> Result<String, Error> {
+    Ok(serde_json::to_string(&[committed, pending].concat())?)
+}
+
+/// Decodes the token history [`Context::save`] stored, which [`Context::load`] fills.
+fn decode_saved_tokens(json: &str) -> Result<Vec<u32>, Error> {
+    Ok(serde_json::from_str(json)?)
+}
+
+/// Returns the text [`Context::fill_healed`] tokenizes in place of the last token's
+/// text `boundary` and `text`, or `None` if the boundary cannot be healed.
+fn healed_text(boundary: Option<&str>, text: &str) -> Option<String> {
+    boundary
+        .filter(|boundary| !boundary.contains(char::REPLACEMENT_CHARACTER))
+        .map(|boundary| format!("{}{}", boundary, text))
+}
+
+/// Returns how many of `token_count` committed tokens, stored in pages of `page_size`
+/// tokens, lie past the first `page` pages.
+fn tokens_after_page(token_count: usize, page: usize, page_size: usize) -> usize {
+    token_count.saturating_sub(page * page_size)
+}
+
+/// Returns how many KV pages a forward pass has to allocate to hold `needed` pages when
+/// `held` pages are already at hand, plus one if it first copies a shared last page.
+fn pages_to_allocate(needed: usize, held: usize, copy_last: bool) -> usize {
+    needed.saturating_sub(held) + usize::from(copy_last)
+}
+
+/// Returns the offsets of the `1 - weight` fraction of `count` tokens that
+/// [`Context::attend_to`] masks, spread evenly over them.
+#[cfg(feature = "experimental")]
+fn weight_masked_offsets(count: usize, weight: f32) -> Vec<usize> {
+    let masked = ((1.0 - weight) * count as f32).round() as usize;
+    (0..count)
+        .filter(|&i| (i + 1) * masked / count > i * masked / count)
+        .collect()
+}
+
+/// Whether the last of `pages`, which holds `last_len` of `page_size` tokens, is
+/// partially filled and `is_shared` with another context, so it has to be copied before
+/// a forward pass writes the next tokens into it.
+fn last_page_needs_copy<P>(
+    pages: &[P],
+    last_len: usize,
+    page_size: usize,
+    is_shared: impl Fn(&P) -> bool,
+) -> bool {
+    last_len != page_size && pages.last().is_some_and(is_shared)
+}
+
+/// Returns the log-probability of each of `token_ids` under the distribution at its
+/// position, falling back to the smallest reported probability for a token outside of
+/// the candidates.
+fn token_log_probs(token_ids: &[u32], dists: &[Distribution]) -> Vec<f32> {
+    token_ids
+        .iter()
+        .zip(dists)
+        .map(|(token_id, dist)| {
+            let prob = dist
+                .ids
+                .iter()
+                .position(|id| id == token_id)
+                .map(|i| dist.probs[i])
+                .or_else(|| dist.probs.iter().copied().reduce(f32::min))
+                .unwrap_or(0.0);
+            prob.max(f32::MIN_POSITIVE).ln()
+        })
+        .collect()
+}
+
+/// Hashes token IDs with 64-bit FNV-1a, which, unlike the standard library's hashers,
+/// gives the same value across runs and builds.
+fn fnv1a<'a>(token_ids: impl Iterator<Item = &'a u32>) -> u64 {
+    fnv1a_bytes(token_ids.flat_map(|id| id.to_le_bytes()))
+}
+
+/// Hashes bytes with 64-bit FNV-1a.
+pub(crate) fn fnv1a_bytes(bytes: impl Iterator<Item = u8>) -> u64 {
+    bytes.fold(0xcbf2_9ce4_8422_2325_u64, |hash, byte| {
+        (hash ^ byte as u64).wrapping_mul(0x0000_0100_0000_01b3)
+    })
+}
+
+/// The editable token history returned by [`Context::token_history_mut`].
+///
+/// It dereferences to the committed and pending tokens, in order. The edits are
+/// applied to the context when the guard is dropped.
+pub struct TokenHistoryMut<'a> {
+    ctx: &'a mut Context,
+    tokens: Vec<u32>,
+}
+
+impl Deref for TokenHistoryMut<'_> {
+    type Target = Vec<u32>;
+
+    fn deref(&self) -> &Vec<u32> {
+        &self.tokens
+    }
+}
+
+impl DerefMut for TokenHistoryMut<'_> {
+    fn deref_mut(&mut self) -> &mut Vec<u32> {
+        &mut self.tokens
+    }
+}
+
+impl Drop for TokenHistoryMut<'_> {
+    fn drop(&mut self) {
+        self.ctx.apply_token_history(mem::take(&mut self.tokens));
+    }
+}
+
+/// The state behind [`Context::generate_sentences`] and [`Context::generate_for_tts`].
+struct SentenceStream<'a, S> {
+    ctx: &'a mut Context,
+    sampler: Sampler,
+    stop_condition: S,
+    decoder: StreamDecoder,
+    generated_token_ids: Vec<u32>,
+    placeholder: Option<std::vec::IntoIter<u32>>,
+    /// Decoded text that has not been yielded yet.
+    pending_text: String,
+    /// Length in bytes of the text yielded so far.
+    yielded_len: usize,
+    /// Punctuation that ends a chunk besides sentence ends, if chunks are clauses.
+    clause_boundaries: Option<Vec<char>>,
+    /// Decode steps run so far, counted for [`Context::set_yield_interval`].
+    steps: usize,
+    done: bool,
+}
+
+impl<S: StopCondition> SentenceStream<'_, S> {
+    async fn next_sentence(&mut self) -> Option<String> {
+        loop {
+            let end = match &self.clause_boundaries {
+                Some(boundaries) => clause_end(&self.pending_text, boundaries),
+                None => sentence_end(&self.pending_text),
+            };
+            if let Some(end) = end {
+                self.yielded_len += end;
+                return Some(self.pending_text.drain(..end).collect());
+            }
+            if self.done {
+                return (!self.pending_text.is_empty()).then(|| mem::take(&mut self.pending_text));
+            }
+            self.step().await;
+        }
+    }
+
+    /// Generates one token, ending the stream if generation is over.
+    async fn step(&mut self) {
+        if self.ctx.remaining_context() < 1 {
+            return self.finish(Some(FinishReason::ContextFull));
+        }
+        if self.ctx.remaining_quota() == Some(0) {
+            return self.finish(Some(FinishReason::QuotaExceeded));
+        }
+        if !self.ctx.kv_pages_within_limit() {
+            return self.finish(Some(FinishReason::OutOfMemory));
+        }
+        if crate::shutdown_token().is_cancelled() {
+            return self.finish(Some(FinishReason::Cancelled));
+        }
+
+        let next_token_id = match &mut self.placeholder {
+            Some(placeholder) => match placeholder.next() {
+                Some(token_id) => token_id,
+                None => return self.finish(Some(FinishReason::Stop(None))),
+            },
+            None => {
+                let sampler = OverriddenSampler::new(&self.sampler, &SamplerOverrides::default());
+                let step = self
+                    .ctx
+                    .decode_step_observed(
+                        &sampler,
+                        self.stop_condition.needs_distribution(),
+                        self.stop_condition.distribution_top_k(),
+                    )
+                    .await;
+                let Some((token_id, dist)) = step else {
+                    return self.finish(None);
+                };
+                if let Some(dist) = &dist {
+                    self.stop_condition.observe(dist);
+                }
+                token_id
+            }
+        };
+
+        self.ctx.fill_token(next_token_id);
+        self.generated_token_ids.push(next_token_id);
+        if let Some(chunk) = self.decoder.push(&[next_token_id]) {
+            self.pending_text.push_str(&chunk);
+        }
+        self.check_stop();
+        if !self.done {
+            yield_after_step(&mut self.steps, self.ctx.yield_interval).await;
+        }
+    }
+
+    fn check_stop(&mut self) {
+        if !self.stop_condition.check(&self.generated_token_ids) {
+            return;
+        }
+        let generated = self.generated_token_ids.len();
+        self.ctx
+            .finish_on_stop(&self.stop_condition, &mut self.generated_token_ids);
+        if self.generated_token_ids.len() == generated {
+            return self.finish(None);
+        }
+
+        // The decoder already saw the stop sequence, so decode the trimmed output again
+        // and only keep what has not been yielded.
+        let text = self.ctx.tokenizer.detokenize(&self.generated_token_ids);
+        self.pending_text = unyielded_text(&text, self.yielded_len).to_string();
+        self.done = true;
+    }
+
+    fn finish(&mut self, finish_reason: Option<FinishReason>) {
+        if finish_reason.is_some() {
+            self.ctx.finish_reason = finish_reason;
+        }
+        self.pending_text.push_str(&self.decoder.finish());
+        self.done = true;
+    }
+}
+
+/// The stop condition behind [`Context::generate_observed`], which requests the full
+/// distribution of every step and hands it to the observer as dense logits.
+struct Observed<S, F> {
+    inner: S,
+    observer: RefCell<F>,
+    top_k: u32,
+    vocab_size: usize,
+    step: Cell<usize>,
+}
+
+impl<S, F> StopCondition for Observed<S, F>
+where
+    S: StopCondition,
+    F: FnMut(usize, &[f32]),
+{
+    fn check(&self, token_ids: &[u32]) -> bool {
+        self.inner.check(token_ids)
+    }
+
+    fn needs_distribution(&self) -> bool {
+        true
+    }
+
+    fn observe(&self, dist: &Distribution) {
+        if self.inner.needs_distribution() {
+            self.inner.observe(dist);
+        }
+
+        let mut logits = vec![f32::NEG_INFINITY; self.vocab_size];
+        for (&id, &prob) in dist.ids.iter().zip(&dist.probs) {
+            if let Some(logit) = logits.get_mut(id as usize) {
+                *logit = prob.ln();
+            }
+        }
+        let step = self.step.get();
+        (self.observer.borrow_mut())(step, &logits);
+        self.step.set(step + 1);
+    }
+
+    fn distribution_top_k(&self) -> Option<u32> {
+        Some(self.top_k)
+    }
+
+    fn matched_sequence(&self, token_ids: &[u32]) -> Option<&[u32]> {
+        self.inner.matched_sequence(token_ids)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn state_for_tokens_at_page_boundaries() {
+        let state = |token_count| {
+            let s = ContextState::for_tokens(token_count, 16);
+            (s.page_count, s.last_len)
+        };
+        assert_eq!(state(0), (0, 0));
+        assert_eq!(state(1), (1, 1));
+        assert_eq!(state(16), (1, 16));
+        assert_eq!(state(17), (2, 1));
+        assert_eq!(state(32), (2, 16));
+    }
+
+    #[test]
+    fn saved_tokens_round_trip() {
+        let json = encode_saved_tokens(&[1, 2, 3], &[4]).unwrap();
+        // Pending tokens are saved after the committed ones; `load` prefills all but
+        // the last again.
+        assert_eq!(decode_saved_tokens(&json).unwrap(), vec![1, 2, 3, 4]);
+        assert!(
+            decode_saved_tokens(&encode_saved_tokens(&[], &[]).unwrap())
+                .unwrap()
+                .is_empty()
+        );
+        assert!(matches!(
+            decode_saved_tokens("not a context"),
+            Err(Error::ParseInput(_))
+        ));
+    }
+
+    #[test]
+    fn healing_joins_a_split_word() {
+        // A prompt ending in "hel" followed by "lo" is tokenized as "hello" again.
+        assert_eq!(healed_text(Some("hel"), "lo").as_deref(), Some("hello"));
+        assert_eq!(healed_text(Some(" the"), "re").as_deref(), Some(" there"));
+        // Half of a multi-byte character cannot be rebuilt from its text.
+        assert_eq!(healed_text(Some("\u{FFFD}"), "lo"), None);
+        assert_eq!(healed_text(None, "lo"), None);
+    }
+
+    #[test]
+    fn tokens_after_a_page_range_are_counted() {
+        // 40 tokens in pages of 16: the third page holds the last 8.
+        assert_eq!(tokens_after_page(40, 3, 16), 0);
+        assert_eq!(tokens_after_page(40, 2, 16), 8);
+        assert_eq!(tokens_after_page(40, 1, 16), 24);
+        assert_eq!(tokens_after_page(0, 0, 16), 0);
+    }
+
+    #[test]
+    fn pages_to_allocate_counts_copy_of_shared_last_page() {
+        assert_eq!(pages_to_allocate(4, 4, false), 0);
+        assert_eq!(pages_to_allocate(4, 4, true), 1);
+        assert_eq!(pages_to_allocate(5, 3, true), 3);
+        assert_eq!(pages_to_allocate(2, 6, false), 0);
+    }
+
+    #[test]
+    fn sentence_stream_ends_without_the_stop_text() {
+        // "Hi. Bye<|end|>" had "Hi." yielded before the stop sequence matched.
+        assert_eq!(unyielded_text("Hi. Bye", 3), " Bye");
+        // The stop text was already partially yielded, so nothing is left.
+        assert_eq!(unyielded_text("Hi.", 5), "");
+        // An offset inside a character skips to the next one.
+        assert_eq!(unyielded_text("é!", 1), "!");
+    }
+
+    #[test]
+    fn concurrent_generations_interleave_at_yields() {
+        // Runs two joined loops of four steps each and records the order they ran in.
+        let run = |interval| {
+            let order = RefCell::new(Vec::new());
+            let generation = |name| {
+                let order = &order;
+                async move {
+                    let mut steps = 0;
+                    for _ in 0..4 {
+                        order.borrow_mut().push(name);
+                        yield_after_step(&mut steps, interval).await;
+                    }
+                }
+            };
+            futures::executor::block_on(futures::future::join(generation('a'), generation('b')));
+            order.into_inner().into_iter().collect::<String>()
+        };
+        assert_eq!(run(1), "abababab");
+        assert_eq!(run(2), "aabbaabb");
+        assert_eq!(run(0), "aaaabbbb");
+    }
+
+    #[test]
+    fn recompute_from_the_midpoint_forwards_only_the_tail() {
+        let token_ids: Vec<u32> = (0..40).collect();
+        let (full_state, full) = recompute_plan(&token_ids, 0, 16);
+        let (kept_state, tail) = recompute_plan(&token_ids, 20, 16);
+
+        // Both rebuild the same committed tokens, the midpoint on top of the kept pages.
+        assert_eq!(full_state, ContextState::for_tokens(0, 16));
+        assert_eq!(full, &token_ids[..]);
+        assert_eq!(kept_state, ContextState::for_tokens(20, 16));
+        assert_eq!([&token_ids[..20], tail].concat(), token_ids);
+        // The single forward pass covers half the tokens.
+        assert_eq!(tail.len(), 20);
+        assert!(tail.len() < full.len());
+    }
+
+    #[test]
+    fn reusing_the_whole_kv_cache_plans_no_prefill_pass() {
+        let token_ids: Vec<u32> = (0..20).collect();
+        let (kept, recomputed) = recompute_plan(&token_ids, 20, 16);
+        // Nothing is forwarded before decoding, so the next decode step is the only pass.
+        assert!(recomputed.is_empty());
+        assert_eq!(kept, ContextState::for_tokens(20, 16));
+    }
+
+    #[test]
+    fn copy_of_a_shared_last_page_is_reported_as_an_allocation() {
+        let allocated = Rc::new(Cell::new(0));
+        let counter = allocated.clone();
+        let hook = Some(PageAllocHook(Rc::new(move |n, _| {
+            counter.set(counter.get() + n)
+        })));
+
+        // A fork holding 3 pages whose partial last one is shared grows to 5 pages:
+        // `unshare_last_kv_page` reports the copy, then the growth reports the rest.
+        let pages = [Rc::new(()), Rc::new(()), Rc::new(())];
+        let _parent = pages.clone();
+        let is_shared = |p: &Rc<()>| Rc::strong_count(p) > 1;
+        let copy_last = last_page_needs_copy(&pages, 5, 16, is_shared);
+        if copy_last {
+            report_page_alloc(&hook, 1, 3);
+        }
+        report_page_alloc(&hook, 2, 5);
+
+        assert_eq!(allocated.get(), pages_to_allocate(5, 3, copy_last));
+        assert_eq!(allocated.get(), 3);
+    }
+
+    #[test]
+    fn fork_copies_only_a_shared_partial_last_page() {
+        // Page handles shared by cloning, like the `KvPage`s of a forked context.
+        let is_shared = |p: &Rc<()>| Rc::strong_count(p) > 1;
+        let parent = vec![Rc::new(()), Rc::new(())];
+
+        // Before the fork, the context writes into its own partial last page.
+        assert!(!last_page_needs_copy(&parent, 5, 16, is_shared));
+        assert_eq!(pages_to_allocate(2, 2, false), 0);
+
+        let fork = parent.clone();
+        // A partial last page now also belongs to the parent and must be copied first,
+        // which takes one page more than growing the cache.
+        assert!(last_page_needs_copy(&fork, 5, 16, is_shared));
+        assert!(last_page_needs_copy(&parent, 5, 16, is_shared));
+        assert_eq!(pages_to_allocate(3, 2, true), 2);
+        // A full last page is never written again, so the fork appends a new one.
+        assert!(!last_page_needs_copy(&fork, 16, 16, is_shared));
+        assert!(!last_page_needs_copy::<Rc<()>>(&[], 0, 16, is_shared));
+
+        // Once the parent is gone, the fork owns its pages again.
+        drop(parent);
+        assert!(!last_page_needs_copy(&fork, 5, 16, is_shared));
+    }
+
+    #[cfg(feature = "experimental")]
+    #[test]
+    fn attention_weight_masks_a_matching_fraction() {
+        // A weight of 0 masks every appended token, the same as not attending to them.
+        assert_eq!(weight_masked_offsets(8, 0.0), (0..8).collect::<Vec<_>>());
+        assert!(weight_masked_offsets(8, 1.0).is_empty());
+        assert_eq!(weight_masked_offsets(8, 0.5), vec![1, 3, 5, 7]);
+        assert_eq!(weight_masked_offsets(8, 0.75), vec![3, 7]);
+    }
+}
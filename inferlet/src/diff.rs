@@ -0,0 +1,223 @@
+/// One edit that turns the first token sequence given to [`diff_generations`] into the
+/// second.
+///
+/// `a_index` and `b_index` locate the edit in both sequences: for a deletion or
+/// substitution `a[a_index]` is the affected token, for an insertion the token is put
+/// before `a[a_index]`. Likewise `b[b_index]` is the inserted or substituted token,
+/// and a deleted token would have been before `b[b_index]`.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum DiffOp {
+    /// `token` appears in `b` but not in `a`.
+    Insert {
+        a_index: usize,
+        b_index: usize,
+        token: u32,
+    },
+    /// `token` appears in `a` but not in `b`.
+    Delete {
+        a_index: usize,
+        b_index: usize,
+        token: u32,
+    },
+    /// `a` has `from` where `b` has `to`.
+    Substitute {
+        a_index: usize,
+        b_index: usize,
+        from: u32,
+        to: u32,
+    },
+}
+
+impl DiffOp {
+    /// Returns the position of the edit in the first sequence.
+    pub fn a_index(&self) -> usize {
+        match *self {
+            DiffOp::Insert { a_index, .. }
+            | DiffOp::Delete { a_index, .. }
+            | DiffOp::Substitute { a_index, .. } => a_index,
+        }
+    }
+
+    /// Returns the position of the edit in the second sequence.
+    pub fn b_index(&self) -> usize {
+        match *self {
+            DiffOp::Insert { b_index, .. }
+            | DiffOp::Delete { b_index, .. }
+            | DiffOp::Substitute { b_index, .. } => b_index,
+        }
+    }
+}
+
+/// Returns the fewest token insertions, deletions and substitutions that turn `a` into
+/// `b`, in order of position, e.g. to see where two samplers made the same prompt
+/// diverge.
+///
+/// Tokens the sequences share are not reported, so equal sequences yield no edits and
+/// the first edit marks where they diverge. Among equally short edit scripts,
+/// substitutions are preferred over a deletion plus an insertion. The common prefix
+/// and suffix are skipped up front; the rest takes time and memory proportional to the
+/// product of the remaining lengths.
+pub fn diff_generations(a: &[u32], b: &[u32]) -> Vec<DiffOp> {
+    let prefix = a.iter().zip(b).take_while(|(x, y)| x == y).count();
+    let suffix = a[prefix..]
+        .iter()
+        .rev()
+        .zip(b[prefix..].iter().rev())
+        .take_while(|(x, y)| x == y)
+        .count();
+    let a_mid = &a[prefix..a.len() - suffix];
+    let b_mid = &b[prefix..b.len() - suffix];
+
+    // distances[i][j] is the edit distance between a_mid[i..] and b_mid[j..].
+    let (n, m) = (a_mid.len(), b_mid.len());
+    let mut distances = vec![vec![0usize; m + 1]; n + 1];
+    for i in (0..=n).rev() {
+        for j in (0..=m).rev() {
+            distances[i][j] = if i == n {
+                m - j
+            } else if j == m {
+                n - i
+            } else if a_mid[i] == b_mid[j] {
+                distances[i + 1][j + 1]
+            } else {
+                1 + distances[i + 1][j + 1]
+                    .min(distances[i + 1][j])
+                    .min(distances[i][j + 1])
+            };
+        }
+    }
+
+    let mut ops = Vec::with_capacity(distances[0][0]);
+    let (mut i, mut j) = (0, 0);
+    while i < n || j < m {
+        let (a_index, b_index) = (prefix + i, prefix + j);
+        if i < n && j < m && a_mid[i] == b_mid[j] {
+            i += 1;
+            j += 1;
+        } else if i < n && j < m && distances[i][j] == 1 + distances[i + 1][j + 1] {
+            ops.push(DiffOp::Substitute {
+                a_index,
+                b_index,
+                from: a_mid[i],
+                to: b_mid[j],
+            });
+            i += 1;
+            j += 1;
+        } else if i < n && distances[i][j] == 1 + distances[i + 1][j] {
+            ops.push(DiffOp::Delete {
+                a_index,
+                b_index,
+                token: a_mid[i],
+            });
+            i += 1;
+        } else {
+            ops.push(DiffOp::Insert {
+                a_index,
+                b_index,
+                token: b_mid[j],
+            });
+            j += 1;
+        }
+    }
+    ops
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn equal_sequences_have_no_edits() {
+        assert_eq!(diff_generations(&[1, 2, 3], &[1, 2, 3]), vec![]);
+    }
+
+    #[test]
+    fn empty_inputs() {
+        assert_eq!(diff_generations(&[], &[]), vec![]);
+        assert_eq!(
+            diff_generations(&[], &[7, 8]),
+            vec![
+                DiffOp::Insert {
+                    a_index: 0,
+                    b_index: 0,
+                    token: 7
+                },
+                DiffOp::Insert {
+                    a_index: 0,
+                    b_index: 1,
+                    token: 8
+                },
+            ]
+        );
+        assert_eq!(
+            diff_generations(&[7], &[]),
+            vec![DiffOp::Delete {
+                a_index: 0,
+                b_index: 0,
+                token: 7
+            }]
+        );
+    }
+
+    #[test]
+    fn pure_insertion() {
+        assert_eq!(
+            diff_generations(&[1, 2, 4], &[1, 2, 3, 4]),
+            vec![DiffOp::Insert {
+                a_index: 2,
+                b_index: 2,
+                token: 3
+            }]
+        );
+    }
+
+    #[test]
+    fn pure_deletion() {
+        assert_eq!(
+            diff_generations(&[1, 2, 3, 4], &[1, 3, 4]),
+            vec![DiffOp::Delete {
+                a_index: 1,
+                b_index: 1,
+                token: 2
+            }]
+        );
+    }
+
+    #[test]
+    fn substitution_is_preferred_over_delete_and_insert() {
+        assert_eq!(
+            diff_generations(&[1, 2, 3], &[1, 9, 3]),
+            vec![DiffOp::Substitute {
+                a_index: 1,
+                b_index: 1,
+                from: 2,
+                to: 9
+            }]
+        );
+    }
+
+    #[test]
+    fn common_prefix_and_suffix_are_skipped() {
+        let a = [5, 5, 5, 1, 6, 6];
+        let b = [5, 5, 5, 2, 3, 6, 6];
+        let ops = diff_generations(&a, &b);
+        assert_eq!(
+            ops,
+            vec![
+                DiffOp::Substitute {
+                    a_index: 3,
+                    b_index: 3,
+                    from: 1,
+                    to: 2
+                },
+                DiffOp::Insert {
+                    a_index: 4,
+                    b_index: 4,
+                    token: 3
+                },
+            ]
+        );
+        assert_eq!(ops[0].a_index(), 3);
+        assert_eq!(ops[1].b_index(), 4);
+    }
+}
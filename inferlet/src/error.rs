@@ -0,0 +1,70 @@
+use std::fmt;
+
+/// Errors returned by the fallible APIs of this crate.
+///
+/// It is exported as [`InferletError`](crate::InferletError), next to the
+/// [`anyhow::Error`](crate::Error) the crate root re-exports. It implements
+/// `std::error::Error`, so `?` converts it into the latter inside functions returning
+/// [`crate::Result`],
+/// while callers that need to can match on the cause, e.g. start fresh on
+/// [`Error::StoreMissing`] instead of failing.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Error {
+    /// Input or stored data, e.g. JSON metadata or a pub/sub message, could not be parsed.
+    ParseInput(String),
+    /// Nothing is stored under the given key.
+    StoreMissing(String),
+    /// Nothing is exported under the given KV page export name.
+    KvImportFailed(String),
+    /// Exporting resources failed, e.g. because the name is already taken.
+    ExportFailed(String),
+    /// The backend produced no output or did not become available.
+    Backend(String),
+    /// The context window cannot hold the request.
+    ContextFull,
+    /// An argument was rejected, e.g. an out-of-bounds page range.
+    InvalidArgument(String),
+    /// Allocating `requested` KV pages would exceed the limit set with
+    /// [`set_memory_limit`](crate::set_memory_limit), which leaves `available`.
+    OutOfMemory { requested: usize, available: usize },
+    /// Holding the context's tokens takes `pages` KV pages, more than the `max_pages`
+    /// its [`Quota`](crate::Quota) allows.
+    QuotaExceeded { pages: usize, max_pages: usize },
+}
+
+impl fmt::Display for Error {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Error::ParseInput(message) => write!(f, "Failed to parse input: {}", message),
+            Error::StoreMissing(key) => write!(f, "Nothing is stored under '{}'", key),
+            Error::KvImportFailed(name) => {
+                write!(f, "No KV pages are exported under '{}'", name)
+            }
+            Error::ExportFailed(message) => write!(f, "Export failed: {}", message),
+            Error::Backend(message) => write!(f, "Backend error: {}", message),
+            Error::ContextFull => write!(f, "The context window is full"),
+            Error::InvalidArgument(message) => write!(f, "{}", message),
+            Error::OutOfMemory {
+                requested,
+                available,
+            } => write!(
+                f,
+                "Cannot allocate {} KV pages: the memory limit leaves {}",
+                requested, available
+            ),
+            Error::QuotaExceeded { pages, max_pages } => write!(
+                f,
+                "KV page quota exceeded: the context needs {} pages but its quota allows {}",
+                pages, max_pages
+            ),
+        }
+    }
+}
+
+impl std::error::Error for Error {}
+
+impl From<serde_json::Error> for Error {
+    fn from(e: serde_json::Error) -> Self {
+        Error::ParseInput(e.to_string())
+    }
+}
@@ -1,12 +1,25 @@
 use crate::api;
 use crate::brle::Brle;
-use crate::{Queue, Resource};
+use crate::error::Error;
+use crate::{Queue, Resource, Result};
+pub use api::ExportMode;
+pub use api::forward::LogitPrecision;
+use std::cell::{Cell, RefCell};
+use std::collections::HashMap;
 use std::rc::Rc;
 use wstd::io::AsyncPollable;
 
 #[derive(Debug, Clone)]
 pub struct ForwardPass {
     pub(crate) inner: Rc<api::forward::ForwardPass>,
+    forward_pass_count: Rc<Cell<usize>>,
+    position_offset: u32,
+    max_position: u32,
+    kv_page_size: u32,
+    /// The number of tokens passed to `input_tokens` and `input_embed_ptrs` so far.
+    num_input_tokens: Rc<Cell<usize>>,
+    /// The number of tokens the KV cache set with `kv_cache` holds, if set.
+    num_kv_tokens: Rc<Cell<Option<usize>>>,
 }
 
 #[derive(Debug, Clone)]
@@ -24,12 +37,110 @@ pub struct Distribution {
     pub probs: Vec<f32>,
 }
 
+impl Distribution {
+    /// Reorders the entries by descending probability.
+    ///
+    /// Entries with equal probability are ordered by ascending token ID, so the
+    /// resulting order does not depend on how the backend enumerated the candidates.
+    pub fn sort(&mut self) {
+        let mut entries: Vec<(u32, f32)> = self
+            .ids
+            .iter()
+            .copied()
+            .zip(self.probs.iter().copied())
+            .collect();
+        entries.sort_by(|a, b| b.1.total_cmp(&a.1).then(a.0.cmp(&b.0)));
+        (self.ids, self.probs) = entries.into_iter().unzip();
+    }
+
+    /// Returns the most probable token ID, preferring the lowest ID among ties.
+    ///
+    /// Returns `None` if the distribution is empty.
+    pub fn argmax(&self) -> Option<u32> {
+        argmax(&self.ids, &self.probs)
+    }
+}
+
+/// Returns the ID of `ids` with the highest of `probs`, preferring the lowest ID among
+/// ties, or `None` if `ids` is empty.
+pub(crate) fn argmax(ids: &[u32], probs: &[f32]) -> Option<u32> {
+    ids.iter()
+        .zip(probs)
+        .max_by(|a, b| a.1.total_cmp(b.1).then(b.0.cmp(a.0)))
+        .map(|(&id, _)| id)
+}
+
+/// Counts the KV pages this instance allocated against the limit set with
+/// [`crate::set_memory_limit`].
+///
+/// Only pages the instance allocated itself count; pages imported from an export are
+/// owned by the exporter and neither add to nor, when dropped, subtract from the count.
+#[derive(Debug, Default)]
+struct KvPageLedger {
+    in_use: usize,
+    limit: Option<usize>,
+}
+
+impl KvPageLedger {
+    /// Returns how many more pages the limit allows, or `None` if no limit is set.
+    fn budget(&self) -> Option<usize> {
+        self.limit.map(|limit| limit.saturating_sub(self.in_use))
+    }
+
+    /// Allocates `count` pages with `allocate` and counts them, failing with
+    /// [`Error::OutOfMemory`] without calling `allocate` if that would exceed the limit.
+    fn allocate(
+        &mut self,
+        count: usize,
+        allocate: impl FnOnce(usize) -> Vec<u32>,
+    ) -> Result<Vec<u32>, Error> {
+        if let Some(available) = self.budget()
+            && count > available
+        {
+            return Err(Error::OutOfMemory {
+                requested: count,
+                available,
+            });
+        }
+        let ptrs = allocate(count);
+        self.in_use += ptrs.len();
+        Ok(ptrs)
+    }
+
+    /// Records that `count` pages allocated by this instance were freed.
+    fn free(&mut self, count: usize) {
+        self.in_use = self.in_use.saturating_sub(count);
+    }
+}
+
+thread_local! {
+    static KV_PAGES: RefCell<KvPageLedger> = RefCell::new(KvPageLedger::default());
+}
+
+/// Returns how many KV pages this instance allocated and has not deallocated yet.
+pub(crate) fn kv_pages_in_use() -> usize {
+    KV_PAGES.with_borrow(|ledger| ledger.in_use)
+}
+
+pub(crate) fn set_kv_page_limit(limit: Option<usize>) {
+    KV_PAGES.with_borrow_mut(|ledger| ledger.limit = limit);
+}
+
+/// Returns how many more KV pages the limit allows this instance to allocate, or `None`
+/// if no limit is set.
+pub(crate) fn kv_page_budget() -> Option<usize> {
+    KV_PAGES.with_borrow(KvPageLedger::budget)
+}
+
 // "Smart" kv page
 #[derive(Debug, Clone)]
 pub struct KvPage {
     queue: Queue,
     rc: Rc<()>,
     ptr: u32,
+    /// Whether this instance allocated the page, as opposed to importing it, and so
+    /// counts it against its memory limit.
+    owned: bool,
 }
 
 impl KvPage {
@@ -38,18 +149,102 @@ impl KvPage {
             queue: queue.clone(),
             rc: Rc::new(()),
             ptr,
+            owned: false,
         }
     }
 
+    /// Wraps a page this instance allocated, which counts against its memory limit
+    /// until the last handle is dropped.
+    fn new_owned(queue: &Queue, ptr: u32) -> Self {
+        let mut page = KvPage::new(queue, ptr);
+        page.owned = true;
+        page
+    }
+
     pub fn ptr(&self) -> u32 {
         self.ptr
     }
+
+    /// Whether another `KvPage` handle, e.g. of a forked context, refers to this page.
+    pub(crate) fn is_shared(&self) -> bool {
+        Rc::strong_count(&self.rc) > 1
+    }
+
+    /// Allocates a new page on `queue` and copies this page's contents into it.
+    ///
+    /// Clones of a `KvPage` share the same physical page, so a forward pass through one
+    /// clone overwrites what the others see. The copy is a separate physical page: later
+    /// writes to either page leave the other untouched, which makes it a cheap way to
+    /// branch a context from a partially filled page.
+    ///
+    /// The copy runs on the backend and costs one page of device memory plus reading and
+    /// writing the page once on every layer. Recomputing the page instead costs the same
+    /// memory but a forward pass over all of its tokens, which is far more expensive in
+    /// compute for anything but the smallest pages. Before copying, this waits until the
+    /// forward passes already submitted on the page's own queue have been dispatched, so
+    /// the copy reflects them.
+    pub async fn deep_copy(&self, queue: &Queue) -> KvPage {
+        self.queue.synchronize().await;
+        copy_to_new_pages(
+            &[self.ptr],
+            |count| queue.new_kv_pages(count),
+            KvPage::ptr,
+            |src, dst| api::forward::copy_kv_pages(&queue.inner, src, dst),
+        )
+        .pop()
+        .expect("Failed to allocate a KV page")
+    }
+}
+
+/// Checks that the backend can export the transformer `layers` of a KV page, see
+/// [`Forward::export_kv_pages_layers`]; an empty list stands for every layer.
+fn check_exported_layers(layers: &[usize]) -> Result<(), Error> {
+    if !layers.is_empty() {
+        return Err(Error::InvalidArgument(format!(
+            "Per-layer KV export is not supported by the backend (requested layers {:?})",
+            layers
+        )));
+    }
+    Ok(())
+}
+
+/// Takes the page pointers staged under `name` by [`Queue::prefetch_kv_pages`] out of
+/// `prefetched`, or imports them with `import` if none are staged.
+fn take_or_import(
+    prefetched: &RefCell<HashMap<String, Vec<u32>>>,
+    name: &str,
+    import: impl FnOnce() -> Vec<u32>,
+) -> Vec<u32> {
+    let staged = prefetched.borrow_mut().remove(name);
+    staged.unwrap_or_else(import)
+}
+
+/// Allocates a page per pointer in `src` with `allocate` and copies the pages into them
+/// with `copy`, which like the backend's page copy takes the source and destination
+/// pointers.
+fn copy_to_new_pages<P>(
+    src: &[u32],
+    allocate: impl FnOnce(usize) -> Vec<P>,
+    ptr: impl Fn(&P) -> u32,
+    copy: impl FnOnce(&[u32], &[u32]),
+) -> Vec<P> {
+    let pages = allocate(src.len());
+    if !pages.is_empty() {
+        let dst = pages.iter().map(ptr).collect::<Vec<_>>();
+        copy(src, &dst);
+    }
+    pages
 }
 
 impl Drop for KvPage {
     fn drop(&mut self) {
         if Rc::strong_count(&self.rc) == 1 {
-            self.queue.deallocate_kv_page_ptr(self.ptr);
+            if self.owned {
+                self.queue.deallocate_kv_page_ptr(self.ptr);
+            } else {
+                self.queue
+                    .deallocate_resources(Resource::KvPage, &[self.ptr]);
+            }
         }
     }
 }
@@ -70,7 +265,57 @@ pub trait Forward {
     fn new_kv_pages(&self, count: usize) -> Vec<KvPage>;
 
     fn export_kv_pages(&self, ptrs: &[KvPage], name: &str);
+
+    /// Exports `kv_pages` under `name` with an explicit policy for a name that is
+    /// already taken, e.g. by an earlier attempt of a retried task.
+    ///
+    /// - `FailIfExists` leaves the existing export alone and returns an error.
+    /// - `Overwrite` releases the existing export and replaces it. Instances that
+    ///   imported the old pages keep them until they exit, but a reader that imports
+    ///   `name` concurrently may observe either version.
+    /// - `Dedup` keeps the existing export and succeeds; `kv_pages` stay owned by the
+    ///   caller.
+    fn export_kv_pages_with_mode(
+        &self,
+        kv_pages: &[KvPage],
+        name: &str,
+        mode: ExportMode,
+    ) -> Result<(), Error>;
+
+    /// Exports only the given transformer `layers` of `kv_pages` under `name`.
+    ///
+    /// An empty `layers` exports every layer, exactly like `export_kv_pages`.
+    ///
+    /// Partial exports are not available yet: the backend allocates each KV page with
+    /// all layers as a single unit and has no way to import a page whose missing layers
+    /// would have to be recomputed before use, so a non-empty `layers` is rejected with
+    /// an error and nothing is exported.
+    fn export_kv_pages_layers(
+        &self,
+        kv_pages: &[KvPage],
+        name: &str,
+        layers: &[usize],
+    ) -> Result<(), Error>;
+
+    /// Imports the KV pages exported under `name`; same as `import_kv_pages_shared`.
     fn import_kv_pages(&self, name: &str) -> Vec<KvPage>;
+
+    /// Imports the KV pages exported under `name` as aliases of the exported pages.
+    ///
+    /// Nothing is allocated or copied: every import of the same name, in this or any
+    /// other instance, refers to the same device memory. A forward pass that writes into
+    /// an aliased page, e.g. by filling the free slots of a partially filled last page,
+    /// therefore changes the export for everyone. Use it for prefixes that are only
+    /// read, such as the full pages of a KV chain.
+    fn import_kv_pages_shared(&self, name: &str) -> Vec<KvPage>;
+
+    /// Imports the KV pages exported under `name` as independent copies.
+    ///
+    /// Allocates a fresh page per exported page and copies the contents on the backend
+    /// (see [`KvPage::deep_copy`]), so the pages can be written without affecting the
+    /// export or other importers, at the cost of the extra device memory.
+    fn import_kv_pages_owned(&self, name: &str) -> Vec<KvPage>;
+
     fn allocate_kv_page_ptr(&self) -> u32;
     fn allocate_kv_page_ptrs(&self, count: usize) -> Vec<u32>;
     fn deallocate_kv_page_ptr(&self, ptr: u32);
@@ -79,8 +324,44 @@ pub trait Forward {
     fn import_kv_page_ptrs(&self, name: &str) -> Vec<u32>;
 
     fn get_all_exported_kv_pages(&self) -> Vec<(String, u32)>;
+
+    /// Frees the KV pages exported under `name`, the counterpart to `export_kv_pages`.
+    ///
+    /// Instances that already imported the pages keep them valid; the host returns them
+    /// to the pool only once the last such instance has exited.
     fn release_exported_kv_pages(&self, name: &str);
 
+    /// Whether KV pages are currently exported under `name`.
+    fn kv_pages_exist(&self, name: &str) -> bool;
+
+    /// Returns how many KV pages are exported under `name`, or `None` if there is no
+    /// such export. An export can legitimately hold zero pages.
+    fn kv_pages_count(&self, name: &str) -> Option<usize>;
+
+    /// Returns how many more KV pages can be allocated before the device runs out, e.g.
+    /// to check against [`Model::pages_for_tokens`](crate::Model::pages_for_tokens)
+    /// before starting another branch.
+    ///
+    /// This is a best-effort estimate: other queues and instances allocate and free
+    /// pages concurrently, so a later allocation may still fail. It also counts only
+    /// free pages, while the host can make room for an allocation that exceeds them by
+    /// terminating newer instances.
+    fn available_kv_pages(&self) -> usize;
+
+    /// Allocates `count` KV pages, failing with [`Error::OutOfMemory`] instead if that
+    /// would exceed the limit set with [`set_memory_limit`](crate::set_memory_limit).
+    ///
+    /// `new_kv_page` and `new_kv_pages` panic with that error instead.
+    fn try_new_kv_pages(&self, count: usize) -> Result<Vec<KvPage>, Error>;
+
+    /// Like `import_kv_pages`, but fails if nothing is exported under `name` instead of
+    /// returning an empty list that is indistinguishable from an empty export.
+    fn try_import_kv_pages(&self, name: &str) -> Result<Vec<KvPage>, Error>;
+
+    /// Frees every KV page export whose name starts with `prefix` (e.g. all `{run}_`
+    /// resources of a cancelled run) and returns how many exports were released.
+    fn release_kv_pages_prefix(&self, prefix: &str) -> usize;
+
     fn allocate_embed_ptr(&self) -> u32;
     fn allocate_embed_ptrs(&self, count: usize) -> Vec<u32>;
     fn deallocate_embed_ptr(&self, ptr: u32);
@@ -95,15 +376,12 @@ pub trait Forward {
 
 impl Forward for Queue {
     fn new_kv_page(&self) -> KvPage {
-        let ptr = self.allocate_kv_page_ptr();
-        KvPage::new(self, ptr)
+        self.new_kv_pages(1).pop().unwrap()
     }
 
     fn new_kv_pages(&self, count: usize) -> Vec<KvPage> {
-        self.allocate_kv_page_ptrs(count)
-            .into_iter()
-            .map(|ptr| KvPage::new(self, ptr))
-            .collect()
+        self.try_new_kv_pages(count)
+            .unwrap_or_else(|e| panic!("{}", e))
     }
 
     fn export_kv_pages(&self, kv_pages: &[KvPage], name: &str) {
@@ -111,28 +389,66 @@ impl Forward for Queue {
         self.export_resource(Resource::KvPage, &ptrs, name)
     }
 
+    fn export_kv_pages_with_mode(
+        &self,
+        kv_pages: &[KvPage],
+        name: &str,
+        mode: ExportMode,
+    ) -> Result<(), Error> {
+        let ptrs = kv_pages.iter().map(|kv| kv.ptr()).collect::<Vec<_>>();
+        self.export_resource_with_mode(Resource::KvPage, &ptrs, name, mode)
+    }
+
+    fn export_kv_pages_layers(
+        &self,
+        kv_pages: &[KvPage],
+        name: &str,
+        layers: &[usize],
+    ) -> Result<(), Error> {
+        check_exported_layers(layers)?;
+        self.export_kv_pages(kv_pages, name);
+        Ok(())
+    }
+
     fn import_kv_pages(&self, name: &str) -> Vec<KvPage> {
-        let ptrs = self.import_resource(Resource::KvPage, name);
+        self.import_kv_pages_shared(name)
+    }
+
+    fn import_kv_pages_shared(&self, name: &str) -> Vec<KvPage> {
+        let ptrs = take_or_import(&self.prefetched, name, || {
+            self.import_resource(Resource::KvPage, name)
+        });
         ptrs.into_iter().map(|ptr| KvPage::new(self, ptr)).collect()
     }
 
+    fn import_kv_pages_owned(&self, name: &str) -> Vec<KvPage> {
+        let shared = self.import_kv_pages_shared(name);
+        let src_ptrs: Vec<u32> = shared.iter().map(KvPage::ptr).collect();
+        copy_to_new_pages(
+            &src_ptrs,
+            |count| self.new_kv_pages(count),
+            KvPage::ptr,
+            |src, dst| api::forward::copy_kv_pages(&self.inner, src, dst),
+        )
+    }
+
     fn allocate_kv_page_ptr(&self) -> u32 {
-        self.allocate_resources(Resource::KvPage, 1)
-            .into_iter()
-            .next()
-            .unwrap()
+        self.allocate_kv_page_ptrs(1).into_iter().next().unwrap()
     }
 
     fn allocate_kv_page_ptrs(&self, count: usize) -> Vec<u32> {
-        self.allocate_resources(Resource::KvPage, count as u32)
+        let ptrs = self.allocate_resources(Resource::KvPage, count as u32);
+        KV_PAGES.with_borrow_mut(|ledger| ledger.in_use += ptrs.len());
+        ptrs
     }
 
     fn deallocate_kv_page_ptr(&self, ptr: u32) {
-        self.deallocate_resources(Resource::KvPage, &[ptr])
+        self.deallocate_kv_page_ptrs(&[ptr])
     }
 
     fn deallocate_kv_page_ptrs(&self, ptrs: &[u32]) {
-        self.deallocate_resources(Resource::KvPage, ptrs)
+        self.deallocate_resources(Resource::KvPage, ptrs);
+        KV_PAGES.with_borrow_mut(|ledger| ledger.free(ptrs.len()));
     }
 
     fn export_kv_page_ptrs(&self, ptrs: &[u32], name: &str) {
@@ -151,6 +467,53 @@ impl Forward for Queue {
         self.release_exported_resources(Resource::KvPage, name)
     }
 
+    fn kv_pages_exist(&self, name: &str) -> bool {
+        self.kv_pages_count(name).is_some()
+    }
+
+    fn kv_pages_count(&self, name: &str) -> Option<usize> {
+        self.get_all_exported_kv_pages()
+            .into_iter()
+            .find(|(exported, _)| exported == name)
+            .map(|(_, count)| count as usize)
+    }
+
+    fn available_kv_pages(&self) -> usize {
+        self.get_available_resources(Resource::KvPage)
+    }
+
+    fn try_new_kv_pages(&self, count: usize) -> Result<Vec<KvPage>, Error> {
+        let ptrs = KV_PAGES.with_borrow_mut(|ledger| {
+            ledger.allocate(count, |count| {
+                self.allocate_resources(Resource::KvPage, count as u32)
+            })
+        })?;
+        Ok(ptrs
+            .into_iter()
+            .map(|ptr| KvPage::new_owned(self, ptr))
+            .collect())
+    }
+
+    fn try_import_kv_pages(&self, name: &str) -> Result<Vec<KvPage>, Error> {
+        if !self.is_prefetched(name) && !self.kv_pages_exist(name) {
+            return Err(Error::KvImportFailed(name.to_string()));
+        }
+        Ok(self.import_kv_pages(name))
+    }
+
+    fn release_kv_pages_prefix(&self, prefix: &str) -> usize {
+        let names = self
+            .get_all_exported_kv_pages()
+            .into_iter()
+            .map(|(name, _)| name)
+            .filter(|name| name.starts_with(prefix))
+            .collect::<Vec<_>>();
+        for name in &names {
+            self.release_exported_kv_pages(name);
+        }
+        names.len()
+    }
+
     fn allocate_embed_ptr(&self) -> u32 {
         self.allocate_resources(Resource::Embed, 1)
             .into_iter()
@@ -189,12 +552,21 @@ impl Forward for Queue {
     fn create_forward_pass(&self) -> ForwardPass {
         ForwardPass {
             inner: Rc::new(api::forward::create_forward_pass(&self.inner)),
+            forward_pass_count: self.forward_pass_count.clone(),
+            position_offset: 0,
+            max_position: self.max_position,
+            kv_page_size: self.kv_page_size,
+            num_input_tokens: Rc::new(Cell::new(0)),
+            num_kv_tokens: Rc::new(Cell::new(None)),
         }
     }
 }
 
 impl ForwardPass {
     pub async fn execute(&self) -> ForwardPassResult {
+        self.forward_pass_count
+            .set(self.forward_pass_count.get() + 1);
+
         if let Some(future) = self.inner.execute() {
             let pollable = future.pollable();
             AsyncPollable::new(pollable).wait_for().await;
@@ -202,7 +574,9 @@ impl ForwardPass {
             let mut dists = Vec::new();
             if let Some(distributions) = future.get_distributions() {
                 for (ids, probs) in distributions {
-                    dists.push(Distribution { ids, probs });
+                    let mut dist = Distribution { ids, probs };
+                    dist.sort();
+                    dists.push(dist);
                 }
             }
             let distributions = if dists.is_empty() { None } else { Some(dists) };
@@ -219,12 +593,66 @@ impl ForwardPass {
         }
     }
 
+    /// Shifts the positions passed to `input_tokens` and `input_embed_ptrs` by `offset`.
+    ///
+    /// With RoPE, the position of a token is baked into its KV entries, so tokens
+    /// forwarded on top of KV pages computed elsewhere, e.g. a chain of imported
+    /// exports, must continue at the absolute position those pages end at. With an
+    /// offset, the caller can pass positions relative to the start of its own tokens
+    /// instead of tracking that base itself.
+    ///
+    /// Fails if `offset` is not below the model's maximum context length. Positions
+    /// that end up beyond it after shifting make the `input_*` calls panic.
+    pub fn with_position_offset(mut self, offset: u32) -> Result<Self, Error> {
+        if offset >= self.max_position {
+            return Err(Error::InvalidArgument(format!(
+                "Position offset {} exceeds the model's maximum position {}",
+                offset,
+                self.max_position - 1
+            )));
+        }
+        self.position_offset = offset;
+        Ok(self)
+    }
+
+    /// Applies the position offset to `positions`.
+    fn offset_positions(&self, positions: &[u32]) -> Vec<u32> {
+        positions
+            .iter()
+            .map(|&p| {
+                let position = p + self.position_offset;
+                assert!(
+                    position < self.max_position,
+                    "Position {} (offset by {}) exceeds the model's maximum position {}",
+                    position,
+                    self.position_offset,
+                    self.max_position - 1
+                );
+                position
+            })
+            .collect()
+    }
+
     pub fn input_embed_ptrs(&self, embed_ptrs: &[u32], positions: &[u32]) {
-        api::forward::input_embeddings(&self.inner, embed_ptrs, positions);
+        self.num_input_tokens
+            .set(self.num_input_tokens.get() + embed_ptrs.len());
+        if self.position_offset == 0 {
+            api::forward::input_embeddings(&self.inner, embed_ptrs, positions);
+        } else {
+            let positions = self.offset_positions(positions);
+            api::forward::input_embeddings(&self.inner, embed_ptrs, &positions);
+        }
     }
 
     pub fn input_tokens(&self, input_tokens: &[u32], positions: &[u32]) {
-        api::forward::input_tokens(&self.inner, input_tokens, positions);
+        self.num_input_tokens
+            .set(self.num_input_tokens.get() + input_tokens.len());
+        if self.position_offset == 0 {
+            api::forward::input_tokens(&self.inner, input_tokens, positions);
+        } else {
+            let positions = self.offset_positions(positions);
+            api::forward::input_tokens(&self.inner, input_tokens, &positions);
+        }
     }
 
     pub fn output_embed_ptrs(&self, embed_ptrs: &[u32], indices: &[u32]) {
@@ -261,16 +689,260 @@ impl ForwardPass {
         api::forward::output_tokens_top_k_top_p(&self.inner, indices, temperature, top_k, top_p);
     }
 
+    /// Sets the precision the backend computes the softmax and truncation of all token
+    /// and distribution outputs of this pass in.
+    pub fn set_logit_precision(&self, precision: LogitPrecision) {
+        api::forward::set_logit_precision(&self.inner, precision);
+    }
+
+    /// Restricts which tokens of the context the input tokens of this pass attend to.
+    ///
+    /// `mask` holds one flag per token the KV cache set with `kv_cache` holds,
+    /// including the slots of this pass's own input tokens, which come last: `true`
+    /// means attend, `false` means ignore, e.g. the padding of a shorter sequence in a
+    /// batch. The mask is applied on top of causal masking, and every input token
+    /// still attends to itself, so a padding token does not end up with an empty
+    /// attention window.
+    ///
+    /// Call it after `kv_cache` and the `input_*` calls. Fails if the KV cache is not
+    /// set, or if the length of `mask` differs from the number of tokens it holds.
+    pub fn attention_mask_bool(&self, mask: &[bool]) -> Result<(), Error> {
+        let Some(num_total_tokens) = self.num_kv_tokens.get() else {
+            return Err(Error::InvalidArgument(
+                "The KV cache must be set before the attention mask".to_string(),
+            ));
+        };
+        if mask.len() != num_total_tokens {
+            return Err(Error::InvalidArgument(format!(
+                "Attention mask of {} tokens does not match the {} tokens of the KV cache",
+                mask.len(),
+                num_total_tokens
+            )));
+        }
+        let num_input_tokens = self.num_input_tokens.get();
+        if num_input_tokens > num_total_tokens {
+            return Err(Error::InvalidArgument(format!(
+                "The KV cache of {} tokens cannot hold the {} input tokens",
+                num_total_tokens, num_input_tokens
+            )));
+        }
+
+        let offset = num_total_tokens - num_input_tokens;
+        let ignored: Vec<bool> = mask.iter().map(|&attend| !attend).collect();
+        let token_masks = (offset..num_total_tokens)
+            .map(|index| {
+                let mut brle = Brle::from_slice(&ignored[..=index]);
+                brle.mask(&[index], false);
+                brle.buffer
+            })
+            .collect::<Vec<_>>();
+        self.attention_mask(&token_masks);
+        Ok(())
+    }
+
+    /// Sets the attention mask of each input token directly, as the run-length
+    /// encoded buffer of a [`Brle`] in which `true` marks a token to ignore.
     pub fn attention_mask(&self, mask: &[Vec<u32>]) {
         api::forward::attention_mask(&self.inner, mask);
     }
 
     pub fn kv_cache(&self, kv_pages: &[KvPage], last_kv_page_len: usize) {
         let ptrs = kv_pages.iter().map(|kv| kv.ptr()).collect::<Vec<_>>();
-        api::forward::kv_cache(&self.inner, &ptrs, last_kv_page_len as u32);
+        self.kv_cache_ptrs(&ptrs, last_kv_page_len);
     }
 
     pub fn kv_cache_ptrs(&self, kv_page_ptrs: &[u32], last_kv_page_len: usize) {
+        let num_kv_tokens = match kv_page_ptrs.len() {
+            0 => 0,
+            n => (n - 1) * self.kv_page_size as usize + last_kv_page_len,
+        };
+        self.num_kv_tokens.set(Some(num_kv_tokens));
         api::forward::kv_cache(&self.inner, kv_page_ptrs, last_kv_page_len as u32);
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// A backend pool that hands out pointers until its `capacity` is exhausted.
+    struct MockPool {
+        next: u32,
+        capacity: u32,
+    }
+
+    impl MockPool {
+        fn allocate(&mut self, count: usize) -> Vec<u32> {
+            let end = (self.next + count as u32).min(self.capacity);
+            let ptrs = (self.next..end).collect();
+            self.next = end;
+            ptrs
+        }
+    }
+
+    #[test]
+    fn argmax_prefers_the_lowest_id_among_ties() {
+        let dist = Distribution {
+            ids: vec![7, 3, 5, 9],
+            probs: vec![0.3, 0.3, 0.1, 0.3],
+        };
+        assert_eq!(dist.argmax(), Some(3));
+        assert_eq!(argmax(&[], &[]), None);
+    }
+
+    #[test]
+    fn writes_to_a_copied_page_leave_the_original_untouched() {
+        let mut pool = MockPool {
+            next: 0,
+            capacity: 16,
+        };
+        // The contents of each allocated page, by pointer.
+        let mut pages = vec![vec![1, 2, 3]];
+        let original = pool.allocate(1)[0];
+
+        let copy = copy_to_new_pages(
+            &[original],
+            |count| pool.allocate(count),
+            |&ptr| ptr,
+            |src, dst| {
+                assert_eq!(dst[0] as usize, pages.len());
+                pages.push(pages[src[0] as usize].clone());
+            },
+        )[0];
+        assert_ne!(copy, original);
+        assert_eq!(pages[copy as usize], vec![1, 2, 3]);
+
+        pages[copy as usize].push(4);
+        assert_eq!(pages[original as usize], vec![1, 2, 3]);
+    }
+
+    #[test]
+    fn shared_imports_alias_the_export_and_owned_imports_copy_it() {
+        let mut pool = MockPool {
+            next: 0,
+            capacity: 16,
+        };
+        let mut pages = vec![vec![1, 2], vec![3]];
+        let exported = pool.allocate(2);
+
+        // A shared import hands out the exported pointers themselves, so writing into
+        // its partial last page changes the export.
+        let shared = exported.clone();
+        pages[shared[1] as usize].push(4);
+        assert_eq!(pages[exported[1] as usize], vec![3, 4]);
+
+        let owned = copy_to_new_pages(
+            &exported,
+            |count| pool.allocate(count),
+            |&ptr| ptr,
+            |src, dst| {
+                for (&src, &dst) in src.iter().zip(dst) {
+                    assert_eq!(dst as usize, pages.len());
+                    pages.push(pages[src as usize].clone());
+                }
+            },
+        );
+        assert!(owned.iter().all(|ptr| !exported.contains(ptr)));
+        assert_eq!(pages[owned[1] as usize], vec![3, 4]);
+        pages[owned[1] as usize].push(5);
+        assert_eq!(pages[exported[1] as usize], vec![3, 4]);
+
+        // Nothing to import allocates nothing and copies nothing.
+        let none = copy_to_new_pages(
+            &[],
+            |count| pool.allocate(count),
+            |&ptr| ptr,
+            |_, _| panic!("copied an empty import"),
+        );
+        assert!(none.is_empty());
+    }
+
+    #[test]
+    fn only_a_full_layer_export_is_accepted() {
+        assert!(check_exported_layers(&[]).is_ok());
+        assert!(matches!(
+            check_exported_layers(&[0, 1]),
+            Err(Error::InvalidArgument(_))
+        ));
+    }
+
+    #[test]
+    fn prefetched_chain_links_skip_the_import() {
+        let prefetched = RefCell::new(HashMap::from([
+            ("a_kv".to_string(), vec![0, 1]),
+            ("b_kv".to_string(), vec![2]),
+        ]));
+        let imported = RefCell::new(Vec::new());
+        let import = |name: &str| {
+            take_or_import(&prefetched, name, || {
+                imported.borrow_mut().push(name.to_string());
+                vec![9]
+            })
+        };
+
+        // Building a context from the chain only asks the host for the link that was
+        // not prefetched.
+        let pages: Vec<u32> = ["a_kv", "b_kv", "c_kv"]
+            .into_iter()
+            .flat_map(import)
+            .collect();
+        assert_eq!(pages, vec![0, 1, 2, 9]);
+        assert_eq!(*imported.borrow(), vec!["c_kv"]);
+        // Staged pages are handed out once; the next import asks the host again.
+        assert!(prefetched.borrow().is_empty());
+        assert_eq!(import("a_kv"), vec![9]);
+        assert_eq!(*imported.borrow(), vec!["c_kv", "a_kv"]);
+    }
+
+    #[test]
+    fn allocation_past_the_limit_fails_without_touching_the_pool() {
+        let mut pool = MockPool {
+            next: 0,
+            capacity: 16,
+        };
+        let mut ledger = KvPageLedger {
+            in_use: 0,
+            limit: Some(4),
+        };
+
+        assert_eq!(
+            ledger.allocate(3, |n| pool.allocate(n)).unwrap(),
+            vec![0, 1, 2]
+        );
+        match ledger.allocate(2, |n| pool.allocate(n)) {
+            Err(Error::OutOfMemory {
+                requested,
+                available,
+            }) => assert_eq!((requested, available), (2, 1)),
+            other => panic!("expected OutOfMemory, got {:?}", other),
+        }
+        assert_eq!(pool.next, 3);
+        assert_eq!(ledger.in_use, 3);
+
+        ledger.free(3);
+        assert_eq!(ledger.allocate(4, |n| pool.allocate(n)).unwrap().len(), 4);
+        assert_eq!(ledger.budget(), Some(0));
+    }
+
+    #[test]
+    fn imported_pages_do_not_count_against_the_limit() {
+        let mut ledger = KvPageLedger {
+            in_use: 0,
+            limit: Some(2),
+        };
+        ledger.allocate(2, |n| (0..n as u32).collect()).unwrap();
+        // Imported pages never go through the ledger, in either direction, so dropping
+        // them does not hand their count to this instance's budget.
+        assert_eq!(ledger.budget(), Some(0));
+        ledger.free(1);
+        assert_eq!(ledger.budget(), Some(1));
+    }
+
+    #[test]
+    fn no_limit_allows_any_allocation() {
+        let mut ledger = KvPageLedger::default();
+        assert_eq!(ledger.budget(), None);
+        assert_eq!(ledger.allocate(100, |n| vec![0; n]).unwrap().len(), 100);
+        assert_eq!(ledger.in_use, 100);
+    }
+}
@@ -1,39 +1,63 @@
-pub use crate::chat::ChatFormatter;
+pub use crate::chat::{ChatFormatter, ChatMessage, RoleTemplate};
 pub use crate::context::Context;
+pub use crate::diff::{DiffOp, diff_generations};
+pub use crate::error::Error as InferletError;
+use crate::forward::{Forward, KvPage};
 pub use crate::sampler::Sampler;
 use crate::stop_condition::StopCondition;
+pub use crate::stream::{Detokenize, StreamDecoder};
+use crate::wstd::future::FutureExt;
 use crate::wstd::runtime::AsyncPollable;
 pub use anyhow::{Context as AnyhowContext, Error, Result, anyhow, bail, ensure, format_err};
+use futures::Stream;
+use futures::future::join_all;
 pub use inferlet_macros::main;
 pub use pico_args::Arguments as Args;
-use std::collections::HashSet;
+use std::cell::{Cell, RefCell};
+use std::collections::{HashMap, HashSet};
 use std::rc::Rc;
+use std::time::{Duration, Instant};
 pub use wasi;
 pub use wstd;
 
 mod adapter;
+pub mod agent;
 pub mod api;
 pub mod brle;
 pub mod chat;
 pub mod context;
+mod diff;
 pub mod drafter;
+pub mod error;
 pub mod forward;
 mod image;
 mod pool;
 pub mod sampler;
 pub mod stop_condition;
+pub mod stream;
 mod zo;
 
 #[derive(Clone, Debug)]
 pub struct Queue {
     pub(crate) inner: Rc<api::Queue>,
     service_id: u32,
+    pub(crate) forward_pass_count: Rc<Cell<usize>>,
+    /// The model's maximum context length, which bounds the positions a forward pass
+    /// may use.
+    pub(crate) max_position: u32,
+    /// The number of tokens a KV page of the model holds.
+    pub(crate) kv_page_size: u32,
+    /// KV page pointers imported ahead of time with [`Queue::prefetch_kv_pages`], by
+    /// export name.
+    pub(crate) prefetched: Rc<RefCell<HashMap<String, Vec<u32>>>>,
 }
 
 /// Represents a specific model instance, providing access to its metadata and functionality.
 #[derive(Clone, Debug)]
 pub struct Model {
     pub(crate) inner: Rc<api::Model>,
+    dry_run: bool,
+    quota: Quota,
 }
 
 #[derive(Clone, Debug)]
@@ -41,17 +65,186 @@ pub struct Tokenizer {
     inner: Rc<api::tokenize::Tokenizer>,
 }
 
+/// Controls how generated token IDs are turned into the returned text, see
+/// [`Context::generate_with_decode_options`].
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct DecodeOptions {
+    /// Leave special tokens such as role markers and EOS out of the text. They remain
+    /// part of the context's token IDs either way.
+    pub skip_special: bool,
+    /// Remove the space some tokenizers leave before punctuation and English
+    /// contractions (`" ."`, `" ,"`, `" n't"`, `" 's"`, ...), like the
+    /// `clean_up_tokenization_spaces` option of Hugging Face tokenizers. Off by
+    /// default, which keeps the text exactly as the tokenizer decodes it.
+    pub clean_spaces: bool,
+}
+
+impl Default for DecodeOptions {
+    fn default() -> Self {
+        DecodeOptions {
+            skip_special: true,
+            clean_spaces: false,
+        }
+    }
+}
+
+/// Limits on how far each context of a [`Model`] handle may grow, see
+/// [`Model::with_quota`]. `None` leaves a dimension unlimited.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct Quota {
+    /// The most KV pages a context may hold.
+    pub max_pages: Option<usize>,
+    /// The most tokens (committed and pending) a context may hold.
+    pub max_tokens: Option<usize>,
+}
+
+impl Quota {
+    /// Fails with [`InferletError::QuotaExceeded`] if a context may not hold `pages` KV pages.
+    pub(crate) fn check_pages(&self, pages: usize) -> Result<(), InferletError> {
+        match self.max_pages {
+            Some(max_pages) if pages > max_pages => {
+                Err(InferletError::QuotaExceeded { pages, max_pages })
+            }
+            _ => Ok(()),
+        }
+    }
+}
+
 #[derive(Debug)]
 pub struct Blob {
     pub(crate) inner: api::Blob,
 }
 
+/// A flag that stops a [`Context::generate_cancellable`] call before its next step.
+///
+/// Clones share the flag, so one clone can be handed to the generation while another
+/// is kept to trigger it.
+#[derive(Clone, Debug, Default)]
+pub struct CancelToken {
+    cancelled: Rc<Cell<bool>>,
+}
+
+impl CancelToken {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    pub fn cancel(&self) {
+        self.cancelled.set(true);
+    }
+
+    pub fn is_cancelled(&self) -> bool {
+        self.cancelled.get()
+    }
+}
+
+thread_local! {
+    static SHUTDOWN_TOKEN: CancelToken = CancelToken::new();
+    /// Whether an event loop started with [`block_on`] is running.
+    static IN_EVENT_LOOP: Cell<bool> = const { Cell::new(false) };
+}
+
+/// Runs `fut` to completion on a new event loop, see [`wstd::runtime::block_on`].
+///
+/// Unlike calling wstd directly, this records that the loop is running, so blocking
+/// helpers such as [`Context::generate_blocking`] can tell when they are called from
+/// async code. The `#[inferlet::main]` entry point runs through it.
+pub fn block_on<F>(fut: F) -> F::Output
+where
+    F: Future + 'static,
+    F::Output: 'static,
+{
+    let _running = EventLoopGuard::enter();
+    wstd::runtime::block_on(fut)
+}
+
+/// Marks an event loop as running until dropped, then restores the previous state, so
+/// leaving a nested [`block_on`] (or unwinding out of one) keeps the outer loop marked.
+struct EventLoopGuard {
+    was_running: bool,
+}
+
+impl EventLoopGuard {
+    fn enter() -> Self {
+        EventLoopGuard {
+            was_running: IN_EVENT_LOOP.replace(true),
+        }
+    }
+}
+
+impl Drop for EventLoopGuard {
+    fn drop(&mut self) {
+        IN_EVENT_LOOP.set(self.was_running);
+    }
+}
+
+/// Whether the calling code runs inside [`block_on`].
+pub(crate) fn in_event_loop() -> bool {
+    IN_EVENT_LOOP.get()
+}
+
+/// The KV cache memory of this instance, counted in KV pages, see [`device_memory`].
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct MemoryInfo {
+    /// `used` plus `free`.
+    pub total: usize,
+    /// The pages this instance allocated and has not freed yet.
+    pub used: usize,
+    /// The pages this instance can still allocate.
+    pub free: usize,
+}
+
+/// Reports how much KV cache memory this instance holds and can still allocate on the
+/// device of `model`, e.g. for a supervisor to watch a tree of branches.
+///
+/// Memory is counted in KV pages of [`Model::get_kv_page_size`] tokens each, since the
+/// host does not report the byte size of a page. `free` is what the device's pool can
+/// still hand out, capped by [`set_memory_limit`]; like
+/// [`Forward::available_kv_pages`](forward::Forward::available_kv_pages), it is a
+/// best-effort estimate. Pages imported from other instances' exports are not counted
+/// as used.
+pub fn device_memory(model: &Model) -> MemoryInfo {
+    let queue = model.create_queue();
+    let used = forward::kv_pages_in_use();
+    let mut free = queue.get_available_resources(Resource::KvPage);
+    if let Some(budget) = forward::kv_page_budget() {
+        free = free.min(budget);
+    }
+    MemoryInfo {
+        total: used + free,
+        used,
+        free,
+    }
+}
+
+/// Caps how many KV pages this instance may hold at once, or lifts the cap with `None`.
+///
+/// Past the cap, [`Forward::try_new_kv_pages`](forward::Forward::try_new_kv_pages)
+/// fails with [`InferletError::OutOfMemory`] and generation ends with
+/// [`FinishReason::OutOfMemory`](context::FinishReason::OutOfMemory) before the step
+/// that would allocate, instead of exhausting the device, where the host would make room
+/// by terminating newer instances. Lowering the cap below what is held frees nothing.
+pub fn set_memory_limit(max_pages: Option<usize>) {
+    forward::set_kv_page_limit(max_pages);
+}
+
 pub enum Resource {
     KvPage = 0,
     Embed = 1,
     Adapter = 2,
 }
 
+/// Returns the instance-wide cancel handle.
+///
+/// Triggering it (e.g. when the scheduler kills the run) stops every generation loop
+/// of this instance at once, including those started without a per-call
+/// [`CancelToken`]. Forward passes that have already been submitted complete, but no
+/// new step begins; the interrupted calls return what they generated so far and
+/// report [`FinishReason::Cancelled`](context::FinishReason::Cancelled).
+pub fn shutdown_token() -> CancelToken {
+    SHUTDOWN_TOKEN.with(CancelToken::clone)
+}
+
 /// Returns the runtime version string.
 pub fn get_version() -> String {
     api::runtime::get_version()
@@ -62,11 +255,41 @@ pub fn get_instance_id() -> String {
     api::runtime::get_instance_id()
 }
 
+/// A running inferlet instance as reported by [`list_active`]: its ID (see
+/// [`get_instance_id`]), command name and arguments, and the topics it currently
+/// waits on with [`subscribe`] or [`subscribe_stream`].
+pub use api::runtime::ActiveInstance;
+
+/// Lists the inferlet instances running on the host, e.g. for a monitor to spot an
+/// agent that never subscribed to the topic another one is publishing to.
+///
+/// The result is a best-effort snapshot: the host reads its instance registry and its
+/// pub/sub registry one after the other, so instances that start, finish or
+/// (un)subscribe meanwhile may be missing or show stale topics. A plain [`subscribe`]
+/// only appears while the call is waiting for its message.
+pub fn list_active() -> Vec<ActiveInstance> {
+    api::runtime::list_active()
+}
+
 /// Retrieves POSIX-style CLI arguments passed to the inferlet from the remote user client.
 pub fn get_arguments() -> Vec<String> {
     api::runtime::get_arguments()
 }
 
+/// Derives a sampling seed from a task ID, for [`Sampler::with_seed`].
+///
+/// The ID is hashed with 64-bit FNV-1a, so the same ID maps to the same seed across
+/// runs, builds and hosts. Branches of one job that each pass their own ID, e.g.
+/// `"job-7/branch-2"`, diverge from each other yet replay exactly when rerun.
+pub fn seed_from(task_id: &str) -> u64 {
+    context::fnv1a_bytes(task_id.bytes())
+}
+
+/// Returns the number of pages of `page_size` tokens needed to hold `num_tokens` tokens.
+pub(crate) fn pages_for_tokens(num_tokens: usize, page_size: usize) -> usize {
+    num_tokens.div_ceil(page_size)
+}
+
 pub fn set_return(value: &str) {
     api::runtime::set_return(value);
 }
@@ -77,6 +300,8 @@ pub fn set_return(value: &str) {
 pub fn get_model(name: &str) -> Option<Model> {
     api::runtime::get_model(name).map(|inner| Model {
         inner: Rc::new(inner),
+        dry_run: false,
+        quota: Quota::default(),
     })
 }
 
@@ -91,6 +316,57 @@ pub fn get_auto_model() -> Model {
     model
 }
 
+/// How long [`Model::is_ready`] waits for the backend to answer its probe.
+const READY_PROBE_TIMEOUT: Duration = Duration::from_secs(1);
+
+/// The pause between two readiness probes of [`wait_for_model`].
+const READY_POLL_INTERVAL: Duration = Duration::from_millis(250);
+
+/// Waits until a model is available and its backend answers requests, then returns it.
+///
+/// The model is chosen like in [`get_auto_model`]. Instead of panicking when no model
+/// is registered yet, the host is polled until one is and [`Model::is_ready`] holds,
+/// so an inferlet started on a cold or overloaded host can wait for the backend
+/// before its first forward pass. Fails once `timeout` has passed.
+pub async fn wait_for_model(timeout: Duration) -> Result<Model, InferletError> {
+    let deadline = Instant::now() + timeout;
+    loop {
+        let model = get_all_models().first().and_then(|name| get_model(name));
+        if let Some(model) = &model
+            && model.is_ready().await
+        {
+            return Ok(model.clone());
+        }
+
+        if Instant::now() >= deadline {
+            match model {
+                Some(model) => {
+                    return Err(InferletError::Backend(format!(
+                        "Model '{}' did not become ready within {:?}",
+                        model.get_name(),
+                        timeout
+                    )));
+                }
+                None => {
+                    return Err(InferletError::Backend(format!(
+                        "No model became available within {:?}",
+                        timeout
+                    )));
+                }
+            }
+        }
+        wstd::task::sleep(READY_POLL_INTERVAL.into()).await;
+    }
+}
+
+/// Returns the `primary` model if it is available, and the `fallback` model otherwise.
+///
+/// Pair it with [`Context::generate_with_fallback`] to also survive the primary
+/// model failing after generation has started.
+pub fn get_model_with_fallback(primary: &str, fallback: &str) -> Option<Model> {
+    get_model(primary).or_else(|| get_model(fallback))
+}
+
 /// Get a list of all available model names.
 pub fn get_all_models() -> Vec<String> {
     api::runtime::get_all_models()
@@ -134,11 +410,32 @@ pub async fn receive_blob() -> Blob {
 }
 
 /// Publishes a message to a topic, broadcasting it to all subscribers.
+///
+/// The host delivers the messages of a topic in a single order to all subscribers:
+/// the order in which it received them, so the messages of one publisher arrive in the
+/// order they were broadcast. A message only reaches the inferlets that are subscribed
+/// while it is delivered, and a subscriber that falls too far behind misses messages;
+/// use [`broadcast_ordered`] to make such gaps detectable.
 pub fn broadcast(topic: &str, message: &str) {
     api::message::broadcast(topic, message)
 }
 
-/// Subscribes to a topic and returns a `Subscription` handle.
+/// Like [`broadcast`], additionally tagging the message with the topic's next sequence
+/// number, which is returned.
+///
+/// Sequence numbers are assigned by the host in delivery order, per topic and starting
+/// at 1, no matter how many inferlets publish to the topic. Receive the messages with
+/// [`subscribe_ordered`]; a plain [`subscribe`] sees them with a `"<seq>:"` prefix.
+pub fn broadcast_ordered(topic: &str, message: &str) -> u64 {
+    api::message::broadcast_ordered(topic, message)
+}
+
+/// Subscribes to a topic and waits for the next message published to it.
+///
+/// Messages are returned in the order described in [`broadcast`]. The subscription
+/// only lasts for this call: it is removed from the topic once the message arrives, or
+/// when the returned future is dropped before that (e.g. on a timeout), so an abandoned
+/// call never receives a message meant for a later subscriber.
 pub async fn subscribe<S: ToString>(topic: S) -> String {
     let topic = topic.to_string();
     let future = api::message::subscribe(&topic); // Changed from messaging::subscribe
@@ -147,13 +444,139 @@ pub async fn subscribe<S: ToString>(topic: S) -> String {
     future.get().unwrap()
 }
 
+/// Like [`subscribe`], but gives up after `timeout` and returns `None`.
+///
+/// The subscription is removed from the topic when the call times out.
+pub async fn subscribe_timeout<S: ToString>(topic: S, timeout: Duration) -> Option<String> {
+    subscribe(topic)
+        .timeout(wstd::time::Duration::from(timeout))
+        .await
+        .ok()
+}
+
+/// Waits for the next message published to `topic` with [`broadcast_ordered`] and
+/// returns its sequence number together with the message.
+///
+/// A sequence number that is not one more than the previous one reveals messages this
+/// inferlet missed. Fails if the message was published with a plain [`broadcast`].
+pub async fn subscribe_ordered<S: ToString>(topic: S) -> Result<(u64, String), InferletError> {
+    split_seq(&subscribe(topic).await)
+}
+
+/// Splits a message published with [`broadcast_ordered`] into its sequence number and
+/// the message itself.
+fn split_seq(raw: &str) -> Result<(u64, String), InferletError> {
+    raw.split_once(':')
+        .and_then(|(seq, message)| Some((seq.parse().ok()?, message.to_string())))
+        .ok_or_else(|| {
+            InferletError::ParseInput(format!("Message has no sequence number: {}", raw))
+        })
+}
+
+/// A subscription to a topic that is kept until dropped, buffering the messages that
+/// arrive between reads.
+///
+/// Unlike [`subscribe`], which only sees the next message, an inbox collects everything
+/// published to the topic after it was opened, so a consumer can wait for the first
+/// message and then take whatever else is ready in one call. Up to 64 messages are
+/// buffered; a consumer that falls further behind misses messages.
+pub struct Inbox {
+    subscription: api::message::Subscription,
+}
+
+impl Inbox {
+    /// Subscribes to `topic`. Messages published before this call are not received.
+    pub fn new<S: ToString>(topic: S) -> Self {
+        Inbox {
+            subscription: api::message::subscribe(&topic.to_string()),
+        }
+    }
+
+    /// Waits until at least one message is buffered, without taking it.
+    pub async fn wait(&self) {
+        AsyncPollable::new(self.subscription.pollable())
+            .wait_for()
+            .await;
+    }
+
+    /// Takes every message buffered so far, in the order they were published.
+    ///
+    /// Does not wait for future messages: the result is empty if none arrived since the
+    /// last read.
+    pub fn drain(&self) -> Vec<String> {
+        self.subscription.drain()
+    }
+
+    /// Like [`Inbox::drain`], for messages published with [`broadcast_ordered`], split
+    /// into their sequence numbers and the messages.
+    ///
+    /// Fails if a message was published with a plain [`broadcast`]; the messages
+    /// drained with it are lost.
+    pub fn drain_ordered(&self) -> Result<Vec<(u64, String)>, InferletError> {
+        self.drain().iter().map(|raw| split_seq(raw)).collect()
+    }
+}
+
+/// Prefix of the messages that carry the chunks of a [`broadcast_chunks`] stream.
+const STREAM_CHUNK_PREFIX: &str = "chunk:";
+
+/// The message that ends a stream published with [`broadcast_chunks`].
+pub const STREAM_END: &str = "end:";
+
+/// Publishes `chunks` to `topic` one message at a time, followed by [`STREAM_END`], so
+/// that [`subscribe_stream`] consumers can process a large payload while it is still
+/// being produced.
+///
+/// Each chunk is sent as its own message, prefixed with `"chunk:"`. Like a
+/// [`broadcast`], the chunks only reach the inferlets that are subscribed when they are
+/// delivered, so consumers have to subscribe before the producer starts.
+pub fn broadcast_chunks(topic: &str, chunks: impl IntoIterator<Item = String>) {
+    for chunk in chunks {
+        broadcast(topic, &format!("{}{}", STREAM_CHUNK_PREFIX, chunk));
+    }
+    broadcast(topic, STREAM_END);
+}
+
+/// Subscribes to `topic` and yields the chunks of the next [`broadcast_chunks`] stream
+/// as they arrive, ending at its [`STREAM_END`] marker.
+///
+/// The subscription is made when this function is called and kept for the whole
+/// stream, so chunks published while the consumer is busy are buffered; a consumer that
+/// falls more than 64 messages behind misses chunks. Other messages on the topic are
+/// skipped. Dropping the stream removes the subscription.
+pub fn subscribe_stream<S: ToString>(topic: S) -> impl Stream<Item = String> {
+    let subscription = api::message::subscribe(&topic.to_string());
+    futures::stream::unfold(subscription, |subscription| async move {
+        loop {
+            AsyncPollable::new(subscription.pollable()).wait_for().await;
+            let message = subscription.get()?;
+            if message == STREAM_END {
+                return None;
+            }
+            if let Some(chunk) = message.strip_prefix(STREAM_CHUNK_PREFIX) {
+                return Some((chunk.to_string(), subscription));
+            }
+        }
+    })
+}
+
 /// Retrieves a value from the persistent store for a given key.
 ///
 /// Returns `Some(value)` if the key exists, or `None` if it does not.
+///
+/// Use [`store_watch`] to wait for another inferlet to write a missing key.
 pub fn store_get(key: &str) -> Option<String> {
     api::kvs::store_get(key)
 }
 
+/// Retrieves the values for several keys from the persistent store in a single call.
+///
+/// The result has one entry per key, in the same order as `keys`; missing keys
+/// yield `None`.
+pub fn store_get_many(keys: &[String]) -> Vec<Option<String>> {
+    api::kvs::store_get_many(keys)
+}
+
 /// Sets a value in the persistent store for a given key.
 ///
 /// This will create a new entry or overwrite an existing one.
@@ -161,6 +584,16 @@ pub fn store_set(key: &str, value: &str) {
     api::kvs::store_set(key, value)
 }
 
+/// Sets several key-value pairs in the persistent store at once.
+///
+/// The entries are committed together: another inferlet reading the store
+/// observes either none or all of them, never a partially written batch.
+/// Concurrent batches touching the same keys are applied one after another,
+/// so the last batch to reach the store wins for each key.
+pub fn store_set_batch(entries: &[(String, String)]) {
+    api::kvs::store_set_batch(entries)
+}
+
 /// Deletes a key-value pair from the store.
 ///
 /// If the key does not exist, this function does nothing.
@@ -180,6 +613,124 @@ pub fn store_list_keys() -> Vec<String> {
     api::kvs::store_list_keys()
 }
 
+/// Takes up to `amount` from the unsigned integer stored under `key` and returns how
+/// much was taken.
+///
+/// The host applies the take as one operation, so inferlets drawing from the same key
+/// concurrently never take more than it held in total. The result is less than
+/// `amount` once the value runs out, and 0 if the key does not exist or does not hold
+/// an unsigned integer.
+pub fn store_take(key: &str, amount: u64) -> u64 {
+    api::kvs::store_take(key, amount)
+}
+
+/// Checks that a queue can serve KV pages of `page_size` tokens on a backend whose
+/// pages hold `supported` tokens, see [`Model::create_queue_with_page_size`].
+fn check_kv_page_size(page_size: u32, supported: u32) -> Result<(), InferletError> {
+    if page_size != supported {
+        return Err(InferletError::InvalidArgument(format!(
+            "KV page size {} is not supported by the backend (only {} is)",
+            page_size, supported
+        )));
+    }
+    Ok(())
+}
+
+/// Returns the store key of the token budget of `run_id`.
+pub(crate) fn run_budget_key(run_id: &str) -> String {
+    format!("{}_budget", run_id)
+}
+
+/// Creates a pool of `max_tokens` tokens shared by every agent of the run `run_id`.
+///
+/// Agents draw from it with [`Context::generate_within_budget`], one token per decode
+/// step, and stop with [`FinishReason::BudgetExhausted`] once it is empty, which caps
+/// the total cost of a run however many agents it spawns. Calling it again resets the
+/// pool to `max_tokens`.
+///
+/// [`FinishReason::BudgetExhausted`]: crate::context::FinishReason::BudgetExhausted
+pub fn run_budget(run_id: &str, max_tokens: u64) {
+    store_set(&run_budget_key(run_id), &max_tokens.to_string());
+}
+
+/// Returns how many tokens are left in the pool of `run_id`, or `None` if
+/// [`run_budget`] was not called for it.
+pub fn run_budget_remaining(run_id: &str) -> Option<u64> {
+    store_get(&run_budget_key(run_id)).and_then(|value| value.parse().ok())
+}
+
+/// Waits until `key` holds a value and returns it.
+///
+/// Resolves immediately with the current value if the key already exists, otherwise
+/// with the value of the next [`store_set`] or [`store_set_batch`] of the key, by any
+/// inferlet. This lets one inferlet wait for another's result without polling.
+pub async fn store_watch(key: &str) -> String {
+    let watch = api::kvs::store_watch(key);
+    let pollable = watch.pollable();
+    AsyncPollable::new(pollable).wait_for().await;
+    watch.get().unwrap()
+}
+
+/// Like [`store_watch`], but gives up after `timeout` and returns `None`.
+pub async fn store_watch_timeout(key: &str, timeout: Duration) -> Option<String> {
+    store_watch(key)
+        .timeout(wstd::time::Duration::from(timeout))
+        .await
+        .ok()
+}
+
+/// Runs several independent chats concurrently on the auto-selected model.
+///
+/// Each conversation gets its own [`Context`] and is rendered through the model's
+/// chat template; it must end with a [`ChatMessage::User`] message. Results are
+/// returned in the same order as `conversations`, and a conversation that cannot be
+/// run (empty, not ending with a user turn, or larger than the context window)
+/// yields an error without affecting the others, as does one whose forward pass
+/// fails mid-generation.
+pub async fn chat_many<S: StopCondition>(
+    conversations: &[Vec<ChatMessage>],
+    sampler: Sampler,
+    stop_condition: S,
+) -> Vec<Result<String, InferletError>> {
+    let model = get_auto_model();
+
+    let mut contexts = conversations
+        .iter()
+        .map(|messages| {
+            if messages.is_empty() {
+                return Err(InferletError::InvalidArgument(
+                    "Conversation is empty".to_string(),
+                ));
+            }
+            if !matches!(messages.last(), Some(ChatMessage::User(_))) {
+                return Err(InferletError::InvalidArgument(
+                    "Conversation must end with a user message".to_string(),
+                ));
+            }
+            let mut ctx = model.create_context();
+            ctx.fill_messages(messages);
+            if ctx.remaining_context() == 0 {
+                return Err(InferletError::ContextFull);
+            }
+            Ok(ctx)
+        })
+        .collect::<Vec<_>>();
+
+    let replies = join_all(
+        contexts
+            .iter_mut()
+            .filter_map(|ctx| ctx.as_mut().ok())
+            .map(|ctx| ctx.generate_shared(&sampler, &stop_condition)),
+    )
+    .await;
+
+    let mut replies = replies.into_iter();
+    contexts
+        .into_iter()
+        .map(|ctx| ctx.and_then(|_| replies.next().unwrap()))
+        .collect()
+}
+
 /// Executes a debug command and returns the result as a string.
 pub async fn debug_query(query: &str) -> String {
     let future = api::runtime::debug_query(query);
@@ -189,6 +740,51 @@ pub async fn debug_query(query: &str) -> String {
 }
 
 impl Model {
+    /// Returns a handle to this model whose contexts do not run the model when generating.
+    ///
+    /// Every `generate*` call on a context created from the returned handle produces a
+    /// short placeholder, `[dry run <hash>]`, where the hash is derived from the
+    /// context's tokens, so the same prompt always yields the same text. Stop conditions
+    /// still apply per token, so e.g. `max_len` truncates the placeholder. This is meant
+    /// for exercising an agent's wiring (messaging, store keys) without spending compute;
+    /// prompts are still tokenized but never forwarded.
+    pub fn dry_run(&self) -> Model {
+        Model {
+            inner: self.inner.clone(),
+            dry_run: true,
+            quota: self.quota,
+        }
+    }
+
+    /// Returns a handle to this model whose contexts stay within `quota`, so that one
+    /// runaway agent cannot take the KV cache of a shared host from the others.
+    ///
+    /// Generation on such a context ends with [`FinishReason::QuotaExceeded`] once the
+    /// next token would exceed the quota. Any other attempt to grow the context's KV
+    /// cache beyond `max_pages` fails with [`InferletError::QuotaExceeded`] from
+    /// [`Context::try_flush`] and [`Context::try_grow_kv_pages`], or panics with that
+    /// error from their infallible counterparts, instead of drawing on the host's
+    /// pages. Check [`Context::remaining_quota`] before filling a prompt to avoid that.
+    ///
+    /// [`FinishReason::QuotaExceeded`]: crate::context::FinishReason::QuotaExceeded
+    pub fn with_quota(&self, quota: Quota) -> Model {
+        Model {
+            inner: self.inner.clone(),
+            dry_run: self.dry_run,
+            quota,
+        }
+    }
+
+    /// Returns the quota set with [`Model::with_quota`], unlimited by default.
+    pub fn quota(&self) -> Quota {
+        self.quota
+    }
+
+    /// Whether this handle was created with [`Model::dry_run`].
+    pub fn is_dry_run(&self) -> bool {
+        self.dry_run
+    }
+
     /// Returns the model's name (e.g. "llama-3.1-8b-instruct").
     pub fn get_name(&self) -> String {
         self.inner.get_name()
@@ -228,6 +824,46 @@ impl Model {
         self.inner.get_prompt_template()
     }
 
+    /// Returns the markers the model's chat template puts around a message of `role`
+    /// (`"system"`, `"user"`, `"assistant"` or `"tool"`), e.g. to hand-craft a
+    /// multi-part prompt that matches what [`Context::fill_user`] and friends produce.
+    ///
+    /// The markers are found by rendering a single message with the template, without
+    /// the beginning-of-sequence marker or a generation prompt, so they agree with
+    /// [`Model::apply_chat_template`] for templates that render each message on its
+    /// own. Templates that treat a message differently depending on its neighbours
+    /// (e.g. fold the system prompt into the first user turn) can differ. Base models
+    /// without a chat template get an empty [`RoleTemplate`].
+    pub fn role_template(&self, role: &str) -> RoleTemplate {
+        RoleTemplate::from_template(&self.get_prompt_template(), role)
+    }
+
+    /// Returns the tokens of the marker that opens a message of `role`, see
+    /// [`Model::role_template`]. Empty for base models.
+    pub fn role_tokens(&self, role: &str) -> Vec<u32> {
+        let prefix = self.role_template(role).prefix;
+        if prefix.is_empty() {
+            return Vec::new();
+        }
+        self.get_tokenizer().tokenize(&prefix)
+    }
+
+    /// Renders `messages` with the model's chat template, exactly like a fresh
+    /// [`Context`] filled with [`Context::fill_messages`], except that the generation
+    /// prompt is appended if `add_generation_prompt` is set rather than depending on
+    /// the last message.
+    pub fn apply_chat_template(
+        &self,
+        messages: &[ChatMessage],
+        add_generation_prompt: bool,
+    ) -> String {
+        let mut formatter = ChatFormatter::new();
+        for message in messages {
+            formatter.message(message);
+        }
+        formatter.render(&self.get_prompt_template(), add_generation_prompt, true)
+    }
+
     pub fn eos_tokens(&self) -> Vec<Vec<u32>> {
         let tokenizer = api::tokenize::get_tokenizer(&self.inner);
         self.inner
@@ -237,6 +873,33 @@ impl Model {
             .collect()
     }
 
+    /// Returns the stop condition that ends this model's outputs.
+    ///
+    /// It matches every stop sequence the model declares, i.e. its EOS tokens and the
+    /// end-of-turn markers of its chat format. Each sequence is matched as a whole, so a
+    /// marker that tokenizes to several tokens only stops generation once all of its
+    /// tokens were produced. Combine it with a length limit, e.g.
+    /// `ctx.generate(sampler, model.default_stop_condition().or(max_len(n)))`.
+    pub fn default_stop_condition(&self) -> stop_condition::AnyEndsWith {
+        self.stop_condition_with(&[])
+    }
+
+    /// Returns [`Model::default_stop_condition`] extended with `extra_stops`, e.g. a
+    /// task-specific delimiter.
+    ///
+    /// Duplicate sequences are matched once. When one sequence is a suffix of another
+    /// both are kept, and the longer one is reported as the match (see
+    /// [`StopCondition::matched_sequence`]).
+    pub fn stop_condition_with(&self, extra_stops: &[&[u32]]) -> stop_condition::AnyEndsWith {
+        let mut stop_sequences = self.eos_tokens();
+        stop_sequences.extend(extra_stops.iter().map(|seq| seq.to_vec()));
+        // An empty sequence would match immediately and stop every generation.
+        stop_sequences.retain(|seq| !seq.is_empty());
+        stop_sequences.sort();
+        stop_sequences.dedup();
+        stop_condition::ends_with_any(stop_sequences)
+    }
+
     /// Gets the service ID for the model.
     pub fn get_service_id(&self) -> u32 {
         self.inner.get_service_id()
@@ -246,25 +909,249 @@ impl Model {
         self.inner.get_kv_page_size()
     }
 
+    /// Returns the number of KV pages needed to hold `num_tokens` tokens.
+    ///
+    /// This is the ceiling of `num_tokens / page_size`: zero tokens need no page, a
+    /// full page's worth needs one, and one token more needs a second page.
+    pub fn pages_for_tokens(&self, num_tokens: usize) -> usize {
+        pages_for_tokens(num_tokens, self.get_kv_page_size() as usize)
+    }
+
+    /// Returns the maximum number of tokens a single context can hold.
+    pub fn get_max_context_length(&self) -> u32 {
+        self.inner.get_max_context_length()
+    }
+
+    /// Whether the model's backend currently answers requests.
+    ///
+    /// Sends a ping to the backend and waits up to a second for the reply, so a backend
+    /// that is still loading or too busy to respond counts as not ready. Dry-run models
+    /// never reach the backend and are always ready.
+    pub async fn is_ready(&self) -> bool {
+        if self.dry_run {
+            return true;
+        }
+        let queue = self.create_queue();
+        queue
+            .debug_query("ping")
+            .timeout(wstd::time::Duration::from(READY_PROBE_TIMEOUT))
+            .await
+            .is_ok()
+    }
+
+    /// Returns the hit/miss/eviction counters of this model's shared KV prefixes.
+    ///
+    /// Prefixes are shared between inferlets by exporting and importing KV pages,
+    /// so a hit is an import of an existing export, a miss is an import of an
+    /// unknown name, and an eviction is a released (or overwritten) export.
+    /// The counters are global to the model, not to the calling inferlet.
+    pub fn prefix_cache_stats(&self) -> api::CacheStats {
+        self.inner.get_prefix_cache_stats()
+    }
+
+    /// Resets the counters returned by [`Model::prefix_cache_stats`] to zero.
+    pub fn reset_prefix_cache_stats(&self) {
+        self.inner.reset_prefix_cache_stats()
+    }
+
     /// Create a new command queue for this model.
     pub fn create_queue(&self) -> Queue {
         Queue {
             inner: Rc::new(self.inner.create_queue()),
             service_id: self.inner.get_service_id(),
+            forward_pass_count: Rc::new(Cell::new(0)),
+            max_position: self.get_max_context_length(),
+            kv_page_size: self.get_kv_page_size(),
+            prefetched: Rc::new(RefCell::new(HashMap::new())),
         }
     }
 
+    /// Creates a command queue after checking that it can serve KV pages of
+    /// `page_size` tokens.
+    ///
+    /// The backend lays out its KV cache in fixed-size pages when it starts (its
+    /// `kv_page_size` launch option), so the only allowed size is
+    /// [`Model::get_kv_page_size`] and any other value is rejected. Smaller pages waste
+    /// less memory on short outputs, while larger pages cut per-page bookkeeping and
+    /// attention overhead on long runs; that tradeoff is made when launching the backend.
+    pub fn create_queue_with_page_size(&self, page_size: u32) -> Result<Queue, InferletError> {
+        check_kv_page_size(page_size, self.get_kv_page_size())?;
+        Ok(self.create_queue())
+    }
+
     pub fn create_context(&self) -> Context {
         Context::new(self)
     }
+
+    /// Scores how well each of `labels` describes `text` (zero-shot classification).
+    ///
+    /// Uses [`DEFAULT_SCORE_TEMPLATE`]; see [`Model::score_with_template`].
+    pub async fn score(&self, text: &str, labels: &[&str]) -> Vec<f32> {
+        self.score_with_template(text, labels, DEFAULT_SCORE_TEMPLATE)
+            .await
+    }
+
+    /// Scores how well each of `labels` describes `text`, using a custom prompt.
+    ///
+    /// `template` is rendered by replacing `{text}` with `text` and `{labels}` with the
+    /// comma-separated labels, and sent as a user turn through the chat template. The
+    /// score of a label is the log-probability that the assistant answers with exactly
+    /// that label (see [`Context::continuation_log_prob`]), divided by the label's
+    /// length in tokens. Without that, every extra token lowers the total, so longer
+    /// labels would lose to shorter ones regardless of the text; with it, scores are
+    /// comparable across labels and higher is better. Each label costs one forward pass.
+    pub async fn score_with_template(
+        &self,
+        text: &str,
+        labels: &[&str],
+        template: &str,
+    ) -> Vec<f32> {
+        let prompt = template
+            .replace("{labels}", &labels.join(", "))
+            .replace("{text}", text);
+        self.score_prompt(&prompt, labels).await
+    }
+
+    /// Ranks `candidates` by relevance to `query`, most relevant first.
+    ///
+    /// Uses [`DEFAULT_RERANK_TEMPLATE`]; see [`Model::rerank_with_template`].
+    pub async fn rerank(&self, query: &str, candidates: &[&str]) -> Vec<(usize, f32)> {
+        self.rerank_with_template(query, candidates, DEFAULT_RERANK_TEMPLATE)
+            .await
+    }
+
+    /// Ranks `candidates` by relevance to `query`, using a custom prompt.
+    ///
+    /// For each candidate, `template` is rendered by replacing `{query}` and
+    /// `{candidate}`, and the model is asked to answer "Yes" or "No". The relevance is
+    /// the per-token log-probability of "Yes" minus that of "No" (see [`Model::score`]).
+    /// All candidates are returned as `(index, relevance)` pairs sorted by descending
+    /// relevance (ties keep input order), so callers can take the top-k themselves.
+    /// Each candidate costs two forward passes.
+    pub async fn rerank_with_template(
+        &self,
+        query: &str,
+        candidates: &[&str],
+        template: &str,
+    ) -> Vec<(usize, f32)> {
+        let relevances = candidates
+            .iter()
+            .map(|candidate| {
+                let prompt = template
+                    .replace("{query}", query)
+                    .replace("{candidate}", candidate);
+                async move {
+                    let scores = self.score_prompt(&prompt, &["Yes", "No"]).await;
+                    scores[0] - scores[1]
+                }
+            })
+            .collect::<Vec<_>>();
+
+        let mut ranked = join_all(relevances)
+            .await
+            .into_iter()
+            .enumerate()
+            .collect::<Vec<_>>();
+        ranked.sort_by(|a, b| b.1.total_cmp(&a.1));
+        ranked
+    }
+
+    /// Scores each label as the assistant's answer to `prompt`, as its mean per-token
+    /// log-probability.
+    async fn score_prompt(&self, prompt: &str, labels: &[&str]) -> Vec<f32> {
+        let mut ctx = self.create_context();
+        ctx.fill_user(prompt);
+        ctx.flush_prefix().await;
+
+        let tokenizer = self.get_tokenizer();
+        let scores = labels
+            .iter()
+            .map(|label| tokenizer.tokenize(label))
+            .map(|label_ids| {
+                let ctx = &ctx;
+                async move {
+                    let num_tokens = label_ids.len().max(1) as f32;
+                    ctx.continuation_log_prob(&label_ids).await / num_tokens
+                }
+            })
+            .collect::<Vec<_>>();
+        join_all(scores).await
+    }
 }
 
+/// The prompt [`Model::score`] uses for zero-shot classification.
+///
+/// `{text}` and `{labels}` are replaced by the input text and the comma-separated labels.
+pub const DEFAULT_SCORE_TEMPLATE: &str = "Classify the following text.\n\n\
+Text:\n{text}\n\n\
+Labels: {labels}\n\n\
+Answer with exactly one of the labels and nothing else.";
+
+/// The prompt [`Model::rerank`] uses to judge a single candidate.
+///
+/// `{query}` and `{candidate}` are replaced by the query and the candidate text.
+pub const DEFAULT_RERANK_TEMPLATE: &str = "Query:\n{query}\n\n\
+Document:\n{candidate}\n\n\
+Is the document relevant to the query? Answer Yes or No.";
+
 impl Queue {
     /// Gets the service ID for the queue.
     pub fn get_service_id(&self) -> u32 {
         self.service_id
     }
 
+    /// Returns the number of forward passes executed on this queue so far.
+    pub fn forward_pass_count(&self) -> usize {
+        self.forward_pass_count.get()
+    }
+
+    /// Imports the KV pages exported under each of `keys` ahead of time, e.g. while a
+    /// scheduler is still deciding what an agent runs, so that building its context
+    /// later does not wait for the imports.
+    ///
+    /// The next import of a prefetched key on this queue or a clone of it, e.g. by
+    /// [`Context::from_multiple_imports`] or [`Forward::try_import_kv_pages`], takes
+    /// the staged pages instead of asking the host again. Prefetched pages are held
+    /// like any imported ones: if the exporter releases the key in the meantime, the
+    /// host keeps the pages valid until this instance exits, so they resist eviction
+    /// until they are used or discarded with [`Queue::discard_prefetched`]. Keys that
+    /// are already prefetched are not imported again.
+    ///
+    /// Fails with [`InferletError::KvImportFailed`] on the first key that is not exported;
+    /// the keys before it stay prefetched.
+    ///
+    /// [`Forward::try_import_kv_pages`]: crate::forward::Forward::try_import_kv_pages
+    pub fn prefetch_kv_pages(&self, keys: &[String]) -> Result<(), InferletError> {
+        for key in keys {
+            if self.is_prefetched(key) {
+                continue;
+            }
+            if !self.kv_pages_exist(key) {
+                return Err(InferletError::KvImportFailed(key.clone()));
+            }
+            let ptrs = self.import_kv_page_ptrs(key);
+            self.prefetched.borrow_mut().insert(key.clone(), ptrs);
+        }
+        Ok(())
+    }
+
+    /// Whether the KV pages exported under `key` are prefetched and not yet taken by
+    /// an import.
+    pub fn is_prefetched(&self, key: &str) -> bool {
+        self.prefetched.borrow().contains_key(key)
+    }
+
+    /// Drops the pages prefetched under `key` that no import has taken yet.
+    pub fn discard_prefetched(&self, key: &str) {
+        drop(self.take_prefetched(key));
+    }
+
+    /// Removes the pages prefetched under `key`, if any, handing them over as pages.
+    pub(crate) fn take_prefetched(&self, key: &str) -> Option<Vec<KvPage>> {
+        let ptrs = self.prefetched.borrow_mut().remove(key)?;
+        Some(ptrs.into_iter().map(|ptr| KvPage::new(self, ptr)).collect())
+    }
+
     /// Begins a synchronization process for the queue, returning a `SynchronizationResult`.
     pub async fn synchronize(&self) -> bool {
         let future = self.inner.synchronize(); // Changed from messaging::receive
@@ -297,6 +1184,21 @@ impl Queue {
         api::export_resources(&self.inner, resource as u32, ptrs, name)
     }
 
+    /// Exports `ptrs` under `name`, resolving a name collision according to `mode`.
+    ///
+    /// Returns an error only under `ExportMode::FailIfExists` or `ExportMode::Strict` when
+    /// the name is taken.
+    pub fn export_resource_with_mode(
+        &self,
+        resource: Resource,
+        ptrs: &[u32],
+        name: &str,
+        mode: api::ExportMode,
+    ) -> Result<(), InferletError> {
+        api::export_resources_with_mode(&self.inner, resource as u32, ptrs, name, mode)
+            .map_err(InferletError::ExportFailed)
+    }
+
     pub fn import_resource(&self, resource: Resource, name: &str) -> Vec<u32> {
         api::import_resources(&self.inner, resource as u32, name)
     }
@@ -305,6 +1207,14 @@ impl Queue {
         api::get_all_exported_resources(&self.inner, resource as u32)
     }
 
+    /// Returns how many more resources of this type can be allocated before the pool of
+    /// the queue's model is exhausted, see [`Forward::available_kv_pages`].
+    ///
+    /// [`Forward::available_kv_pages`]: crate::forward::Forward::available_kv_pages
+    pub fn get_available_resources(&self, resource: Resource) -> usize {
+        api::get_available_resources(&self.inner, resource as u32) as usize
+    }
+
     pub fn release_exported_resources(&self, resource: Resource, name: &str) {
         api::release_exported_resources(&self.inner, resource as u32, name)
     }
@@ -347,6 +1257,23 @@ impl Tokenizer {
         self.inner.detokenize(tokens)
     }
 
+    /// Converts a sequence of token IDs back into a string according to `options`.
+    ///
+    /// A [`StreamDecoder::with_options`] given the same options produces the same text
+    /// when its chunks are concatenated.
+    pub fn detokenize_with(&self, tokens: &[u32], options: &DecodeOptions) -> String {
+        let text = if options.skip_special {
+            self.inner.detokenize_skip_special(tokens)
+        } else {
+            self.inner.detokenize(tokens)
+        };
+        if options.clean_spaces {
+            stream::clean_up_spaces(&text)
+        } else {
+            text
+        }
+    }
+
     /// Retrieves the entire vocabulary of the tokenizer.
     ///
     /// # Returns
@@ -373,7 +1300,7 @@ where
     T: Run,
 {
     fn run() -> Result<(), String> {
-        let result = wstd::runtime::block_on(async { T::run().await });
+        let result = block_on(async { T::run().await });
         if let Err(e) = result {
             return Err(format!("{:?}", e));
         }
@@ -431,3 +1358,69 @@ macro_rules! main_async {
 //         pie::wasi::http::proxy::export!(_Server with_types_in pie::bindings_server);
 //     };
 // }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn only_the_backend_page_size_is_accepted() {
+        assert!(check_kv_page_size(16, 16).is_ok());
+        assert!(matches!(
+            check_kv_page_size(8, 16),
+            Err(InferletError::InvalidArgument(_))
+        ));
+        assert!(check_kv_page_size(32, 16).is_err());
+    }
+
+    #[test]
+    fn nested_event_loop_keeps_the_outer_one_marked() {
+        assert!(!in_event_loop());
+        let outer = EventLoopGuard::enter();
+        let inner = EventLoopGuard::enter();
+        assert!(in_event_loop());
+        drop(inner);
+        assert!(in_event_loop());
+        drop(outer);
+        assert!(!in_event_loop());
+    }
+
+    #[test]
+    fn page_quota_is_enforced() {
+        let quota = Quota {
+            max_pages: Some(2),
+            max_tokens: None,
+        };
+        assert_eq!(quota.check_pages(0), Ok(()));
+        assert_eq!(quota.check_pages(2), Ok(()));
+        assert_eq!(
+            quota.check_pages(3),
+            Err(InferletError::QuotaExceeded {
+                pages: 3,
+                max_pages: 2
+            })
+        );
+        assert_eq!(Quota::default().check_pages(usize::MAX), Ok(()));
+    }
+
+    #[test]
+    fn pages_for_tokens_rounds_up_to_whole_pages() {
+        assert_eq!(pages_for_tokens(0, 16), 0);
+        assert_eq!(pages_for_tokens(1, 16), 1);
+        assert_eq!(pages_for_tokens(16, 16), 1);
+        assert_eq!(pages_for_tokens(17, 16), 2);
+        assert_eq!(pages_for_tokens(32, 16), 2);
+    }
+
+    #[test]
+    fn seed_from_is_fnv1a() {
+        // Reference values of 64-bit FNV-1a.
+        assert_eq!(seed_from(""), 0xcbf2_9ce4_8422_2325);
+        assert_eq!(seed_from("a"), 0xaf63_dc4c_8601_ec8c);
+        assert_eq!(seed_from("foobar"), 0x8594_4171_f739_67e8);
+
+        // Branches of one job get different seeds that stay the same across runs.
+        assert_eq!(seed_from("job-7/branch-1"), 0xf013_f6c1_5669_f4df);
+        assert_eq!(seed_from("job-7/branch-2"), 0xf013_f7c1_5669_f692);
+    }
+}
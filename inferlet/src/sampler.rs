@@ -1,3 +1,10 @@
+use crate::error::Error;
+use crate::forward::LogitPrecision;
+use std::cell::{Cell, RefCell};
+use std::collections::{HashMap, HashSet, VecDeque};
+use std::fmt;
+use std::rc::Rc;
+
 pub enum Sampler {
     Custom {
         temperature: f32,
@@ -53,6 +60,443 @@ impl Sampler {
     pub fn reasoning() -> Self {
         Self::top_k_top_p(0.6, 20, 0.95)
     }
+
+    /// Returns the temperature the backend applies before sampling.
+    ///
+    /// A temperature of zero (or below) selects the most likely token, whichever
+    /// truncation the sampler applies, e.g. `Sampler::top_p(0.0, 0.9)` decodes greedily.
+    pub fn temperature(&self) -> f32 {
+        match self {
+            Sampler::Custom { temperature, .. }
+            | Sampler::Multinomial { temperature }
+            | Sampler::TopP { temperature, .. }
+            | Sampler::TopK { temperature, .. }
+            | Sampler::MinP { temperature, .. }
+            | Sampler::TopKTopP { temperature, .. } => *temperature,
+        }
+    }
+
+    /// Returns the precision the backend computes this sampler's softmax and truncation
+    /// in, see [`Sampler::with_logit_precision`].
+    pub fn logit_precision(&self) -> LogitPrecision {
+        match self {
+            Sampler::Custom { sampler, .. } => sampler.logit_precision(),
+            _ => LogitPrecision::Native,
+        }
+    }
+
+    /// Passes the token history of the context a generation starts from to a `Custom`
+    /// sampler, see [`Sample::start`].
+    pub(crate) fn start(&self, history: &[u32]) {
+        if let Sampler::Custom { sampler, .. } = self {
+            sampler.start(history);
+        }
+    }
+
+    /// Samples a token from dense `logits`, indexed by token ID, entirely on the client.
+    ///
+    /// Applies the same temperature and truncation rules as the backend, which makes
+    /// it possible to check a sampler against hand-crafted logits without a device. A
+    /// temperature of zero picks the most likely token (the lowest ID among ties);
+    /// otherwise the token is drawn with `rng`, so the same logits and seed always
+    /// select the same token. This is also how seeded generation
+    /// ([`SamplerOverrides::seed`]) selects its tokens. `Custom` samplers receive the
+    /// temperature-scaled distribution over the whole vocabulary and draw their own
+    /// randomness.
+    ///
+    /// # Panics
+    ///
+    /// Panics if `logits` is empty.
+    pub fn sample(&self, logits: &[f32], rng: &mut SamplerRng) -> u32 {
+        let ids: Vec<u32> = (0..logits.len() as u32).collect();
+        self.sample_sparse_with(&ids, logits, || rng.next_unit())
+    }
+
+    /// Like [`Sampler::sample`], for the `logits` of the candidates `ids` only.
+    pub(crate) fn sample_sparse(&self, ids: &[u32], logits: &[f32]) -> u32 {
+        self.sample_sparse_with(ids, logits, next_random_unit)
+    }
+
+    /// Like [`Sampler::sample_sparse`], but draws from `random_unit` instead of the
+    /// host's random source, unless this is a `Custom` sampler.
+    fn sample_sparse_with(
+        &self,
+        ids: &[u32],
+        logits: &[f32],
+        random_unit: impl FnOnce() -> f32,
+    ) -> u32 {
+        assert!(!logits.is_empty(), "Cannot sample from empty logits");
+        let temperature = self.temperature();
+        if temperature <= 0.0 {
+            return Greedy.sample(ids, logits);
+        }
+
+        let scaled: Vec<(u32, f32)> = ids
+            .iter()
+            .zip(logits)
+            .map(|(&id, &logit)| (id, logit / temperature))
+            .collect();
+        let (ids, probs): (Vec<u32>, Vec<f32>) = scaled
+            .iter()
+            .map(|&(id, _)| id)
+            .zip(softmax(&scaled))
+            .unzip();
+        if let Sampler::Custom { sampler, .. } = self {
+            return sampler.sample(&ids, &probs);
+        }
+
+        let entries = self.filter_unsorted(&ids, &probs);
+        draw(&entries, random_unit())
+    }
+
+    /// Runs `processors` over the logits of every decode step, in order, before this
+    /// sampler selects the token from the processed logits.
+    ///
+    /// The result is a `Custom` sampler, so the backend returns its candidates at
+    /// temperature 1.0 and the processors, temperature and truncation all run on the
+    /// client. Only those candidates (the backend's top 32 by default) reach the
+    /// processors; a token outside of them can never be selected, however much a
+    /// processor raises its logit.
+    pub fn with_processors(self, processors: Vec<Box<dyn LogitProcessor>>) -> Sampler {
+        Sampler::Custom {
+            temperature: 1.0,
+            sampler: Box::new(ProcessedSampler {
+                base: self,
+                processors: RefCell::new(processors),
+                step: Cell::new(0),
+            }),
+        }
+    }
+
+    /// Subtracts `penalty` from the logit of every token among the last `window` tokens
+    /// of the context, before the sampler selects the next one.
+    ///
+    /// Limiting the penalty to recent tokens keeps it on the loops it is meant to break,
+    /// without punishing a long text for reusing frequent words. `None` considers the
+    /// whole context. Each token is penalized once, however often it occurs in the
+    /// window. The window is refilled from the context's token history, prompt included,
+    /// whenever a generation starts, and then follows the generated tokens, so one
+    /// sampler can be reused across generations and contexts. Runs as a processor, see
+    /// [`Sampler::with_processors`].
+    pub fn with_repetition_penalty_window(self, penalty: f32, window: Option<usize>) -> Sampler {
+        self.with_processors(vec![Box::new(WindowedRepetitionPenalty {
+            penalty,
+            window,
+            recent: VecDeque::new(),
+        })])
+    }
+
+    /// Samples among the `top_k` most likely tokens with a temperature per rank range,
+    /// e.g. to keep the head of the distribution focused while flattening its tail.
+    ///
+    /// Each tier is `(ranks, temperature)` and covers the next `ranks` ranks, so
+    /// `&[(3, 0.5), (7, 1.5)]` applies 0.5 to the three most likely tokens and 1.5 to
+    /// ranks 4 to 10. The last tier extends to `top_k` if the tiers cover fewer ranks;
+    /// without tiers every rank uses temperature 1.0. A temperature of zero or below
+    /// drops the tier's tokens, or picks the top token if the tier contains it.
+    ///
+    /// Temperatures scale each logit relative to the top one, so a low temperature
+    /// pushes its ranks away from the top token and a high one pulls them closer.
+    /// The result is a `Custom` sampler that runs on the client, so only the backend's
+    /// candidates (its top 32 by default) can be selected, however large `top_k` is.
+    pub fn tiered(top_k: usize, tiers: &[(usize, f32)]) -> Sampler {
+        Sampler::Custom {
+            temperature: 1.0,
+            sampler: Box::new(TieredSampler {
+                top_k,
+                tiers: tiers.to_vec(),
+            }),
+        }
+    }
+
+    /// Discards every candidate whose probability falls below `floor` after this
+    /// sampler's temperature and truncation were applied and the rest renormalized,
+    /// then renormalizes again and draws from what is left.
+    ///
+    /// Unlike min-p, which compares each token to the most likely one before
+    /// truncation, the floor is an absolute bound on the final probabilities, so it
+    /// cuts off the long tail that can occasionally derail a summary. If no candidate
+    /// reaches the floor, the most likely one is kept. A `Custom` sampler receives the
+    /// floored distribution. The result is itself a `Custom` sampler that runs on the
+    /// client, so only the backend's candidates (its top 32 by default) are considered.
+    pub fn with_min_token_prob(self, floor: f32) -> Sampler {
+        Sampler::Custom {
+            temperature: 1.0,
+            sampler: Box::new(FlooredSampler { base: self, floor }),
+        }
+    }
+
+    /// Draws every token of this sampler from a pseudo-random generator seeded with
+    /// `seed`, so the same seed, prompt and model reproduce the same output.
+    ///
+    /// Derive the seed from a task ID with [`seed_from`](crate::seed_from) to give
+    /// each branch of a job its own reproducible sequence. The result is a `Custom`
+    /// sampler that runs on the client, so only the backend's candidates (its top 32
+    /// by default) are considered. A `Custom` sampler wrapped this way draws its own
+    /// randomness and is not affected by the seed.
+    pub fn with_seed(self, seed: u64) -> Sampler {
+        Sampler::Custom {
+            temperature: 1.0,
+            sampler: Box::new(SeededSampler {
+                base: self,
+                rng: RefCell::new(SamplerRng::new(seed)),
+            }),
+        }
+    }
+
+    /// Asks the backend to compute the softmax of every decode step in `precision`.
+    ///
+    /// With [`LogitPrecision::Fp32`], the logits of an fp16 or bf16 model are upcast
+    /// before the softmax, which avoids the rare garbage tokens that rounding in the
+    /// tail of a half-precision distribution can produce. The upcast costs one copy of
+    /// the logits per step, and since the backend samples a batch at once, it applies
+    /// to every request batched with this one. The default is the backend's native
+    /// precision. The result is a `Custom` sampler: the backend returns the candidates
+    /// (its top 32 by default) computed in `precision`, and this sampler's temperature
+    /// and truncation are applied to them on the client.
+    pub fn with_logit_precision(self, precision: LogitPrecision) -> Sampler {
+        Sampler::Custom {
+            temperature: 1.0,
+            sampler: Box::new(PreciseSampler {
+                base: self,
+                precision,
+            }),
+        }
+    }
+
+    /// Applies this sampler's truncation rules to a temperature-scaled distribution
+    /// and renormalizes what is left.
+    ///
+    /// `ids` and `probs` must be sorted by descending probability. `Custom` samplers
+    /// are opaque, so their distribution is returned unchanged.
+    pub(crate) fn filter(&self, ids: &[u32], probs: &[f32]) -> Vec<(u32, f32)> {
+        let mut entries: Vec<(u32, f32)> = ids.iter().copied().zip(probs.iter().copied()).collect();
+
+        let top_p_cutoff = |entries: &[(u32, f32)], top_p: f32| {
+            let mut cumulative = 0.0;
+            entries
+                .iter()
+                .position(|&(_, p)| {
+                    cumulative += p;
+                    cumulative >= top_p
+                })
+                .map_or(entries.len(), |i| i + 1)
+        };
+
+        match self {
+            Sampler::Custom { .. } | Sampler::Multinomial { .. } => {}
+            Sampler::TopP { top_p, .. } => {
+                entries.truncate(top_p_cutoff(&entries, *top_p));
+            }
+            Sampler::TopK { top_k, .. } => {
+                entries.truncate(*top_k as usize);
+            }
+            Sampler::MinP { min_p, .. } => {
+                let threshold = entries.first().map_or(0.0, |&(_, p)| p * min_p);
+                entries.retain(|&(_, p)| p >= threshold);
+            }
+            Sampler::TopKTopP { top_k, top_p, .. } => {
+                entries.truncate(*top_k as usize);
+                let total: f32 = entries.iter().map(|&(_, p)| p).sum();
+                let cutoff = top_p_cutoff(&entries, top_p * total);
+                entries.truncate(cutoff);
+            }
+        }
+
+        let total: f32 = entries.iter().map(|&(_, p)| p).sum();
+        if total > 0.0 {
+            entries.iter_mut().for_each(|(_, p)| *p /= total);
+        }
+        entries
+    }
+
+    /// Like [`Sampler::filter`], for a distribution in arbitrary order.
+    fn filter_unsorted(&self, ids: &[u32], probs: &[f32]) -> Vec<(u32, f32)> {
+        let mut entries: Vec<(u32, f32)> = ids.iter().copied().zip(probs.iter().copied()).collect();
+        entries.sort_by(|a, b| b.1.total_cmp(&a.1).then(a.0.cmp(&b.0)));
+        let (ids, probs): (Vec<u32>, Vec<f32>) = entries.into_iter().unzip();
+        self.filter(&ids, &probs)
+    }
+}
+
+/// Per-call adjustments of a [`Sampler`], see
+/// [`Context::generate_with_overrides`](crate::Context::generate_with_overrides).
+///
+/// Unset fields inherit from the base sampler, which is left untouched.
+#[derive(Debug, Clone, Copy, Default, PartialEq)]
+pub struct SamplerOverrides {
+    pub temperature: Option<f32>,
+    /// Replaces the base sampler's nucleus threshold. A min-p sampler becomes a
+    /// top-p one, since the backend cannot apply both.
+    pub top_p: Option<f32>,
+    /// Draws the tokens from a generator seeded with this value instead of the
+    /// backend's, so repeating the call on the same context reproduces its output.
+    /// `Custom` samplers draw their own randomness and ignore it.
+    pub seed: Option<u64>,
+}
+
+/// A [`Sampler`] with [`SamplerOverrides`] applied for the duration of one call.
+pub(crate) struct OverriddenSampler<'a> {
+    base: &'a Sampler,
+    /// The base sampler with the overrides folded in, unless it is `Custom`.
+    native: Option<Sampler>,
+    temperature: f32,
+    top_p: Option<f32>,
+    rng: Option<RefCell<SamplerRng>>,
+}
+
+impl<'a> OverriddenSampler<'a> {
+    pub(crate) fn new(base: &'a Sampler, overrides: &SamplerOverrides) -> Self {
+        let temperature = overrides.temperature.unwrap_or(base.temperature());
+        let top_p = overrides.top_p;
+        let native = match base {
+            Sampler::Custom { .. } => None,
+            Sampler::Multinomial { .. } => Some(match top_p {
+                Some(top_p) => Sampler::top_p(temperature, top_p),
+                None => Sampler::Multinomial { temperature },
+            }),
+            Sampler::TopP { top_p: base_p, .. } => {
+                Some(Sampler::top_p(temperature, top_p.unwrap_or(*base_p)))
+            }
+            Sampler::TopK { top_k, .. } => Some(match top_p {
+                Some(top_p) => Sampler::top_k_top_p(temperature, *top_k, top_p),
+                None => Sampler::top_k(temperature, *top_k),
+            }),
+            Sampler::MinP { min_p, .. } => Some(match top_p {
+                Some(top_p) => Sampler::top_p(temperature, top_p),
+                None => Sampler::min_p(temperature, *min_p),
+            }),
+            Sampler::TopKTopP {
+                top_k,
+                top_p: base_p,
+                ..
+            } => Some(Sampler::top_k_top_p(
+                temperature,
+                *top_k,
+                top_p.unwrap_or(*base_p),
+            )),
+        };
+        // A zero temperature asks for greedy decoding. Rather than have the backend divide
+        // the logits by it, sample from the single most likely token at temperature 1.0.
+        // Custom samplers get their distribution at 1.0 and pick its argmax locally.
+        let native = match native {
+            Some(_) if temperature <= 0.0 => Some(Sampler::top_k(1.0, 1)),
+            native => native,
+        };
+        let rng = native
+            .as_ref()
+            .and(overrides.seed)
+            .map(|seed| RefCell::new(SamplerRng::new(seed)));
+
+        OverriddenSampler {
+            base,
+            native,
+            temperature,
+            top_p,
+            rng,
+        }
+    }
+
+    /// Returns the sampler the backend should apply, or `None` if the token has to be
+    /// drawn locally with [`OverriddenSampler::sample`].
+    pub(crate) fn native(&self) -> Option<&Sampler> {
+        match self.rng {
+            Some(_) => None,
+            None => self.native.as_ref(),
+        }
+    }
+
+    /// The precision the backend should compute the pass's outputs in.
+    pub(crate) fn logit_precision(&self) -> LogitPrecision {
+        self.base.logit_precision()
+    }
+
+    /// Passes the token history a generation starts from to the base sampler.
+    pub(crate) fn start(&self, history: &[u32]) {
+        self.base.start(history);
+    }
+
+    /// The temperature of the distribution [`OverriddenSampler::sample`] expects.
+    ///
+    /// A seeded sampler applies its temperature itself, like [`Sampler::sample`], and a
+    /// zero temperature is never sent to the backend.
+    pub(crate) fn distribution_temperature(&self) -> f32 {
+        if self.rng.is_some() || self.temperature <= 0.0 {
+            1.0
+        } else {
+            self.temperature
+        }
+    }
+
+    /// Draws a token locally from a distribution at
+    /// [`OverriddenSampler::distribution_temperature`].
+    pub(crate) fn sample(&self, ids: &[u32], probs: &[f32]) -> u32 {
+        let (Some(native), Some(rng)) = (&self.native, &self.rng) else {
+            let Sampler::Custom { sampler, .. } = self.base else {
+                unreachable!("Only custom or seeded samplers sample locally");
+            };
+            if self.temperature <= 0.0 {
+                return Greedy.sample(ids, probs);
+            }
+            let Some(top_p) = self.top_p else {
+                return sampler.sample(ids, probs);
+            };
+            let entries = Sampler::top_p(self.temperature, top_p).filter_unsorted(ids, probs);
+            let (ids, probs): (Vec<u32>, Vec<f32>) = entries.into_iter().unzip();
+            return sampler.sample(&ids, &probs);
+        };
+
+        let logits: Vec<f32> = probs
+            .iter()
+            .map(|p| p.max(f32::MIN_POSITIVE).ln())
+            .collect();
+        native.sample_sparse_with(ids, &logits, || rng.borrow_mut().next_unit())
+    }
+}
+
+/// Picks the entry of a normalized distribution that the uniform value `r` in
+/// `[0, 1)` falls into, or the last one if rounding leaves `r` past the total.
+fn draw(entries: &[(u32, f32)], r: f32) -> u32 {
+    let mut cumulative = 0.0;
+    for &(id, p) in entries {
+        cumulative += p;
+        if r < cumulative {
+            return id;
+        }
+    }
+    entries
+        .last()
+        .map(|&(id, _)| id)
+        .expect("Cannot sample from an empty distribution")
+}
+
+/// Returns a uniform value in `[0, 1)` from the host's randomness.
+fn next_random_unit() -> f32 {
+    (wasi::random::random::get_random_u64() >> 40) as f32 / (1u64 << 24) as f32
+}
+
+/// The pseudo-random generator of [`Sampler::sample`] and seeded sampling.
+///
+/// It is SplitMix64, so a seed yields the same sequence on every run, build and host.
+#[derive(Debug, Clone)]
+pub struct SamplerRng {
+    state: u64,
+}
+
+impl SamplerRng {
+    pub fn new(seed: u64) -> Self {
+        SamplerRng { state: seed }
+    }
+
+    /// Advances the generator and returns a uniform value in `[0, 1)`.
+    pub fn next_unit(&mut self) -> f32 {
+        self.state = self.state.wrapping_add(0x9E37_79B9_7F4A_7C15);
+        let mut z = self.state;
+        z = (z ^ (z >> 30)).wrapping_mul(0xBF58_476D_1CE4_E5B9);
+        z = (z ^ (z >> 27)).wrapping_mul(0x94D0_49BB_1331_11EB);
+        z ^= z >> 31;
+        (z >> 40) as f32 / (1u64 << 24) as f32
+    }
 }
 
 pub trait Sample {
@@ -62,4 +506,746 @@ pub trait Sample {
     /// * `ids` - A slice of token IDs.
     /// * `probs` - A slice of corresponding probabilities for each token ID.
     fn sample(&self, ids: &[u32], probs: &[f32]) -> u32;
+
+    /// Called when a generation starts with the context's token history, committed
+    /// tokens followed by pending ones, e.g. to reset per-generation state.
+    fn start(&self, _history: &[u32]) {}
+
+    /// The precision the backend should compute the distribution passed to `sample` in.
+    fn logit_precision(&self) -> LogitPrecision {
+        LogitPrecision::Native
+    }
+}
+
+/// Adjusts the logits of a decode step before the token is selected, see
+/// [`Sampler::with_processors`].
+///
+/// Implementations can add penalties, ban tokens or enforce a grammar outside of the
+/// library, keeping whatever state they need between steps.
+pub trait LogitProcessor {
+    /// Adjusts the `logits` of the candidate `tokens` (in the same order) at the zero-based
+    /// generation `step`.
+    fn process(&mut self, step: usize, tokens: &[u32], logits: &mut [f32]);
+
+    /// Called when a generation starts with the context's token history, committed
+    /// tokens followed by pending ones, before the first [`LogitProcessor::process`].
+    fn start(&mut self, _history: &[u32]) {}
+
+    /// Called with the token selected after [`LogitProcessor::process`], e.g. to track
+    /// the generated tokens for a repetition penalty.
+    fn accept(&mut self, _token_id: u32) {}
+}
+
+/// A sampler that runs logit processors before a base sampler selects the token.
+struct ProcessedSampler {
+    base: Sampler,
+    processors: RefCell<Vec<Box<dyn LogitProcessor>>>,
+    step: Cell<usize>,
+}
+
+impl Sample for ProcessedSampler {
+    fn sample(&self, ids: &[u32], probs: &[f32]) -> u32 {
+        let step = self.step.get();
+        let mut processors = self.processors.borrow_mut();
+        let mut logits: Vec<f32> = probs
+            .iter()
+            .map(|p| p.max(f32::MIN_POSITIVE).ln())
+            .collect();
+        for processor in processors.iter_mut() {
+            processor.process(step, ids, &mut logits);
+        }
+
+        let token_id = self.base.sample_sparse(ids, &logits);
+        for processor in processors.iter_mut() {
+            processor.accept(token_id);
+        }
+        self.step.set(step + 1);
+        token_id
+    }
+
+    fn start(&self, history: &[u32]) {
+        self.base.start(history);
+        self.step.set(0);
+        for processor in self.processors.borrow_mut().iter_mut() {
+            processor.start(history);
+        }
+    }
+
+    fn logit_precision(&self) -> LogitPrecision {
+        self.base.logit_precision()
+    }
+}
+
+/// The processor behind [`Sampler::with_repetition_penalty_window`].
+struct WindowedRepetitionPenalty {
+    penalty: f32,
+    window: Option<usize>,
+    /// The context's latest tokens, oldest first, at most `window` of them.
+    recent: VecDeque<u32>,
+}
+
+impl LogitProcessor for WindowedRepetitionPenalty {
+    fn start(&mut self, history: &[u32]) {
+        let skip = self
+            .window
+            .map_or(0, |window| history.len().saturating_sub(window));
+        self.recent.clear();
+        for &token_id in &history[skip..] {
+            self.accept(token_id);
+        }
+    }
+
+    fn process(&mut self, _step: usize, tokens: &[u32], logits: &mut [f32]) {
+        for (token_id, logit) in tokens.iter().zip(logits) {
+            if self.recent.contains(token_id) {
+                *logit -= self.penalty;
+            }
+        }
+    }
+
+    fn accept(&mut self, token_id: u32) {
+        if self.window == Some(0) {
+            return;
+        }
+        if self
+            .window
+            .is_some_and(|window| self.recent.len() >= window)
+        {
+            self.recent.pop_front();
+        }
+        self.recent.push_back(token_id);
+    }
+}
+
+/// The sampler behind [`Sampler::with_min_token_prob`].
+struct FlooredSampler {
+    base: Sampler,
+    floor: f32,
+}
+
+impl Sample for FlooredSampler {
+    fn sample(&self, ids: &[u32], probs: &[f32]) -> u32 {
+        if self.base.temperature() <= 0.0 {
+            return Greedy.sample(ids, probs);
+        }
+
+        let entries = self.distribution(ids, probs);
+        if let Sampler::Custom { sampler, .. } = &self.base {
+            let (ids, probs): (Vec<u32>, Vec<f32>) = entries.into_iter().unzip();
+            return sampler.sample(&ids, &probs);
+        }
+        draw(&entries, next_random_unit())
+    }
+
+    fn start(&self, history: &[u32]) {
+        self.base.start(history);
+    }
+
+    fn logit_precision(&self) -> LogitPrecision {
+        self.base.logit_precision()
+    }
+}
+
+impl FlooredSampler {
+    /// Returns the floored and renormalized distribution, by descending probability,
+    /// for a base sampler with a positive temperature.
+    fn distribution(&self, ids: &[u32], probs: &[f32]) -> Vec<(u32, f32)> {
+        let temperature = self.base.temperature();
+        let scaled: Vec<(u32, f32)> = ids
+            .iter()
+            .zip(probs)
+            .map(|(&id, &p)| (id, p.max(f32::MIN_POSITIVE).ln() / temperature))
+            .collect();
+        let (ids, probs): (Vec<u32>, Vec<f32>) = scaled
+            .iter()
+            .map(|&(id, _)| id)
+            .zip(softmax(&scaled))
+            .unzip();
+
+        // Sorted by descending probability, so the first entry is the one to keep when
+        // nothing reaches the floor.
+        let mut entries = self.base.filter_unsorted(&ids, &probs);
+        if entries.iter().any(|&(_, p)| p >= self.floor) {
+            entries.retain(|&(_, p)| p >= self.floor);
+        } else {
+            entries.truncate(1);
+        }
+        let total: f32 = entries.iter().map(|&(_, p)| p).sum();
+        if total > 0.0 {
+            entries.iter_mut().for_each(|(_, p)| *p /= total);
+        }
+        entries
+    }
+}
+
+/// The sampler behind [`Sampler::with_logit_precision`].
+struct PreciseSampler {
+    base: Sampler,
+    precision: LogitPrecision,
+}
+
+impl Sample for PreciseSampler {
+    fn sample(&self, ids: &[u32], probs: &[f32]) -> u32 {
+        let logits: Vec<f32> = probs
+            .iter()
+            .map(|p| p.max(f32::MIN_POSITIVE).ln())
+            .collect();
+        self.base.sample_sparse(ids, &logits)
+    }
+
+    fn start(&self, history: &[u32]) {
+        self.base.start(history);
+    }
+
+    fn logit_precision(&self) -> LogitPrecision {
+        self.precision
+    }
+}
+
+/// The sampler behind [`Sampler::with_seed`].
+struct SeededSampler {
+    base: Sampler,
+    rng: RefCell<SamplerRng>,
+}
+
+impl Sample for SeededSampler {
+    fn sample(&self, ids: &[u32], probs: &[f32]) -> u32 {
+        let logits: Vec<f32> = probs
+            .iter()
+            .map(|p| p.max(f32::MIN_POSITIVE).ln())
+            .collect();
+        self.base
+            .sample_sparse_with(ids, &logits, || self.rng.borrow_mut().next_unit())
+    }
+
+    fn start(&self, history: &[u32]) {
+        self.base.start(history);
+    }
+
+    fn logit_precision(&self) -> LogitPrecision {
+        self.base.logit_precision()
+    }
+}
+
+/// The sampler behind [`Sampler::tiered`].
+struct TieredSampler {
+    top_k: usize,
+    tiers: Vec<(usize, f32)>,
+}
+
+impl TieredSampler {
+    /// Returns the temperature of the tier `rank` (counted from 0) falls into.
+    fn temperature(&self, rank: usize) -> f32 {
+        let mut end = 0;
+        for &(ranks, temperature) in &self.tiers {
+            end += ranks;
+            if rank < end {
+                return temperature;
+            }
+        }
+        self.tiers
+            .last()
+            .map_or(1.0, |&(_, temperature)| temperature)
+    }
+
+    /// Returns the distribution the sampler draws from, by descending probability.
+    fn distribution(&self, ids: &[u32], probs: &[f32]) -> Vec<(u32, f32)> {
+        let mut entries: Vec<(u32, f32)> = ids.iter().copied().zip(probs.iter().copied()).collect();
+        entries.sort_by(|a, b| b.1.total_cmp(&a.1).then(a.0.cmp(&b.0)));
+        entries.truncate(self.top_k.max(1));
+
+        let top = entries
+            .first()
+            .map_or(0.0, |&(_, p)| p.max(f32::MIN_POSITIVE).ln());
+        let scaled: Vec<(u32, f32)> = entries
+            .iter()
+            .enumerate()
+            .map(|(rank, &(id, p))| {
+                let relative = p.max(f32::MIN_POSITIVE).ln() - top;
+                let temperature = self.temperature(rank);
+                let logit = if temperature > 0.0 {
+                    relative / temperature
+                } else if rank == 0 {
+                    0.0
+                } else {
+                    f32::NEG_INFINITY
+                };
+                (id, logit)
+            })
+            .collect();
+        scaled
+            .iter()
+            .map(|&(id, _)| id)
+            .zip(softmax(&scaled))
+            .collect()
+    }
+}
+
+impl Sample for TieredSampler {
+    fn sample(&self, ids: &[u32], probs: &[f32]) -> u32 {
+        draw(&self.distribution(ids, probs), next_random_unit())
+    }
+}
+
+/// A deterministic sampler that always selects the most probable token.
+///
+/// When several tokens share the highest probability, the lowest token ID wins,
+/// so identical distributions always resolve to the same token.
+#[derive(Debug, Clone, Copy, Default)]
+pub struct Greedy;
+
+impl Sample for Greedy {
+    fn sample(&self, ids: &[u32], probs: &[f32]) -> u32 {
+        crate::forward::argmax(ids, probs).expect("Cannot sample from an empty distribution")
+    }
+}
+
+/// A stage of a [`SamplerBuilder`] pipeline.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub enum Transform {
+    /// Divides the logits by the temperature.
+    Temperature,
+    /// Subtracts the repetition penalty from the logits of the penalized tokens.
+    Penalty,
+    /// Adds the per-token logit biases.
+    Bias,
+    /// Drops candidates outside of the top-k / top-p / min-p limits.
+    Truncation,
+}
+
+/// The order in which a [`SamplerBuilder`] applies its stages unless told otherwise.
+pub const DEFAULT_TRANSFORM_ORDER: [Transform; 4] = [
+    Transform::Penalty,
+    Transform::Bias,
+    Transform::Temperature,
+    Transform::Truncation,
+];
+
+/// A logit adjustment that depends on the generation step, see
+/// [`SamplerBuilder::with_positional_fn`].
+///
+/// It receives the step index, the candidate token IDs and their logits, in the same
+/// order.
+pub type PositionalFn = dyn Fn(usize, &[u32], &mut [f32]);
+
+#[derive(Clone)]
+struct Positional(Rc<PositionalFn>);
+
+impl fmt::Debug for Positional {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str("Positional(..)")
+    }
+}
+
+/// Builds a client-side sampler that combines penalties, biases, temperature and
+/// truncation in a configurable order.
+///
+/// The stages operate on the logits of the candidates the backend returns for the
+/// next position (its top 32 by default), so tokens outside of them can never be
+/// sampled. Because penalties and biases are additive, applying the temperature before
+/// or after them changes how strongly they act.
+#[derive(Debug, Clone)]
+pub struct SamplerBuilder {
+    temperature: f32,
+    penalty: f32,
+    penalized: HashSet<u32>,
+    bias: HashMap<u32, f32>,
+    top_k: Option<usize>,
+    top_p: Option<f32>,
+    min_p: Option<f32>,
+    order: Vec<Transform>,
+    positional: Option<Positional>,
+    /// Number of tokens sampled so far, passed to the positional function.
+    step: Cell<usize>,
+}
+
+impl Default for SamplerBuilder {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl SamplerBuilder {
+    pub fn new() -> Self {
+        SamplerBuilder {
+            temperature: 1.0,
+            penalty: 0.0,
+            penalized: HashSet::new(),
+            bias: HashMap::new(),
+            top_k: None,
+            top_p: None,
+            min_p: None,
+            order: DEFAULT_TRANSFORM_ORDER.to_vec(),
+            positional: None,
+            step: Cell::new(0),
+        }
+    }
+
+    /// Sets the temperature. A temperature of zero always picks the best candidate.
+    pub fn temperature(mut self, temperature: f32) -> Self {
+        self.temperature = temperature;
+        self
+    }
+
+    /// Subtracts `penalty` from the logits of `token_ids`, e.g. the tokens already
+    /// generated.
+    pub fn repetition_penalty<I: IntoIterator<Item = u32>>(
+        mut self,
+        token_ids: I,
+        penalty: f32,
+    ) -> Self {
+        self.penalized.extend(token_ids);
+        self.penalty = penalty;
+        self
+    }
+
+    /// Adds `bias` to the logit of `token_id`.
+    pub fn logit_bias(mut self, token_id: u32, bias: f32) -> Self {
+        self.bias.insert(token_id, bias);
+        self
+    }
+
+    pub fn top_k(mut self, top_k: usize) -> Self {
+        self.top_k = Some(top_k);
+        self
+    }
+
+    pub fn top_p(mut self, top_p: f32) -> Self {
+        self.top_p = Some(top_p);
+        self
+    }
+
+    pub fn min_p(mut self, min_p: f32) -> Self {
+        self.min_p = Some(min_p);
+        self
+    }
+
+    /// Adjusts the logits with `f` depending on the generation step, e.g. to penalize
+    /// newlines near the start of the output but not later on.
+    ///
+    /// `f` runs on every decode step, right before [`Transform::Truncation`] and after
+    /// whichever stages precede it, and receives the step index along with the
+    /// candidate token IDs and their logits. The step counts the tokens this sampler
+    /// has drawn, starting at zero, so build a fresh sampler for every generation.
+    pub fn with_positional_fn<F>(mut self, f: F) -> Self
+    where
+        F: Fn(usize, &[u32], &mut [f32]) + 'static,
+    {
+        self.positional = Some(Positional(Rc::new(f)));
+        self
+    }
+
+    /// Sets the order of the stages, replacing [`DEFAULT_TRANSFORM_ORDER`].
+    pub fn order(mut self, order: &[Transform]) -> Self {
+        self.order = order.to_vec();
+        self
+    }
+
+    /// Builds the sampler.
+    ///
+    /// Fails unless the configured order contains every [`Transform`] exactly once.
+    pub fn build(self) -> Result<Sampler, Error> {
+        for transform in DEFAULT_TRANSFORM_ORDER {
+            let count = self.order.iter().filter(|&&t| t == transform).count();
+            if count != 1 {
+                return Err(Error::InvalidArgument(format!(
+                    "Sampler order must contain {:?} exactly once, found {} times",
+                    transform, count
+                )));
+            }
+        }
+
+        Ok(Sampler::Custom {
+            temperature: 1.0,
+            sampler: Box::new(self),
+        })
+    }
+
+    fn truncate(&self, candidates: &mut Vec<(u32, f32)>) {
+        candidates.sort_by(|a, b| b.1.total_cmp(&a.1).then(a.0.cmp(&b.0)));
+        if let Some(top_k) = self.top_k {
+            candidates.truncate(top_k.max(1));
+        }
+
+        let probs = softmax(candidates);
+        let mut keep = candidates.len();
+        if let Some(top_p) = self.top_p {
+            let mut cumulative = 0.0;
+            if let Some(i) = probs.iter().position(|&p| {
+                cumulative += p;
+                cumulative >= top_p
+            }) {
+                keep = keep.min(i + 1);
+            }
+        }
+        if let Some(min_p) = self.min_p {
+            let threshold = probs.first().copied().unwrap_or(0.0) * min_p;
+            keep = keep.min(probs.iter().take_while(|&&p| p >= threshold).count().max(1));
+        }
+        candidates.truncate(keep);
+    }
+}
+
+impl Sample for SamplerBuilder {
+    fn sample(&self, ids: &[u32], probs: &[f32]) -> u32 {
+        let mut candidates: Vec<(u32, f32)> = ids
+            .iter()
+            .zip(probs)
+            .map(|(&id, &p)| (id, p.max(f32::MIN_POSITIVE).ln()))
+            .collect();
+
+        for transform in &self.order {
+            match transform {
+                Transform::Temperature if self.temperature > 0.0 => {
+                    candidates
+                        .iter_mut()
+                        .for_each(|(_, logit)| *logit /= self.temperature);
+                }
+                Transform::Temperature => {}
+                Transform::Penalty => candidates
+                    .iter_mut()
+                    .filter(|(id, _)| self.penalized.contains(id))
+                    .for_each(|(_, logit)| *logit -= self.penalty),
+                Transform::Bias => candidates.iter_mut().for_each(|(id, logit)| {
+                    *logit += self.bias.get(id).copied().unwrap_or(0.0);
+                }),
+                Transform::Truncation => {
+                    if let Some(Positional(f)) = &self.positional {
+                        let (ids, mut logits): (Vec<u32>, Vec<f32>) =
+                            candidates.iter().copied().unzip();
+                        f(self.step.get(), &ids, &mut logits);
+                        candidates = ids.into_iter().zip(logits).collect();
+                    }
+                    self.truncate(&mut candidates)
+                }
+            }
+        }
+        self.step.set(self.step.get() + 1);
+
+        if self.temperature <= 0.0 {
+            let (ids, logits): (Vec<u32>, Vec<f32>) = candidates.into_iter().unzip();
+            return Greedy.sample(&ids, &logits);
+        }
+
+        let entries: Vec<(u32, f32)> = candidates
+            .iter()
+            .map(|&(id, _)| id)
+            .zip(softmax(&candidates))
+            .collect();
+        draw(&entries, next_random_unit())
+    }
+}
+
+fn softmax(candidates: &[(u32, f32)]) -> Vec<f32> {
+    let max = candidates
+        .iter()
+        .map(|&(_, logit)| logit)
+        .fold(f32::NEG_INFINITY, f32::max);
+    let exps: Vec<f32> = candidates
+        .iter()
+        .map(|&(_, logit)| (logit - max).exp())
+        .collect();
+    let total: f32 = exps.iter().sum();
+    exps.into_iter().map(|e| e / total).collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// At temperature 1.0 these give token 1 a probability of about 0.64, token 2 0.24,
+    /// token 3 0.09 and token 0 0.03.
+    const LOGITS: [f32; 4] = [0.0, 3.0, 2.0, 1.0];
+
+    /// Returns every token `sampler` selects from `LOGITS` over a range of seeds.
+    fn selected(sampler: &Sampler) -> HashSet<u32> {
+        (0..500)
+            .map(|seed| sampler.sample(&LOGITS, &mut SamplerRng::new(seed)))
+            .collect()
+    }
+
+    #[test]
+    fn tiered_temperatures_set_the_entropy_of_their_ranks() {
+        let ids: Vec<u32> = (0..10).collect();
+        let logits: Vec<(u32, f32)> = ids.iter().map(|&id| (id, -0.5 * id as f32)).collect();
+        let probs = softmax(&logits);
+        // The entropy of the tiered distribution over `ranks`, renormalized.
+        let entropy = |tiers: &[(usize, f32)], ranks: std::ops::Range<usize>| {
+            let sampler = TieredSampler {
+                top_k: 10,
+                tiers: tiers.to_vec(),
+            };
+            let dist = sampler.distribution(&ids, &probs);
+            let total: f32 = dist[ranks.clone()].iter().map(|&(_, p)| p).sum();
+            -dist[ranks]
+                .iter()
+                .map(|&(_, p)| p / total * (p / total).ln())
+                .sum::<f32>()
+        };
+
+        assert!(entropy(&[(10, 0.5)], 0..10) < entropy(&[], 0..10));
+        assert!(entropy(&[], 0..10) < entropy(&[(10, 2.0)], 0..10));
+        // A cold head gets more focused and a hot tail flatter than at temperature 1.0.
+        let mixed = [(3, 0.5), (7, 2.0)];
+        assert!(entropy(&mixed, 0..3) < entropy(&[], 0..3));
+        assert!(entropy(&mixed, 3..10) > entropy(&[], 3..10));
+    }
+
+    #[test]
+    fn token_below_the_floor_is_never_selected() {
+        let (ids, probs) = distribution_at(1.0);
+        let draws = |floor| {
+            let sampler = FlooredSampler {
+                base: Sampler::top_k(1.0, 4),
+                floor,
+            };
+            let entries = sampler.distribution(&ids, &probs);
+            (0..2000)
+                .map(|seed| draw(&entries, SamplerRng::new(seed).next_unit()))
+                .collect::<HashSet<u32>>()
+        };
+        // Token 0 has a probability of about 0.03, so it shows up without a floor...
+        assert!(draws(0.0).contains(&0));
+        // ...but never once the floor is above it.
+        assert_eq!(draws(0.05), HashSet::from([1, 2, 3]));
+        // If nothing reaches the floor, only the most likely token is left.
+        assert_eq!(draws(0.9), HashSet::from([1]));
+    }
+
+    #[test]
+    fn greedy_breaks_ties_like_distribution_argmax() {
+        let ids = [7, 3, 5, 9];
+        let probs = [0.3, 0.3, 0.1, 0.3];
+        assert_eq!(Greedy.sample(&ids, &probs), 3);
+        assert_eq!(Greedy.sample(&[4, 2], &[0.5, 0.5]), 2);
+    }
+
+    #[test]
+    fn zero_temperature_selects_the_argmax() {
+        assert_eq!(selected(&Sampler::greedy()), HashSet::from([1]));
+    }
+
+    #[test]
+    fn top_k_keeps_the_k_most_likely_tokens() {
+        assert_eq!(selected(&Sampler::top_k(1.0, 1)), HashSet::from([1]));
+        assert_eq!(selected(&Sampler::top_k(1.0, 2)), HashSet::from([1, 2]));
+    }
+
+    #[test]
+    fn top_p_keeps_the_smallest_nucleus_reaching_p() {
+        assert_eq!(selected(&Sampler::top_p(1.0, 0.6)), HashSet::from([1]));
+        assert_eq!(selected(&Sampler::top_p(1.0, 0.85)), HashSet::from([1, 2]));
+        assert_eq!(
+            selected(&Sampler::top_p(1.0, 0.95)),
+            HashSet::from([1, 2, 3])
+        );
+    }
+
+    #[test]
+    fn min_p_keeps_tokens_relative_to_the_most_likely_one() {
+        assert_eq!(selected(&Sampler::min_p(1.0, 0.5)), HashSet::from([1]));
+        assert_eq!(selected(&Sampler::min_p(1.0, 0.3)), HashSet::from([1, 2]));
+        assert_eq!(
+            selected(&Sampler::min_p(1.0, 0.1)),
+            HashSet::from([1, 2, 3])
+        );
+    }
+
+    /// Returns the distribution over `LOGITS` the backend would return at `temperature`.
+    fn distribution_at(temperature: f32) -> (Vec<u32>, Vec<f32>) {
+        let scaled: Vec<(u32, f32)> = (0..)
+            .zip(LOGITS)
+            .map(|(id, logit)| (id, logit / temperature))
+            .collect();
+        let probs = softmax(&scaled);
+        assert!(probs.iter().all(|p| p.is_finite()));
+        (scaled.into_iter().map(|(id, _)| id).collect(), probs)
+    }
+
+    #[test]
+    fn zero_temperature_top_p_selects_the_argmax() {
+        let base = Sampler::top_p(0.0, 0.9);
+        assert_eq!(selected(&base), HashSet::from([1]));
+
+        let sampler = OverriddenSampler::new(&base, &SamplerOverrides::default());
+        assert!(matches!(
+            sampler.native(),
+            Some(Sampler::TopK {
+                temperature: 1.0,
+                top_k: 1
+            })
+        ));
+
+        let overrides = SamplerOverrides {
+            seed: Some(3),
+            ..Default::default()
+        };
+        let seeded = OverriddenSampler::new(&base, &overrides);
+        let (ids, probs) = distribution_at(seeded.distribution_temperature());
+        assert_eq!(seeded.sample(&ids, &probs), 1);
+    }
+
+    #[test]
+    fn zero_temperature_custom_sampler_selects_the_argmax() {
+        let base = SamplerBuilder::new().build().unwrap();
+        let overrides = SamplerOverrides {
+            temperature: Some(0.0),
+            ..Default::default()
+        };
+        let sampler = OverriddenSampler::new(&base, &overrides);
+        assert!(sampler.native().is_none());
+        let (ids, probs) = distribution_at(sampler.distribution_temperature());
+        assert_eq!(sampler.sample(&ids, &probs), 1);
+    }
+
+    #[test]
+    fn the_same_seed_selects_the_same_tokens() {
+        let sampler = Sampler::Multinomial { temperature: 1.0 };
+        let run = |seed| {
+            let mut rng = SamplerRng::new(seed);
+            (0..32)
+                .map(|_| sampler.sample(&LOGITS, &mut rng))
+                .collect::<Vec<_>>()
+        };
+        assert_eq!(run(7), run(7));
+        assert_ne!(run(7), run(8));
+    }
+
+    #[test]
+    fn repetition_window_covers_only_the_latest_context_tokens() {
+        let mut penalty = WindowedRepetitionPenalty {
+            penalty: 1.0,
+            window: Some(2),
+            recent: VecDeque::new(),
+        };
+        let penalized = |penalty: &mut WindowedRepetitionPenalty| {
+            let mut logits = [0.0; 4];
+            penalty.process(0, &[1, 2, 3, 4], &mut logits);
+            logits
+        };
+
+        // The prompt counts, but token 1 is outside of the window.
+        penalty.start(&[1, 2, 3]);
+        assert_eq!(penalized(&mut penalty), [0.0, -1.0, -1.0, 0.0]);
+        penalty.accept(4);
+        assert_eq!(penalized(&mut penalty), [0.0, 0.0, -1.0, -1.0]);
+        // Nothing carries over into the next generation.
+        penalty.start(&[]);
+        assert_eq!(penalized(&mut penalty), [0.0; 4]);
+    }
+
+    #[test]
+    fn repetition_window_is_filled_from_the_context() {
+        let sampler = Sampler::greedy().with_repetition_penalty_window(10.0, Some(2));
+        let Sampler::Custom {
+            sampler: custom, ..
+        } = &sampler
+        else {
+            unreachable!();
+        };
+        sampler.start(&[1, 2, 3]);
+        assert_eq!(custom.sample(&[1, 2, 3], &[0.5, 0.3, 0.2]), 1);
+        sampler.start(&[2, 3, 1]);
+        assert_eq!(custom.sample(&[1, 2, 3], &[0.5, 0.3, 0.2]), 2);
+    }
 }
@@ -1,8 +1,37 @@
+use crate::forward::Distribution;
+use crate::{Model, StreamDecoder, Tokenizer};
+use std::cell::{Cell, RefCell};
+
 /// A trait for defining stopping conditions during token generation.
 pub trait StopCondition {
     /// Checks if the generation should stop based on the sequence of token IDs.
     fn check(&self, token_ids: &[u32]) -> bool;
 
+    /// Whether this condition inspects the next-token distributions via `observe`.
+    ///
+    /// Requesting the distribution adds an output to every forward pass, so the
+    /// generation loop only does so when this returns `true`.
+    fn needs_distribution(&self) -> bool {
+        false
+    }
+
+    /// Records the (temperature 1.0) distribution the latest token was sampled from.
+    ///
+    /// Called once per step, before `check`, when `needs_distribution` is `true`.
+    fn observe(&self, _dist: &Distribution) {}
+
+    /// How many of the most likely tokens the distribution passed to `observe` holds,
+    /// or `None` for the backend's default of 32.
+    fn distribution_top_k(&self) -> Option<u32> {
+        None
+    }
+
+    /// Returns the stop sequence `token_ids` ends with, if this condition stops on
+    /// token sequences and one of them matched.
+    fn matched_sequence(&self, _token_ids: &[u32]) -> Option<&[u32]> {
+        None
+    }
+
     /// Combines this condition with another using a logical OR.
     ///
     /// This allows for creating complex conditions by chaining calls.
@@ -13,6 +42,24 @@ pub trait StopCondition {
     {
         Or::new(self, other)
     }
+
+    /// Restricts this condition to the tokens from `start_index` on, ignoring the ones
+    /// before it.
+    ///
+    /// The tokens a condition checks are those of the current generation, which begin
+    /// with any prefix forced with [`Context::force_prefix`](crate::Context::force_prefix).
+    /// Skipping that prefix keeps a stop sequence inside it, or one that straddles its
+    /// end, from stopping the generation before anything was generated, e.g.
+    /// `ends_with_any_str(&tokenizer, &["\n\n"]).after(prefix_len)`.
+    fn after(self, start_index: usize) -> After<Self>
+    where
+        Self: Sized,
+    {
+        After {
+            inner: self,
+            start_index,
+        }
+    }
 }
 
 // --- Concrete Conditions ---
@@ -27,6 +74,10 @@ impl StopCondition for EndsWith {
     fn check(&self, token_ids: &[u32]) -> bool {
         token_ids.ends_with(&self.token_ids)
     }
+
+    fn matched_sequence(&self, token_ids: &[u32]) -> Option<&[u32]> {
+        self.check(token_ids).then_some(&self.token_ids[..])
+    }
 }
 
 /// Stops generation if the sequence reaches a maximum length.
@@ -41,6 +92,207 @@ impl StopCondition for MaxLen {
     }
 }
 
+/// Stops generation once the model has been confident for several steps in a row.
+///
+/// The entropy (in nats) is computed over the top candidates the backend returns,
+/// renormalized, so it slightly underestimates the entropy of the full vocabulary.
+#[derive(Debug, Clone)]
+pub struct LowEntropy {
+    threshold: f32,
+    consecutive: usize,
+    streak: Cell<usize>,
+}
+
+impl StopCondition for LowEntropy {
+    fn check(&self, _token_ids: &[u32]) -> bool {
+        self.streak.get() >= self.consecutive
+    }
+
+    fn needs_distribution(&self) -> bool {
+        true
+    }
+
+    fn observe(&self, dist: &Distribution) {
+        let total: f32 = dist.probs.iter().sum();
+        let entropy: f32 = dist
+            .probs
+            .iter()
+            .map(|&p| p / total)
+            .filter(|&p| p > 0.0)
+            .map(|p| -p * p.ln())
+            .sum();
+
+        if entropy < self.threshold {
+            self.streak.set(self.streak.get() + 1);
+        } else {
+            self.streak.set(0);
+        }
+    }
+}
+
+/// Stops generation once every opened code fence (a line starting with three
+/// backticks) was closed again and the model followed the last one with a blank line.
+///
+/// The generated tokens are decoded incrementally, so each step only decodes the new
+/// tokens. Fences are matched by toggling on every fence line, without regard to their
+/// length or info string; output with nested or malformed fences may never balance, so
+/// combine it with a hard limit, e.g. `balanced_fences(tokenizer).or(max_len(1024))`.
+#[derive(Debug, Clone)]
+pub struct BalancedFences {
+    state: RefCell<FenceState>,
+    tokenizer: Tokenizer,
+}
+
+#[derive(Debug, Clone)]
+struct FenceState {
+    decoder: StreamDecoder,
+    /// How many of the generated tokens were decoded.
+    seen: usize,
+    /// The decoded text of the current, unfinished line.
+    line: String,
+    open: bool,
+    closed: bool,
+    done: bool,
+}
+
+impl FenceState {
+    fn new(tokenizer: Tokenizer) -> Self {
+        FenceState {
+            decoder: StreamDecoder::new(tokenizer),
+            seen: 0,
+            line: String::new(),
+            open: false,
+            closed: false,
+            done: false,
+        }
+    }
+
+    fn end_line(&mut self) {
+        let line = std::mem::take(&mut self.line);
+        if line.trim_start().starts_with("```") {
+            self.open = !self.open;
+            self.closed |= !self.open;
+        } else if line.trim().is_empty() && self.closed && !self.open {
+            self.done = true;
+        }
+    }
+}
+
+impl StopCondition for BalancedFences {
+    fn check(&self, token_ids: &[u32]) -> bool {
+        let mut state = self.state.borrow_mut();
+        // A shorter sequence means a new generation started with this condition.
+        if token_ids.len() < state.seen {
+            *state = FenceState::new(self.tokenizer.clone());
+        }
+
+        let new_token_ids = &token_ids[state.seen..];
+        state.seen = token_ids.len();
+        if let Some(chunk) = state.decoder.push(new_token_ids) {
+            for (i, part) in chunk.split('\n').enumerate() {
+                if i > 0 {
+                    state.end_line();
+                }
+                state.line.push_str(part);
+            }
+        }
+        state.done
+    }
+}
+
+/// Stops generation once the decoded output contains any of several strings, e.g. the
+/// marker a model closes a tool call with.
+///
+/// The generated tokens are decoded incrementally, so a marker is found even when it
+/// spans several tokens or shares a token with the text around it. Empty strings never
+/// match.
+#[derive(Debug, Clone)]
+pub struct ContainsAny {
+    markers: Vec<String>,
+    state: RefCell<TextState>,
+    tokenizer: Tokenizer,
+}
+
+#[derive(Debug, Clone)]
+struct TextState {
+    decoder: StreamDecoder,
+    /// How many of the generated tokens were decoded.
+    seen: usize,
+    /// The end of the decoded text, long enough to hold all but the last character of
+    /// the longest marker.
+    tail: String,
+    matched: Option<usize>,
+}
+
+impl TextState {
+    fn new(tokenizer: Tokenizer) -> Self {
+        TextState {
+            decoder: StreamDecoder::new(tokenizer),
+            seen: 0,
+            tail: String::new(),
+            matched: None,
+        }
+    }
+}
+
+impl ContainsAny {
+    /// Returns the index (in the order they were given) of the string found by the last
+    /// successful `check`.
+    ///
+    /// When several strings appear in the same step, the one that starts first wins.
+    pub fn matched_index(&self) -> Option<usize> {
+        self.state.borrow().matched
+    }
+}
+
+impl StopCondition for ContainsAny {
+    fn check(&self, token_ids: &[u32]) -> bool {
+        let mut state = self.state.borrow_mut();
+        // A shorter sequence means a new generation started with this condition.
+        if token_ids.len() < state.seen {
+            *state = TextState::new(self.tokenizer.clone());
+        }
+        if state.matched.is_some() {
+            return true;
+        }
+
+        let new_token_ids = &token_ids[state.seen..];
+        state.seen = token_ids.len();
+        let Some(chunk) = state.decoder.push(new_token_ids) else {
+            return false;
+        };
+        state.tail.push_str(&chunk);
+
+        state.matched = self
+            .markers
+            .iter()
+            .enumerate()
+            .filter(|(_, marker)| !marker.is_empty())
+            .filter_map(|(i, marker)| state.tail.find(marker.as_str()).map(|pos| (pos, i)))
+            .min()
+            .map(|(_, i)| i);
+
+        // Keep enough text for a marker that starts in this chunk and ends in a later one.
+        let keep = self
+            .markers
+            .iter()
+            .map(|marker| marker.chars().count().saturating_sub(1))
+            .max()
+            .unwrap_or(0);
+        let start = match keep {
+            0 => state.tail.len(),
+            _ => state
+                .tail
+                .char_indices()
+                .rev()
+                .nth(keep - 1)
+                .map_or(0, |(i, _)| i),
+        };
+        state.tail.drain(..start);
+        state.matched.is_some()
+    }
+}
+
 // --- Combinators ---
 
 /// A combinator that stops if *any* of its inner conditions are met.
@@ -48,11 +300,72 @@ impl StopCondition for MaxLen {
 #[derive(Debug, Clone)]
 pub struct AnyEndsWith {
     conditions: Vec<EndsWith>,
+    matched: Cell<Option<usize>>,
+}
+
+impl AnyEndsWith {
+    /// Returns the index (in the order they were given) of the sequence that matched
+    /// in the last successful `check`.
+    ///
+    /// When several sequences match, e.g. one is a suffix of another, the longest wins.
+    pub fn matched_index(&self) -> Option<usize> {
+        self.matched.get()
+    }
+
+    fn longest_match(&self, token_ids: &[u32]) -> Option<usize> {
+        self.conditions
+            .iter()
+            .enumerate()
+            .filter(|(_, c)| c.check(token_ids))
+            .max_by_key(|&(i, c)| (c.token_ids.len(), std::cmp::Reverse(i)))
+            .map(|(i, _)| i)
+    }
 }
 
 impl StopCondition for AnyEndsWith {
     fn check(&self, token_ids: &[u32]) -> bool {
-        self.conditions.iter().any(|c| c.check(token_ids))
+        let matched = self.longest_match(token_ids);
+        if matched.is_some() {
+            self.matched.set(matched);
+        }
+        matched.is_some()
+    }
+
+    fn matched_sequence(&self, token_ids: &[u32]) -> Option<&[u32]> {
+        self.longest_match(token_ids)
+            .map(|i| &self.conditions[i].token_ids[..])
+    }
+}
+
+/// A combinator that only lets its inner condition see the tokens from `start_index` on,
+/// see [`StopCondition::after`].
+#[derive(Debug, Clone, Copy)]
+pub struct After<C> {
+    inner: C,
+    start_index: usize,
+}
+
+impl<C: StopCondition> StopCondition for After<C> {
+    fn check(&self, token_ids: &[u32]) -> bool {
+        token_ids.len() > self.start_index && self.inner.check(&token_ids[self.start_index..])
+    }
+
+    fn needs_distribution(&self) -> bool {
+        self.inner.needs_distribution()
+    }
+
+    fn observe(&self, dist: &Distribution) {
+        self.inner.observe(dist);
+    }
+
+    fn distribution_top_k(&self) -> Option<u32> {
+        self.inner.distribution_top_k()
+    }
+
+    fn matched_sequence(&self, token_ids: &[u32]) -> Option<&[u32]> {
+        token_ids
+            .get(self.start_index..)
+            .and_then(|token_ids| self.inner.matched_sequence(token_ids))
     }
 }
 
@@ -79,6 +392,55 @@ where
     fn check(&self, token_ids: &[u32]) -> bool {
         self.first.check(token_ids) || self.second.check(token_ids)
     }
+
+    fn needs_distribution(&self) -> bool {
+        self.first.needs_distribution() || self.second.needs_distribution()
+    }
+
+    fn observe(&self, dist: &Distribution) {
+        self.first.observe(dist);
+        self.second.observe(dist);
+    }
+
+    fn distribution_top_k(&self) -> Option<u32> {
+        match (
+            self.first.distribution_top_k(),
+            self.second.distribution_top_k(),
+        ) {
+            (Some(first), Some(second)) => Some(first.max(second)),
+            (first, second) => first.or(second),
+        }
+    }
+
+    fn matched_sequence(&self, token_ids: &[u32]) -> Option<&[u32]> {
+        self.first
+            .matched_sequence(token_ids)
+            .or_else(|| self.second.matched_sequence(token_ids))
+    }
+}
+
+/// A borrowed condition, so the caller can still inspect it after the generation, e.g.
+/// `ContainsAny::matched_index`.
+impl<T: StopCondition + ?Sized> StopCondition for &T {
+    fn check(&self, token_ids: &[u32]) -> bool {
+        (**self).check(token_ids)
+    }
+
+    fn needs_distribution(&self) -> bool {
+        (**self).needs_distribution()
+    }
+
+    fn observe(&self, dist: &Distribution) {
+        (**self).observe(dist)
+    }
+
+    fn distribution_top_k(&self) -> Option<u32> {
+        (**self).distribution_top_k()
+    }
+
+    fn matched_sequence(&self, token_ids: &[u32]) -> Option<&[u32]> {
+        (**self).matched_sequence(token_ids)
+    }
 }
 
 // --- Constructor Functions ---
@@ -94,10 +456,101 @@ pub fn ends_with_any(stop_sequences: Vec<Vec<u32>>) -> AnyEndsWith {
         .into_iter()
         .map(|token_ids| EndsWith { token_ids })
         .collect();
-    AnyEndsWith { conditions }
+    AnyEndsWith {
+        conditions,
+        matched: Cell::new(None),
+    }
+}
+
+/// Creates a condition that stops if the sequence ends with the tokens of any of the
+/// provided strings, each tokenized on its own with `tokenizer`.
+///
+/// Empty strings are ignored, since they would match immediately.
+pub fn ends_with_any_str(tokenizer: &Tokenizer, stop_sequences: &[&str]) -> AnyEndsWith {
+    ends_with_any(
+        stop_sequences
+            .iter()
+            .filter(|text| !text.is_empty())
+            .map(|text| tokenizer.tokenize(text))
+            .collect(),
+    )
+}
+
+/// Creates a condition that stops at the model's stop sequences or when the output
+/// opens a user or system message, i.e. when the model starts writing the next turn
+/// of the conversation instead of ending its own.
+///
+/// The role markers are those of [`Model::role_tokens`], matched like the sequences
+/// of [`Model::default_stop_condition`], so a generation that ends on one trims it
+/// from the context and the returned text. For base models without a chat template
+/// it is the default stop condition.
+pub fn on_role_token(model: &Model) -> AnyEndsWith {
+    let user = model.role_tokens("user");
+    let system = model.role_tokens("system");
+    model.stop_condition_with(&[&user, &system])
+}
+
+/// Creates a condition that stops when the next-token entropy stays below `threshold`
+/// (in nats) for `consecutive` steps, i.e. when the model is just padding out an answer
+/// it has already finished.
+///
+/// Combine it with a hard limit, e.g. `low_entropy(0.05, 8).or(max_len(256))`.
+pub fn low_entropy(threshold: f32, consecutive: usize) -> LowEntropy {
+    LowEntropy {
+        threshold,
+        consecutive,
+        streak: Cell::new(0),
+    }
+}
+
+/// Creates a condition that stops once the code fences in the output are balanced and
+/// followed by a blank line, see [`BalancedFences`].
+///
+/// `tokenizer` must be the one of the generating model, e.g. `model.get_tokenizer()`.
+pub fn balanced_fences(tokenizer: Tokenizer) -> BalancedFences {
+    BalancedFences {
+        state: RefCell::new(FenceState::new(tokenizer.clone())),
+        tokenizer,
+    }
+}
+
+/// Creates a condition that stops once the decoded output contains any of `markers`,
+/// see [`ContainsAny`].
+///
+/// `tokenizer` must be the one of the generating model, e.g. `model.get_tokenizer()`.
+pub fn contains_any(tokenizer: Tokenizer, markers: &[&str]) -> ContainsAny {
+    ContainsAny {
+        markers: markers.iter().map(|marker| marker.to_string()).collect(),
+        state: RefCell::new(TextState::new(tokenizer.clone())),
+        tokenizer,
+    }
 }
 
 /// Creates a condition that stops if the sequence ends with a single provided token sequence.
 pub fn ends_with(token_ids: Vec<u32>) -> EndsWith {
     EndsWith { token_ids }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn after_ignores_a_stop_sequence_ending_the_prompt() {
+        // A prompt ending in "\n\n" (token 10 twice), as the history of a generation.
+        let prompt = [1, 2, 10, 10];
+        let stop = ends_with_any(vec![vec![10, 10]]);
+        assert!(stop.check(&prompt));
+
+        let stop = stop.after(prompt.len());
+        assert!(!stop.check(&prompt));
+        assert!(!stop.check(&[&prompt[..], &[3]].concat()));
+        // A stop sequence straddling the prompt boundary does not count either.
+        let straddling = ends_with_any(vec![vec![10, 10]]).after(4);
+        assert!(!straddling.check(&[1, 2, 3, 10, 10]));
+
+        let history = [&prompt[..], &[3, 10, 10]].concat();
+        assert!(stop.check(&history));
+        assert_eq!(stop.matched_sequence(&history), Some(&[10, 10][..]));
+    }
+}
@@ -0,0 +1,390 @@
+use crate::{DecodeOptions, Tokenizer};
+use std::mem;
+
+/// Turns token IDs into text for a [`StreamDecoder`].
+///
+/// Implemented by [`Tokenizer`]; other implementations let a decoder run without a
+/// model, e.g. a fake byte-fallback tokenizer in tests.
+pub trait Detokenize {
+    /// Decodes `token_ids` according to `options`, see [`Tokenizer::detokenize_with`].
+    fn detokenize_with(&self, token_ids: &[u32], options: &DecodeOptions) -> String;
+}
+
+impl Detokenize for Tokenizer {
+    fn detokenize_with(&self, token_ids: &[u32], options: &DecodeOptions) -> String {
+        Tokenizer::detokenize_with(self, token_ids, options)
+    }
+}
+
+/// Incrementally turns generated tokens into text without ever emitting a broken
+/// character.
+///
+/// Byte-fallback tokenizers can spread one character over several tokens (a 4-byte
+/// emoji may arrive as three or four pieces), and decoding a prefix of them yields
+/// replacement characters. The decoder holds back such trailing bytes, as well as a
+/// trailing zero-width joiner or unpaired regional indicator that only forms a
+/// complete grapheme together with what follows, until the next tokens complete them.
+/// [`StreamDecoder::finish`] flushes whatever is still held back at the end of the
+/// stream.
+///
+/// Only a short window of recent tokens is re-decoded on every push, so the cost per
+/// token does not grow with the length of the output.
+#[derive(Debug, Clone)]
+pub struct StreamDecoder<T = Tokenizer> {
+    tokenizer: T,
+    options: DecodeOptions,
+    token_ids: Vec<u32>,
+    /// Start of the window that is re-decoded to find the new text.
+    prefix_offset: usize,
+    /// End of the text that has already been returned, as a token index.
+    read_offset: usize,
+    /// Decoded text held back because the following text may remove its leading
+    /// space, see [`DecodeOptions::clean_spaces`].
+    held_text: String,
+}
+
+impl<T: Detokenize> StreamDecoder<T> {
+    /// Creates a decoder that returns every token as the tokenizer decodes it, special
+    /// tokens included.
+    pub fn new(tokenizer: T) -> Self {
+        Self::with_options(
+            tokenizer,
+            DecodeOptions {
+                skip_special: false,
+                clean_spaces: false,
+            },
+        )
+    }
+
+    /// Creates a decoder whose chunks, concatenated, equal what
+    /// [`Tokenizer::detokenize_with`] returns for all pushed tokens and `options`.
+    ///
+    /// With [`DecodeOptions::clean_spaces`], a trailing space is held back until the
+    /// following text shows whether it belongs before punctuation or a contraction.
+    pub fn with_options(tokenizer: T, options: DecodeOptions) -> Self {
+        StreamDecoder {
+            tokenizer,
+            options,
+            token_ids: Vec::new(),
+            prefix_offset: 0,
+            read_offset: 0,
+            held_text: String::new(),
+        }
+    }
+
+    /// Appends `token_ids` and returns the text they complete, if any.
+    pub fn push(&mut self, token_ids: &[u32]) -> Option<String> {
+        self.token_ids.extend_from_slice(token_ids);
+
+        let prefix_text = self.decode(&self.token_ids[self.prefix_offset..self.read_offset]);
+        let new_text = self.decode(&self.token_ids[self.prefix_offset..]);
+
+        if new_text.len() <= prefix_text.len() || is_incomplete(&new_text) {
+            return None;
+        }
+        let chunk = new_text.get(prefix_text.len()..)?.to_string();
+        self.prefix_offset = self.read_offset;
+        self.read_offset = self.token_ids.len();
+        self.clean(chunk)
+    }
+
+    /// Returns the text still held back, ending the stream.
+    pub fn finish(&mut self) -> String {
+        let prefix_text = self.decode(&self.token_ids[self.prefix_offset..self.read_offset]);
+        let new_text = self.decode(&self.token_ids[self.prefix_offset..]);
+        self.prefix_offset = self.token_ids.len();
+        self.read_offset = self.token_ids.len();
+        let rest = mem::take(&mut self.held_text) + new_text.get(prefix_text.len()..).unwrap_or("");
+        if self.options.clean_spaces {
+            clean_up_spaces(&rest)
+        } else {
+            rest
+        }
+    }
+
+    /// Decodes `token_ids` without cleaning up spaces, which is applied to the
+    /// returned chunks instead, so the window arithmetic works on the raw text.
+    fn decode(&self, token_ids: &[u32]) -> String {
+        let options = DecodeOptions {
+            clean_spaces: false,
+            ..self.options
+        };
+        self.tokenizer.detokenize_with(token_ids, &options)
+    }
+
+    /// Cleans up the spaces of `chunk` and the text held back before it, holding back
+    /// the tail that may start a pattern [`clean_up_spaces`] replaces, so no pattern
+    /// is split between two chunks.
+    fn clean(&mut self, chunk: String) -> Option<String> {
+        if !self.options.clean_spaces {
+            return Some(chunk);
+        }
+        let text = mem::take(&mut self.held_text) + &chunk;
+        let mut split = text.len();
+        // Moving the split may leave another partial pattern before it, e.g. the
+        // `" '"` of a `" ' "` cut after its second space.
+        while split > 0 && partial_cleanup_len(&text[..split]) > 0 {
+            split -= partial_cleanup_len(&text[..split]);
+        }
+        self.held_text = text[split..].to_string();
+        (split > 0).then(|| clean_up_spaces(&text[..split]))
+    }
+}
+
+/// The replacements of [`DecodeOptions::clean_spaces`], applied in order, as in
+/// Hugging Face's `clean_up_tokenization`.
+const SPACE_CLEANUPS: [(&str, &str); 10] = [
+    (" .", "."),
+    (" ?", "?"),
+    (" !", "!"),
+    (" ,", ","),
+    (" ' ", "'"),
+    (" n't", "n't"),
+    (" 'm", "'m"),
+    (" 's", "'s"),
+    (" 've", "'ve"),
+    (" 're", "'re"),
+];
+
+/// Removes the spaces tokenizers leave before punctuation and contractions.
+pub(crate) fn clean_up_spaces(text: &str) -> String {
+    SPACE_CLEANUPS
+        .iter()
+        .fold(text.to_string(), |text, (from, to)| text.replace(from, to))
+}
+
+/// Returns the length of the longest tail of `text` that is a proper prefix of a
+/// pattern in [`SPACE_CLEANUPS`], i.e. that the following text may complete.
+fn partial_cleanup_len(text: &str) -> usize {
+    SPACE_CLEANUPS
+        .iter()
+        .flat_map(|(from, _)| (1..from.len()).filter(|&len| text.ends_with(&from[..len])))
+        .max()
+        .unwrap_or(0)
+}
+
+/// Whether `text` ends in a way the following tokens may still change: an
+/// incomplete multi-byte sequence, a zero-width joiner, or a lone regional indicator.
+fn is_incomplete(text: &str) -> bool {
+    const ZERO_WIDTH_JOINER: char = '\u{200D}';
+    let is_regional_indicator = |c: char| ('\u{1F1E6}'..='\u{1F1FF}').contains(&c);
+
+    match text.chars().next_back() {
+        Some(char::REPLACEMENT_CHARACTER | ZERO_WIDTH_JOINER) => true,
+        Some(c) if is_regional_indicator(c) => {
+            let trailing = text
+                .chars()
+                .rev()
+                .take_while(|&c| is_regional_indicator(c))
+                .count();
+            trailing % 2 == 1
+        }
+        _ => false,
+    }
+}
+
+/// Returns the byte offset right after the first complete sentence in `text`, if any.
+///
+/// Full-width sentence punctuation ends a sentence on its own, while `.`, `!`, `?` and
+/// `…` only do once whitespace follows, so decimals and text that is still being
+/// generated are not cut. A period after a common abbreviation or a single-letter
+/// initial does not end a sentence. Closing quotes and brackets right after the
+/// punctuation belong to the sentence they end.
+pub(crate) fn sentence_end(text: &str) -> Option<usize> {
+    let mut chars = text.char_indices().peekable();
+    while let Some((i, punct)) = chars.next() {
+        let needs_space = match punct {
+            '。' | '！' | '？' => false,
+            '.' | '!' | '?' | '…' => true,
+            _ => continue,
+        };
+
+        let mut end = i + punct.len_utf8();
+        while let Some(&(j, c)) = chars.peek() {
+            if !is_closing(c) {
+                break;
+            }
+            end = j + c.len_utf8();
+            chars.next();
+        }
+
+        if !needs_space {
+            return Some(end);
+        }
+        if !chars.peek().is_some_and(|&(_, c)| c.is_whitespace()) {
+            continue;
+        }
+        if punct == '.' && ends_with_abbreviation(&text[..i]) {
+            continue;
+        }
+        return Some(end);
+    }
+    None
+}
+
+/// The clause punctuation [`Context::generate_for_tts`] ends a chunk at besides the
+/// sentence ends: commas, semicolons and colons, in their ASCII and full-width forms,
+/// and the ideographic comma.
+///
+/// [`Context::generate_for_tts`]: crate::Context::generate_for_tts
+pub const TTS_CLAUSE_BOUNDARIES: [char; 7] = [',', ';', ':', '，', '；', '：', '、'];
+
+/// Returns the byte offset right after the first complete clause in `text`, if any.
+///
+/// A clause ends at a sentence end (see [`sentence_end`]) or at one of `boundaries`.
+/// ASCII boundaries only count once whitespace follows, so numbers such as "1,000"
+/// or "3:45" are not cut, while full-width ones end a clause on their own. Closing
+/// quotes and brackets right after the punctuation belong to the clause they end.
+pub(crate) fn clause_end(text: &str, boundaries: &[char]) -> Option<usize> {
+    let sentence = sentence_end(text);
+
+    let mut chars = text.char_indices().peekable();
+    while let Some((i, punct)) = chars.next() {
+        if sentence.is_some_and(|end| i >= end) {
+            break;
+        }
+        if !boundaries.contains(&punct) {
+            continue;
+        }
+
+        let mut end = i + punct.len_utf8();
+        while let Some(&(j, c)) = chars.peek() {
+            if !is_closing(c) {
+                break;
+            }
+            end = j + c.len_utf8();
+            chars.next();
+        }
+
+        if punct.is_ascii() && !chars.peek().is_some_and(|&(_, c)| c.is_whitespace()) {
+            continue;
+        }
+        return Some(end);
+    }
+    sentence
+}
+
+/// Whether `c` is a closing quote or bracket, which belongs to the sentence or clause
+/// the punctuation before it ends.
+fn is_closing(c: char) -> bool {
+    matches!(c, '"' | '\'' | ')' | ']' | '”' | '’' | '」' | '』' | '）')
+}
+
+/// Whether the last word of `text` is an abbreviation that is written with a period.
+fn ends_with_abbreviation(text: &str) -> bool {
+    const ABBREVIATIONS: [&str; 12] = [
+        "mr", "mrs", "ms", "dr", "prof", "sr", "jr", "st", "vs", "etc", "e.g", "i.e",
+    ];
+
+    let word = text
+        .rsplit(char::is_whitespace)
+        .next()
+        .unwrap_or("")
+        .trim_start_matches(|c: char| !c.is_alphanumeric());
+    let mut chars = word.chars();
+    match (chars.next(), chars.next()) {
+        (Some(c), None) => c.is_alphabetic(),
+        _ => ABBREVIATIONS.contains(&word.to_lowercase().as_str()),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// A byte-fallback tokenizer: IDs below 256 are single bytes, and any other ID
+    /// stands for the middle two bytes of the 4-byte emoji U+1F600.
+    struct ByteTokenizer;
+
+    impl Detokenize for ByteTokenizer {
+        fn detokenize_with(&self, token_ids: &[u32], _options: &DecodeOptions) -> String {
+            let bytes: Vec<u8> = token_ids
+                .iter()
+                .flat_map(|&id| match id {
+                    0..=255 => vec![id as u8],
+                    _ => vec![0x9F, 0x98],
+                })
+                .collect();
+            String::from_utf8_lossy(&bytes).into_owned()
+        }
+    }
+
+    fn push_str(decoder: &mut StreamDecoder<ByteTokenizer>, text: &str) -> Option<String> {
+        let ids: Vec<u32> = text.bytes().map(u32::from).collect();
+        decoder.push(&ids)
+    }
+
+    #[test]
+    fn emoji_split_over_three_tokens_is_emitted_once_complete() {
+        let mut decoder = StreamDecoder::new(ByteTokenizer);
+        assert_eq!(push_str(&mut decoder, "hi "), Some("hi ".to_string()));
+        assert_eq!(decoder.push(&[0xF0]), None);
+        assert_eq!(decoder.push(&[1000]), None);
+        assert_eq!(decoder.push(&[0x80]), Some("\u{1F600}".to_string()));
+        assert_eq!(decoder.finish(), "");
+    }
+
+    #[test]
+    fn cleaned_chunks_concatenate_to_the_full_decode() {
+        let options = DecodeOptions {
+            skip_special: false,
+            clean_spaces: true,
+        };
+        for text in [
+            "I do n't know , he said .",
+            "it 's ' fine ' !",
+            "trailing space ",
+            "what ?  really  ?",
+        ] {
+            // The full-sequence decode cleans up the spaces of the whole text at once.
+            let full = clean_up_spaces(text);
+            for chunk_len in [1, 2, 3, 5] {
+                let mut decoder = StreamDecoder::with_options(ByteTokenizer, options);
+                let mut streamed = String::new();
+                for chunk in text.as_bytes().chunks(chunk_len) {
+                    let ids: Vec<u32> = chunk.iter().copied().map(u32::from).collect();
+                    streamed.extend(decoder.push(&ids));
+                }
+                streamed.push_str(&decoder.finish());
+                assert_eq!(streamed, full, "{:?} in chunks of {}", text, chunk_len);
+            }
+        }
+    }
+
+    #[test]
+    fn finish_flushes_an_incomplete_tail() {
+        let mut decoder = StreamDecoder::new(ByteTokenizer);
+        assert_eq!(decoder.push(&[0xF0, 1000]), None);
+        assert_eq!(decoder.finish(), "\u{FFFD}");
+    }
+
+    #[test]
+    fn zero_width_joiner_is_held_until_the_next_character() {
+        let mut decoder = StreamDecoder::new(ByteTokenizer);
+        assert_eq!(
+            push_str(&mut decoder, "\u{1F468}"),
+            Some("\u{1F468}".to_string())
+        );
+        assert_eq!(push_str(&mut decoder, "\u{200D}"), None);
+        assert_eq!(
+            push_str(&mut decoder, "\u{1F469}"),
+            Some("\u{200D}\u{1F469}".to_string())
+        );
+    }
+
+    #[test]
+    fn incomplete_zero_width_joiner_sequences() {
+        assert!(is_incomplete("\u{1F468}\u{200D}"));
+        assert!(!is_incomplete("\u{1F468}\u{200D}\u{1F469}"));
+    }
+
+    #[test]
+    fn incomplete_regional_indicator_pairs() {
+        // U+1F1FA U+1F1F8 is the flag of the United States.
+        assert!(is_incomplete("\u{1F1FA}"));
+        assert!(!is_incomplete("\u{1F1FA}\u{1F1F8}"));
+        assert!(is_incomplete("\u{1F1FA}\u{1F1F8}\u{1F1EC}"));
+        assert!(!is_incomplete("flag"));
+        assert!(!is_incomplete(""));
+    }
+}
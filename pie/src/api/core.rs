@@ -5,11 +5,11 @@ pub mod runtime;
 pub mod tokenize;
 
 use crate::api::inferlet;
-use crate::api::inferlet::core::common::Priority;
+use crate::api::inferlet::core::common::{CacheStats, ExportMode, Priority};
 use crate::instance::InstanceState;
 use crate::model;
 use crate::model::request::{QueryRequest, QueryResponse, Request};
-use crate::model::resource::{ResourceId, ResourceTypeId};
+use crate::model::resource::{self, ResourceId, ResourceTypeId};
 use crate::model::{ModelInfo, submit_request};
 use anyhow::Result;
 use bytes::Bytes;
@@ -133,7 +133,8 @@ impl inferlet::core::common::Host for InstanceState {
     ) -> Result<()> {
         let inst_id = self.id();
         let svc_id = self.ctx().table.get(&queue)?.service_id;
-        self.unmap_resources(svc_id, resource_type, &ptrs);
+        // The model tracks physical pointers, not the instance's virtual ones.
+        let ptrs = self.unmap_resources(svc_id, resource_type, &ptrs);
 
         model::Command::Deallocate {
             inst_id,
@@ -168,6 +169,22 @@ impl inferlet::core::common::Host for InstanceState {
         Ok(c)
     }
 
+    async fn get_available_resources(
+        &mut self,
+        queue: Resource<Queue>,
+        resource_type: ResourceTypeId,
+    ) -> Result<u32> {
+        let q = self.ctx().table.get(&queue)?;
+        let (tx, rx) = oneshot::channel();
+        model::Command::GetAvailable {
+            type_id: resource_type,
+            response: tx,
+        }
+        .dispatch(q.service_id)?;
+
+        Ok(rx.await? as u32)
+    }
+
     async fn release_exported_resources(
         &mut self,
         queue: Resource<Queue>,
@@ -212,6 +229,45 @@ impl inferlet::core::common::Host for InstanceState {
         Ok(())
     }
 
+    async fn export_resources_with_mode(
+        &mut self,
+        queue: Resource<Queue>,
+        resource_type: ResourceTypeId,
+        mut ptrs: Vec<ResourceId>,
+        name: String,
+        mode: ExportMode,
+    ) -> Result<Result<(), String>> {
+        let inst_id = self.id();
+        let svc_id = self.ctx().table.get(&queue)?.service_id;
+
+        ptrs.iter_mut().try_for_each(|ptr| {
+            *ptr = self.translate_resource_ptr(svc_id, resource_type, *ptr)?;
+            Ok::<_, anyhow::Error>(())
+        })?;
+
+        let mode = match mode {
+            ExportMode::FailIfExists => resource::ExportMode::FailIfExists,
+            ExportMode::Overwrite => resource::ExportMode::Overwrite,
+            ExportMode::Dedup => resource::ExportMode::Dedup,
+            // The token bookkeeping that strict mode checks lives in the inferlet.
+            ExportMode::Strict => resource::ExportMode::FailIfExists,
+        };
+
+        let (tx, rx) = oneshot::channel();
+
+        model::Command::ExportWithMode {
+            inst_id,
+            type_id: resource_type,
+            ptrs,
+            name,
+            mode,
+            response: tx,
+        }
+        .dispatch(svc_id)?;
+
+        Ok(rx.await?)
+    }
+
     async fn import_resources(
         &mut self,
         queue: Resource<Queue>,
@@ -271,6 +327,29 @@ impl inferlet::core::common::HostModel for InstanceState {
         Ok(kv_page_size)
     }
 
+    async fn get_max_context_length(&mut self, this: Resource<Model>) -> Result<u32> {
+        let max_context_length = self.ctx().table.get(&this)?.info.max_context_length;
+        Ok(max_context_length)
+    }
+
+    async fn get_prefix_cache_stats(&mut self, this: Resource<Model>) -> Result<CacheStats> {
+        let svc_id = self.ctx().table.get(&this)?.service_id;
+        let (tx, rx) = oneshot::channel();
+        model::Command::GetExportStats { response: tx }.dispatch(svc_id)?;
+        let stats = rx.await?;
+        Ok(CacheStats {
+            hits: stats.hits,
+            misses: stats.misses,
+            evictions: stats.evictions,
+        })
+    }
+
+    async fn reset_prefix_cache_stats(&mut self, this: Resource<Model>) -> Result<()> {
+        let svc_id = self.ctx().table.get(&this)?.service_id;
+        model::Command::ResetExportStats.dispatch(svc_id)?;
+        Ok(())
+    }
+
     async fn create_queue(&mut self, this: Resource<Model>) -> Result<Resource<Queue>> {
         let model = self.ctx().table.get(&this)?;
         let queue = Queue {
@@ -1,7 +1,8 @@
 use crate::api::core::Queue;
 use crate::api::inferlet;
+use crate::api::inferlet::core::forward::LogitPrecision;
 use crate::instance::InstanceState;
-use crate::model::request::{ForwardPassRequest, ForwardPassResponse, Request};
+use crate::model::request::{CopyKvPagesRequest, ForwardPassRequest, ForwardPassResponse, Request};
 use crate::model::resource::{EMBED_TYPE_ID, KV_PAGE_TYPE_ID, ResourceId};
 use crate::model::submit_request;
 use anyhow::{Result, bail};
@@ -30,6 +31,7 @@ pub struct ForwardPass {
     output_token_samplers: Vec<HashMap<String, rmpv::Value>>,
     output_embed_ptrs: Vec<u32>,
     output_embed_indices: Vec<u32>,
+    logits_fp32: bool,
 }
 
 #[derive(Debug)]
@@ -66,6 +68,39 @@ impl Pollable for ForwardPassResult {
 }
 
 impl inferlet::core::forward::Host for InstanceState {
+    async fn copy_kv_pages(
+        &mut self,
+        queue: Resource<Queue>,
+        mut src_kv_page_ptrs: Vec<ResourceId>,
+        mut dst_kv_page_ptrs: Vec<ResourceId>,
+    ) -> Result<()> {
+        if src_kv_page_ptrs.len() != dst_kv_page_ptrs.len() {
+            bail!(
+                "mismatched kv page copy, sources: {}, destinations: {}",
+                src_kv_page_ptrs.len(),
+                dst_kv_page_ptrs.len()
+            );
+        }
+
+        let (svc_id, queue_id, priority) = self.read_queue(&queue)?;
+        src_kv_page_ptrs
+            .iter_mut()
+            .chain(dst_kv_page_ptrs.iter_mut())
+            .try_for_each(|kv_page_ptr| {
+                *kv_page_ptr =
+                    self.translate_resource_ptr(svc_id, KV_PAGE_TYPE_ID, *kv_page_ptr)?;
+                Ok::<_, anyhow::Error>(())
+            })?;
+
+        let req = Request::CopyKvPages(CopyKvPagesRequest {
+            src_kv_page_ptrs,
+            dst_kv_page_ptrs,
+        });
+        submit_request(svc_id, queue_id, priority, req)?;
+
+        Ok(())
+    }
+
     async fn create_forward_pass(
         &mut self,
         queue: Resource<Queue>,
@@ -87,6 +122,7 @@ impl inferlet::core::forward::Host for InstanceState {
             output_token_samplers: vec![],
             output_embed_ptrs: vec![],
             output_embed_indices: vec![],
+            logits_fp32: false,
         };
         Ok(self.ctx().table.push(pass)?)
     }
@@ -344,6 +380,16 @@ impl inferlet::core::forward::Host for InstanceState {
 
         Ok(())
     }
+
+    async fn set_logit_precision(
+        &mut self,
+        pass: Resource<ForwardPass>,
+        precision: LogitPrecision,
+    ) -> Result<()> {
+        let pass = self.ctx().table.get_mut(&pass)?;
+        pass.logits_fp32 = matches!(precision, LogitPrecision::Fp32);
+        Ok(())
+    }
 }
 
 impl inferlet::core::forward::HostForwardPass for InstanceState {
@@ -365,6 +411,12 @@ impl inferlet::core::forward::HostForwardPass for InstanceState {
             let queue_id = pass.queue.uid;
             let priority = pass.queue.priority;
 
+            if pass.logits_fp32 {
+                for sampler in &mut pass.output_token_samplers {
+                    sampler.insert("logits_fp32".to_string(), rmpv::Value::from(true));
+                }
+            }
+
             let request = ForwardPassRequest {
                 input_tokens: take(&mut pass.input_tokens),
                 input_token_positions: take(&mut pass.input_token_positions),
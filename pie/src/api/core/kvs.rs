@@ -3,6 +3,28 @@ use crate::instance::InstanceState;
 use crate::kvs;
 use crate::service::ServiceCommand;
 use tokio::sync::oneshot;
+use wasmtime::component::Resource;
+use wasmtime_wasi::p2::{DynPollable, Pollable, subscribe};
+use wasmtime_wasi::{WasiView, async_trait};
+
+#[derive(Debug)]
+pub struct WatchResult {
+    receiver: oneshot::Receiver<String>,
+    result: Option<String>,
+    done: bool,
+}
+
+#[async_trait]
+impl Pollable for WatchResult {
+    async fn ready(&mut self) {
+        if self.done {
+            return;
+        }
+        // The sender is only dropped if the store service shut down; resolve with none.
+        self.result = (&mut self.receiver).await.ok();
+        self.done = true;
+    }
+}
 
 impl inferlet::core::kvs::Host for InstanceState {
     async fn store_get(&mut self, key: String) -> anyhow::Result<Option<String>> {
@@ -12,10 +34,22 @@ impl inferlet::core::kvs::Host for InstanceState {
         Ok(res)
     }
 
+    async fn store_get_many(&mut self, keys: Vec<String>) -> anyhow::Result<Vec<Option<String>>> {
+        let (tx, rx) = oneshot::channel();
+        kvs::Command::GetMany { keys, response: tx }.dispatch();
+        let res = rx.await?;
+        Ok(res)
+    }
+
     async fn store_set(&mut self, key: String, value: String) -> anyhow::Result<()> {
         kvs::Command::Set { key, value }.dispatch();
         Ok(())
     }
+    async fn store_set_batch(&mut self, entries: Vec<(String, String)>) -> anyhow::Result<()> {
+        kvs::Command::SetBatch { entries }.dispatch();
+        Ok(())
+    }
+
     async fn store_delete(&mut self, key: String) -> anyhow::Result<()> {
         kvs::Command::Delete { key }.dispatch();
         Ok(())
@@ -34,4 +68,45 @@ impl inferlet::core::kvs::Host for InstanceState {
         let res = rx.await?;
         Ok(res)
     }
+
+    async fn store_take(&mut self, key: String, amount: u64) -> anyhow::Result<u64> {
+        let (tx, rx) = oneshot::channel();
+        kvs::Command::Take {
+            key,
+            amount,
+            response: tx,
+        }
+        .dispatch();
+        let res = rx.await?;
+        Ok(res)
+    }
+
+    async fn store_watch(&mut self, key: String) -> anyhow::Result<Resource<WatchResult>> {
+        let (tx, rx) = oneshot::channel();
+        kvs::Command::Watch { key, response: tx }.dispatch();
+        let res = WatchResult {
+            receiver: rx,
+            result: None,
+            done: false,
+        };
+        Ok(self.ctx().table.push(res)?)
+    }
+}
+
+impl inferlet::core::kvs::HostWatchResult for InstanceState {
+    async fn pollable(
+        &mut self,
+        this: Resource<WatchResult>,
+    ) -> anyhow::Result<Resource<DynPollable>> {
+        subscribe(self.ctx().table, this)
+    }
+
+    async fn get(&mut self, this: Resource<WatchResult>) -> anyhow::Result<Option<String>> {
+        Ok(self.ctx().table.get_mut(&this)?.result.clone())
+    }
+
+    async fn drop(&mut self, this: Resource<WatchResult>) -> anyhow::Result<()> {
+        self.ctx().table.delete(this)?;
+        Ok(())
+    }
 }
@@ -18,6 +18,22 @@ pub struct Subscription {
     receiver: mpsc::Receiver<String>,
     result: Option<String>,
     done: bool,
+    /// Whether the subscriber was already removed from the topic.
+    unsubscribed: bool,
+}
+
+impl Subscription {
+    /// Removes the subscriber from its topic, once.
+    fn unsubscribe(&mut self) {
+        if !self.unsubscribed {
+            self.unsubscribed = true;
+            PubSubCommand::Unsubscribe {
+                topic: self.topic.clone(),
+                sub_id: self.id,
+            }
+            .dispatch();
+        }
+    }
 }
 
 #[derive(Debug)]
@@ -110,11 +126,23 @@ impl inferlet::core::message::Host for InstanceState {
         Ok(())
     }
 
+    async fn broadcast_ordered(&mut self, topic: String, message: String) -> anyhow::Result<u64> {
+        let (tx, rx) = oneshot::channel();
+        PubSubCommand::PublishOrdered {
+            topic,
+            message,
+            seq: tx,
+        }
+        .dispatch();
+        Ok(rx.await?)
+    }
+
     async fn subscribe(&mut self, topic: String) -> anyhow::Result<Resource<Subscription>> {
         let (tx, rx) = mpsc::channel(64);
         let (sub_tx, sub_rx) = oneshot::channel();
         PubSubCommand::Subscribe {
             topic: topic.clone(),
+            inst_id: self.id(),
             sender: tx,
             sub_id: sub_tx,
         }
@@ -126,6 +154,7 @@ impl inferlet::core::message::Host for InstanceState {
             receiver: rx,
             result: None,
             done: false,
+            unsubscribed: false,
         };
         Ok(self.ctx().table.push(sub)?)
     }
@@ -158,20 +187,41 @@ impl inferlet::core::message::HostSubscription for InstanceState {
     }
 
     async fn get(&mut self, this: Resource<Subscription>) -> anyhow::Result<Option<String>> {
-        Ok(mem::take(&mut self.ctx().table.get_mut(&this)?.result))
+        let sub = self.ctx().table.get_mut(&this)?;
+        let result = mem::take(&mut sub.result);
+        // Taking a message re-arms the pollable, so a guest that keeps the subscription
+        // receives the following messages, buffered in the meantime, in order.
+        if result.is_some() && !sub.unsubscribed {
+            sub.done = false;
+        }
+        Ok(result)
+    }
+
+    async fn drain(&mut self, this: Resource<Subscription>) -> anyhow::Result<Vec<String>> {
+        let sub = self.ctx().table.get_mut(&this)?;
+        // A message the pollable already received precedes the ones still in the channel.
+        let mut messages: Vec<String> = sub.result.take().into_iter().collect();
+        while let Ok(message) = sub.receiver.try_recv() {
+            messages.push(message);
+        }
+        if !messages.is_empty() && !sub.unsubscribed {
+            sub.done = false;
+        }
+        Ok(messages)
     }
 
     async fn unsubscribe(&mut self, this: Resource<Subscription>) -> anyhow::Result<()> {
         let sub = self.ctx().table.get_mut(&this)?;
         sub.done = true;
-        let topic = sub.topic.clone();
-        let sub_id = sub.id;
-        PubSubCommand::Unsubscribe { topic, sub_id }.dispatch();
+        sub.unsubscribe();
         Ok(())
     }
 
     async fn drop(&mut self, this: Resource<Subscription>) -> anyhow::Result<()> {
-        self.ctx().table.delete(this)?;
+        // Dropping a subscription that is still waiting (e.g. a timed-out or cancelled
+        // `subscribe` in the guest) must not leave a subscriber registered on the topic.
+        let mut sub = self.ctx().table.delete(this)?;
+        sub.unsubscribe();
         Ok(())
     }
 }
@@ -1,8 +1,11 @@
 use crate::api::core::{DebugQueryResult, Model};
 use crate::api::inferlet;
 use crate::instance::InstanceState;
+use crate::messaging::PubSubCommand;
 use crate::model;
 use crate::service::ServiceCommand;
+use pie_client::message::InstanceStatus;
+use std::collections::HashMap;
 use std::sync::Arc;
 use tokio::sync::oneshot;
 use wasmtime::component::Resource;
@@ -67,4 +70,35 @@ impl inferlet::core::runtime::Host for InstanceState {
         crate::runtime::Command::DebugQuery { query, event: tx }.dispatch();
         Ok(self.ctx().table.push(res)?)
     }
+
+    async fn list_active(
+        &mut self,
+    ) -> anyhow::Result<Vec<inferlet::core::runtime::ActiveInstance>> {
+        let (tx, rx) = oneshot::channel();
+        crate::runtime::Command::ListInstances { event: tx }.dispatch();
+        let instances = rx.await?;
+
+        // The two registries are queried one after the other, so an instance that starts,
+        // finishes or (un)subscribes in between may be reported inconsistently.
+        let (tx, rx) = oneshot::channel();
+        PubSubCommand::ListSubscriptions { response: tx }.dispatch();
+        let mut topics_by_instance: HashMap<String, Vec<String>> = HashMap::new();
+        for (inst_id, topic) in rx.await? {
+            topics_by_instance
+                .entry(inst_id.to_string())
+                .or_default()
+                .push(topic);
+        }
+
+        Ok(instances
+            .into_iter()
+            .filter(|info| !matches!(info.status, InstanceStatus::Finished))
+            .map(|info| inferlet::core::runtime::ActiveInstance {
+                topics: topics_by_instance.remove(&info.id).unwrap_or_default(),
+                id: info.id,
+                cmd_name: info.cmd_name,
+                arguments: info.arguments,
+            })
+            .collect())
+    }
 }
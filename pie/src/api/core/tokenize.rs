@@ -49,6 +49,21 @@ impl inferlet::core::tokenize::HostTokenizer for InstanceState {
         }
     }
 
+    async fn detokenize_skip_special(
+        &mut self,
+        this: Resource<Tokenizer>,
+        tokens: Vec<u32>,
+    ) -> anyhow::Result<String> {
+        let tokenizer = self.ctx().table.get(&this)?;
+        let out = tokenizer.inner.decode_skip_special(&tokens);
+
+        if let Ok(out) = out {
+            Ok(out)
+        } else {
+            bail!("Failed to decode tokens: {:?}", out);
+        }
+    }
+
     async fn get_vocabs(
         &mut self,
         this: Resource<Tokenizer>,
@@ -262,6 +262,7 @@ async fn run_zmq_server(ready_tx: oneshot::Sender<String>) -> Result<()> {
                         tokenizer_special_tokens: HashMap::new(),
                         tokenizer_split_regex: "".to_string(),
                         tokenizer_escape_non_printable: false,
+                        max_context_length: 0,
                     };
                     Bytes::from(rmp_serde::to_vec_named(&response).unwrap())
                 }
@@ -94,11 +94,15 @@ impl ResourceIdMapper {
     }
 
     /// Removes the mappings for the given virtual IDs and releases them back to the pool.
-    fn unmap_resources(&mut self, virtual_ids: &[ResourceId]) {
-        for &virtual_id in virtual_ids {
-            self.virtual_to_physical.remove(&virtual_id);
-        }
+    ///
+    /// Returns the physical IDs the virtual IDs were mapped to, skipping unmapped ones.
+    fn unmap_resources(&mut self, virtual_ids: &[ResourceId]) -> Vec<ResourceId> {
+        let physical_ids = virtual_ids
+            .iter()
+            .filter_map(|virtual_id| self.virtual_to_physical.remove(virtual_id))
+            .collect();
         self.virtual_id_pool.release_many(virtual_ids).unwrap();
+        physical_ids
     }
 
     /// Translates a single virtual ID to its corresponding physical ID.
@@ -204,10 +208,11 @@ impl InstanceState {
         service_id: usize,
         resource_type: ResourceTypeId,
         virt_ids: &[ResourceId],
-    ) {
+    ) -> Vec<ResourceId> {
         let m = self.resources.get_mut(&(service_id, resource_type));
-        if let Some(m) = m {
-            m.unmap_resources(virt_ids);
+        match m {
+            Some(m) => m.unmap_resources(virt_ids),
+            None => Vec::new(),
         }
     }
 
@@ -447,3 +452,22 @@ impl AsyncWrite for LogStream {
         Poll::Ready(Ok(()))
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn unmapping_returns_physical_ids() {
+        let mut mapper = ResourceIdMapper::default();
+        let virtual_ids = mapper.map_resources(&[40, 41, 42]);
+        assert_ne!(virtual_ids, vec![40, 41, 42]);
+
+        assert_eq!(
+            mapper.unmap_resources(&[virtual_ids[2], virtual_ids[0]]),
+            vec![42, 40]
+        );
+        assert_eq!(mapper.translate(virtual_ids[0]), None);
+        assert_eq!(mapper.translate(virtual_ids[1]), Some(41));
+    }
+}
@@ -1,5 +1,6 @@
 use super::service::{CommandDispatcher, Service, ServiceCommand};
 use dashmap::DashMap;
+use std::collections::HashMap;
 use std::sync::{Arc, OnceLock};
 use tokio::sync::oneshot;
 
@@ -23,9 +24,19 @@ pub enum Command {
         key: String,
         response: oneshot::Sender<Option<String>>,
     },
+    /// Retrieves the values associated with several keys.
+    /// The result is sent back as a `Vec<Option<String>>` in the order of `keys`.
+    GetMany {
+        keys: Vec<String>,
+        response: oneshot::Sender<Vec<Option<String>>>,
+    },
     /// Inserts or updates a key-value pair.
     /// The `oneshot::Sender` is used to signal completion.
     Set { key: String, value: String },
+    /// Inserts or updates several key-value pairs as a single command.
+    /// Since commands are handled sequentially, no other command observes a
+    /// partially applied batch.
+    SetBatch { entries: Vec<(String, String)> },
     /// Removes a key-value pair.
     /// The `oneshot::Sender` is used to signal completion.
     Delete { key: String },
@@ -40,6 +51,21 @@ pub enum Command {
     ListKeys {
         response: oneshot::Sender<Vec<String>>,
     },
+    /// Takes up to `amount` from the unsigned integer stored under a key.
+    /// The amount taken is sent back as a `u64`; since commands are handled
+    /// sequentially, concurrent takes never hand out more than the value held.
+    Take {
+        key: String,
+        amount: u64,
+        response: oneshot::Sender<u64>,
+    },
+    /// Waits for a key to hold a value.
+    /// The current value is sent back right away if the key exists; otherwise the
+    /// value of the next `Set` or `SetBatch` of the key is.
+    Watch {
+        key: String,
+        response: oneshot::Sender<String>,
+    },
 }
 
 impl ServiceCommand for Command {
@@ -50,9 +76,11 @@ impl ServiceCommand for Command {
 ///
 /// It uses a `DashMap` for concurrent, lock-free reads and writes,
 /// making it suitable for a multi-threaded, asynchronous environment.
-#[derive(Debug, Clone)]
+#[derive(Debug)]
 struct KeyValueStore {
     store: Arc<DashMap<String, String>>,
+    /// Pending `Watch` commands, keyed by the key they wait for.
+    watchers: HashMap<String, Vec<oneshot::Sender<String>>>,
 }
 
 impl KeyValueStore {
@@ -60,7 +88,27 @@ impl KeyValueStore {
     fn new() -> Self {
         KeyValueStore {
             store: Arc::new(DashMap::new()),
+            watchers: HashMap::new(),
+        }
+    }
+
+    /// Stores a value and resolves the watchers of its key.
+    fn insert(&mut self, key: String, value: String) {
+        if let Some(watchers) = self.watchers.remove(&key) {
+            for watcher in watchers {
+                let _ = watcher.send(value.clone());
+            }
         }
+        self.store.insert(key, value);
+    }
+
+    /// Forgets the watches whose resource the inferlet has already dropped, so keys that
+    /// are never set do not accumulate them.
+    fn prune_watchers(&mut self) {
+        self.watchers.retain(|_, watchers| {
+            watchers.retain(|watcher| !watcher.is_closed());
+            !watchers.is_empty()
+        });
     }
 }
 
@@ -68,13 +116,26 @@ impl Service for KeyValueStore {
     type Command = Command;
 
     async fn handle(&mut self, cmd: Self::Command) {
+        self.prune_watchers();
         match cmd {
             Command::Get { key, response } => {
                 let value = self.store.get(&key).map(|v| v.value().clone());
                 let _ = response.send(value);
             }
+            Command::GetMany { keys, response } => {
+                let values = keys
+                    .iter()
+                    .map(|key| self.store.get(key).map(|v| v.value().clone()))
+                    .collect();
+                let _ = response.send(values);
+            }
             Command::Set { key, value } => {
-                self.store.insert(key, value);
+                self.insert(key, value);
+            }
+            Command::SetBatch { entries } => {
+                for (key, value) in entries {
+                    self.insert(key, value);
+                }
             }
             Command::Delete { key } => {
                 self.store.remove(&key);
@@ -88,6 +149,80 @@ impl Service for KeyValueStore {
                     self.store.iter().map(|entry| entry.key().clone()).collect();
                 let _ = response.send(keys);
             }
+            Command::Take {
+                key,
+                amount,
+                response,
+            } => {
+                let held = self
+                    .store
+                    .get(&key)
+                    .and_then(|v| v.value().trim().parse::<u64>().ok())
+                    .unwrap_or(0);
+                let taken = amount.min(held);
+                if taken > 0 {
+                    self.insert(key, (held - taken).to_string());
+                }
+                let _ = response.send(taken);
+            }
+            Command::Watch { key, response } => {
+                if let Some(value) = self.store.get(&key).map(|v| v.value().clone()) {
+                    let _ = response.send(value);
+                } else {
+                    self.watchers.entry(key).or_default().push(response);
+                }
+            }
         }
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn closed_watchers_are_pruned() {
+        let mut kvs = KeyValueStore::new();
+        let (open, _open_rx) = oneshot::channel();
+        let (closed, closed_rx) = oneshot::channel();
+        kvs.watchers.insert("a".to_string(), vec![open]);
+        kvs.watchers.insert("b".to_string(), vec![closed]);
+        drop(closed_rx);
+
+        kvs.prune_watchers();
+        assert_eq!(kvs.watchers.len(), 1);
+        assert_eq!(kvs.watchers["a"].len(), 1);
+    }
+
+    #[tokio::test]
+    async fn agents_sharing_a_budget_stop_at_its_limit() {
+        let mut kvs = KeyValueStore::new();
+        kvs.insert("run_budget".to_string(), "5".to_string());
+
+        // Two agents each take one token per decode step, interleaved, until one of
+        // their takes comes back empty.
+        let mut generated = [0, 0];
+        let mut done = [false, false];
+        while !done.iter().all(|&done| done) {
+            for agent in 0..2 {
+                if done[agent] {
+                    continue;
+                }
+                let (response, taken) = oneshot::channel();
+                kvs.handle(Command::Take {
+                    key: "run_budget".to_string(),
+                    amount: 1,
+                    response,
+                })
+                .await;
+                match taken.await.unwrap() {
+                    0 => done[agent] = true,
+                    taken => generated[agent] += taken,
+                }
+            }
+        }
+
+        assert_eq!(generated, [3, 2]);
+        assert_eq!(kvs.store.get("run_budget").unwrap().value(), "0");
+    }
+}
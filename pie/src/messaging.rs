@@ -1,8 +1,9 @@
+use super::instance::InstanceId;
 use super::service::{CommandDispatcher, Service, ServiceCommand};
 use super::utils::IdPool;
 use bytes::Bytes;
 use dashmap::DashMap;
-use std::collections::VecDeque;
+use std::collections::{HashMap, VecDeque};
 use std::sync::{Arc, OnceLock};
 use tokio::sync::mpsc::{self, UnboundedReceiver, UnboundedSender};
 use tokio::sync::oneshot;
@@ -35,15 +36,30 @@ pub enum PubSubCommand {
     //     inst_id: InstanceId,
     // },
     /// Broadcast a message to all subscribers of a topic.
+    ///
+    /// Messages of a topic are delivered to every subscriber in the order the commands
+    /// are handled, so one publisher's messages arrive in the order it sent them.
     Publish { topic: String, message: String },
+    /// Broadcast a message prefixed with `"{seq}:"`, where `seq` is the topic's next
+    /// sequence number (starting at 1), and return the number via the oneshot.
+    PublishOrdered {
+        topic: String,
+        message: String,
+        seq: oneshot::Sender<u64>,
+    },
     /// Subscribe to a topic using a sender; returns a subscription id via the oneshot.
     Subscribe {
         topic: String,
+        inst_id: InstanceId,
         sender: mpsc::Sender<String>,
         sub_id: oneshot::Sender<ListenerId>,
     },
     /// Unsubscribe from a topic using the subscription id.
     Unsubscribe { topic: String, sub_id: ListenerId },
+    /// Report the topic of every live subscription together with its instance.
+    ListSubscriptions {
+        response: oneshot::Sender<Vec<(InstanceId, String)>>,
+    },
 }
 
 impl ServiceCommand for PubSubCommand {
@@ -83,6 +99,10 @@ pub struct PubSub {
     event_loop_handle: tokio::task::JoinHandle<()>,
     subscribers_by_topic: Arc<DashMap<String, Vec<(ListenerId, mpsc::Sender<String>)>>>,
     sub_id_pool: IdPool<ListenerId>,
+    /// The last sequence number assigned by `PublishOrdered`, per topic.
+    last_seq_by_topic: HashMap<String, u64>,
+    /// The instance that holds each subscription.
+    instance_by_sub: HashMap<ListenerId, InstanceId>,
 }
 
 impl PubSub {
@@ -97,6 +117,8 @@ impl PubSub {
             event_loop_handle,
             subscribers_by_topic,
             sub_id_pool: IdPool::new(ListenerId::MAX),
+            last_seq_by_topic: HashMap::new(),
+            instance_by_sub: HashMap::new(),
         }
     }
 
@@ -150,13 +172,28 @@ impl Service for PubSub {
                 // Broadcast the message.
                 self.tx.send((topic, message)).unwrap();
             }
+            PubSubCommand::PublishOrdered {
+                topic,
+                message,
+                seq,
+            } => {
+                let last_seq = self.last_seq_by_topic.entry(topic.clone()).or_insert(0);
+                *last_seq += 1;
+                let next_seq = *last_seq;
+                self.tx
+                    .send((topic, format!("{}:{}", next_seq, message)))
+                    .unwrap();
+                let _ = seq.send(next_seq);
+            }
             PubSubCommand::Subscribe {
                 topic,
+                inst_id,
                 sender,
                 sub_id,
             } => {
                 // Acquire a new subscription id.
                 let id = self.sub_id_pool.acquire().unwrap();
+                self.instance_by_sub.insert(id, inst_id);
 
                 // Insert the new subscriber into the map.
                 self.subscribers_by_topic
@@ -178,8 +215,25 @@ impl Service for PubSub {
                     }
                 }
                 // Release the subscription id back to the pool.
+                self.instance_by_sub.remove(&sub_id);
                 self.sub_id_pool.release(sub_id).unwrap();
             }
+            PubSubCommand::ListSubscriptions { response } => {
+                let subscriptions = self
+                    .subscribers_by_topic
+                    .iter()
+                    .flat_map(|entry| {
+                        entry
+                            .value()
+                            .iter()
+                            .filter(|(_, sender)| !sender.is_closed())
+                            .filter_map(|(id, _)| self.instance_by_sub.get(id))
+                            .map(|inst_id| (*inst_id, entry.key().clone()))
+                            .collect::<Vec<_>>()
+                    })
+                    .collect();
+                let _ = response.send(subscriptions);
+            }
         }
     }
 }
@@ -367,3 +421,117 @@ impl Service for PushPull {
         }
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::time::Duration;
+    use tokio::time::timeout;
+    use uuid::Uuid;
+
+    async fn subscribe(pubsub: &mut PubSub, topic: &str) -> (ListenerId, mpsc::Receiver<String>) {
+        let (sender, receiver) = mpsc::channel(64);
+        let (sub_id, id_rx) = oneshot::channel();
+        pubsub
+            .handle(PubSubCommand::Subscribe {
+                topic: topic.to_string(),
+                inst_id: Uuid::new_v4(),
+                sender,
+                sub_id,
+            })
+            .await;
+        (id_rx.await.unwrap(), receiver)
+    }
+
+    async fn publish(pubsub: &mut PubSub, topic: &str, message: &str) {
+        pubsub
+            .handle(PubSubCommand::Publish {
+                topic: topic.to_string(),
+                message: message.to_string(),
+            })
+            .await;
+    }
+
+    async fn recv(receiver: &mut mpsc::Receiver<String>) -> String {
+        timeout(Duration::from_secs(1), receiver.recv())
+            .await
+            .expect("no message was delivered")
+            .unwrap()
+    }
+
+    #[tokio::test]
+    async fn interleaved_ordered_publishes_arrive_in_sequence() {
+        let mut pubsub = PubSub::new();
+        let (_, mut receiver) = subscribe(&mut pubsub, "t").await;
+
+        // Two publishers send their commands concurrently, as two instances would.
+        let (tx, mut rx) = mpsc::unbounded_channel();
+        let publishers = ["a", "b"].map(|name| {
+            let tx = tx.clone();
+            tokio::spawn(async move {
+                let mut seqs = Vec::new();
+                for i in 0..5 {
+                    let (seq, seq_rx) = oneshot::channel();
+                    tx.send(PubSubCommand::PublishOrdered {
+                        topic: "t".to_string(),
+                        message: format!("{}{}", name, i),
+                        seq,
+                    })
+                    .unwrap();
+                    seqs.push(seq_rx.await.unwrap());
+                }
+                seqs
+            })
+        });
+        drop(tx);
+        while let Some(cmd) = rx.recv().await {
+            pubsub.handle(cmd).await;
+        }
+
+        let mut delivered = Vec::new();
+        for _ in 0..10 {
+            let message = recv(&mut receiver).await;
+            let (seq, body) = message.split_once(':').unwrap();
+            delivered.push((seq.parse::<u64>().unwrap(), body.to_string()));
+        }
+        assert_eq!(
+            delivered.iter().map(|(seq, _)| *seq).collect::<Vec<_>>(),
+            (1..=10).collect::<Vec<_>>()
+        );
+        // Each publisher's messages arrive in the order it sent them, under the sequence
+        // numbers it was given.
+        for (name, publisher) in ["a", "b"].into_iter().zip(publishers) {
+            let seqs = publisher.await.unwrap();
+            let own = delivered
+                .iter()
+                .filter(|(_, body)| body.starts_with(name))
+                .collect::<Vec<_>>();
+            assert_eq!(own.iter().map(|(seq, _)| *seq).collect::<Vec<_>>(), seqs);
+            for (i, (_, body)) in own.iter().enumerate() {
+                assert_eq!(*body, format!("{}{}", name, i));
+            }
+        }
+    }
+
+    #[tokio::test]
+    async fn dropped_subscription_does_not_take_a_new_subscribers_message() {
+        let mut pubsub = PubSub::new();
+        let (old_id, old_receiver) = subscribe(&mut pubsub, "t").await;
+        // A dropped guest subscription closes its receiver before its unsubscribe
+        // command is handled.
+        drop(old_receiver);
+
+        let (_, mut receiver) = subscribe(&mut pubsub, "t").await;
+        publish(&mut pubsub, "t", "first").await;
+        assert_eq!(recv(&mut receiver).await, "first");
+
+        pubsub
+            .handle(PubSubCommand::Unsubscribe {
+                topic: "t".to_string(),
+                sub_id: old_id,
+            })
+            .await;
+        publish(&mut pubsub, "t", "second").await;
+        assert_eq!(recv(&mut receiver).await, "second");
+    }
+}
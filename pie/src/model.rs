@@ -7,7 +7,10 @@ use super::model::batching::{BatchPolicySelector, BatchScheduler, ForwardPassPol
 use super::model::request::{
     FORWARD_PASS_ID, HANDSHAKE_ID, HandshakeRequest, HandshakeResponse, HeartbeatRequest, Request,
 };
-use super::model::resource::{ResourceId, ResourceManager, ResourceTypeId};
+use super::model::resource::{
+    ExportMode, ExportStats, KV_PAGE_TYPE_ID, ResourceError, ResourceId, ResourceManager,
+    ResourceTypeId,
+};
 use super::model::tokenizer::BytePairEncoder;
 use super::runtime::{self, TerminationCause};
 use super::service::ServiceCommand;
@@ -216,16 +219,32 @@ pub enum Command {
     Cleanup {
         inst_id: InstanceId,
     },
+    GetExportStats {
+        response: oneshot::Sender<ExportStats>,
+    },
+    ResetExportStats,
     GetAllExported {
         type_id: ResourceTypeId,
         response: oneshot::Sender<Vec<(String, Vec<ResourceId>)>>,
     },
+    GetAvailable {
+        type_id: ResourceTypeId,
+        response: oneshot::Sender<usize>,
+    },
     Export {
         inst_id: InstanceId,
         type_id: ResourceTypeId,
         ptrs: Vec<ResourceId>,
         name: String,
     },
+    ExportWithMode {
+        inst_id: InstanceId,
+        type_id: ResourceTypeId,
+        ptrs: Vec<ResourceId>,
+        name: String,
+        mode: ExportMode,
+        response: oneshot::Sender<Result<(), String>>,
+    },
     Import {
         inst_id: InstanceId,
         type_id: ResourceTypeId,
@@ -266,6 +285,9 @@ pub struct ModelInfo {
     pub tokenizer: Arc<BytePairEncoder>,
     pub kv_page_size: u32,
     pub max_batch_tokens: usize,
+    /// The maximum number of tokens a single context can hold: the model's context
+    /// window as reported by the backend, bounded by the capacity of its KV page pool.
+    pub max_context_length: u32,
 }
 
 /// An in-memory key-value store service.
@@ -329,6 +351,19 @@ impl Model {
             handshake_info.tokenizer_escape_non_printable,
         ));
 
+        // A context can hold no more tokens than the KV page pool, whatever the model's
+        // own context window.
+        let kv_capacity = handshake_info
+            .resources
+            .get(&KV_PAGE_TYPE_ID)
+            .copied()
+            .unwrap_or(0)
+            .saturating_mul(handshake_info.kv_page_size);
+        let max_context_length = match handshake_info.max_context_length {
+            0 => kv_capacity,
+            model_limit => model_limit.min(kv_capacity),
+        };
+
         let info = ModelInfo {
             name: handshake_info.model_name,
             traits: handshake_info.model_traits,
@@ -339,6 +374,7 @@ impl Model {
             tokenizer,
             kv_page_size: handshake_info.kv_page_size,
             max_batch_tokens: handshake_info.max_batch_tokens,
+            max_context_length,
         };
 
         let resource_manager = ResourceManager::new(handshake_info.resources);
@@ -724,12 +760,27 @@ impl Model {
                     terminate_instance_with_exception(inst_id, e);
                 }
             }
+            Command::GetExportStats { response } => {
+                if response.send(self.resource_manager.export_stats()).is_err() {
+                    println!("[Warn] GetExportStats response channel closed before sending.");
+                }
+            }
+            Command::ResetExportStats => {
+                self.resource_manager.reset_export_stats();
+            }
             Command::GetAllExported { type_id, response } => {
                 let list = self.resource_manager.get_all_exported(type_id);
                 if response.send(list).is_err() {
                     println!("[Warn] GetAllExported response channel closed before sending.");
                 }
             }
+            Command::GetAvailable { type_id, response } => {
+                // An unknown resource type has no pool, hence nothing left to allocate.
+                let available = self.resource_manager.available(type_id).unwrap_or(0);
+                if response.send(available).is_err() {
+                    println!("[Warn] GetAvailable response channel closed before sending.");
+                }
+            }
             Command::Export {
                 inst_id,
                 type_id,
@@ -740,12 +791,36 @@ impl Model {
                     terminate_instance_with_exception(inst_id, e);
                 }
             }
+            Command::ExportWithMode {
+                inst_id,
+                type_id,
+                ptrs,
+                name,
+                mode,
+                response,
+            } => {
+                let result = match self
+                    .resource_manager
+                    .export_with_mode(inst_id, type_id, ptrs, name, mode)
+                {
+                    Ok(()) => Ok(()),
+                    // A taken name is reported back to the caller instead of terminating it.
+                    Err(e @ ResourceError::ExportNameExists { .. }) => Err(e.to_string()),
+                    Err(e) => {
+                        terminate_instance_with_exception(inst_id, e);
+                        return;
+                    }
+                };
+                if response.send(result).is_err() {
+                    println!("[Warn] ExportWithMode response channel closed before sending.");
+                }
+            }
             Command::Import {
                 inst_id,
                 type_id,
                 name,
                 response,
-            } => match self.resource_manager.import(type_id, name) {
+            } => match self.resource_manager.import(inst_id, type_id, name) {
                 Ok(ptrs) => {
                     if response.send(ptrs).is_err() {
                         println!("[Warn] Import response channel closed before sending.");
@@ -13,6 +13,7 @@ pub static INITIALIZE_ADAPTER_ID: u32 = 5;
 pub static UPDATE_ADAPTER_ID: u32 = 6;
 pub static UPLOAD_ADAPTER_ID: u32 = 7;
 pub static DOWNLOAD_ADAPTER_ID: u32 = 8;
+pub static COPY_KV_PAGES_ID: u32 = 9;
 
 #[derive(Debug)]
 pub enum Request {
@@ -29,6 +30,7 @@ pub enum Request {
     UpdateAdapter(UpdateAdapterRequest),
     UploadAdapter(UploadAdapterRequest),
     DownloadAdapter(DownloadAdapterRequest, oneshot::Sender<Bytes>),
+    CopyKvPages(CopyKvPagesRequest),
 }
 
 impl Request {
@@ -68,6 +70,7 @@ impl Request {
             Request::UpdateAdapter(_) => UPDATE_ADAPTER_ID,
             Request::UploadAdapter(_) => UPLOAD_ADAPTER_ID,
             Request::DownloadAdapter(_, _) => DOWNLOAD_ADAPTER_ID,
+            Request::CopyKvPages(_) => COPY_KV_PAGES_ID,
         }
     }
 
@@ -83,6 +86,7 @@ impl Request {
             Request::UpdateAdapter(req) => Bytes::from(rmp_serde::to_vec_named(&req)?),
             Request::UploadAdapter(req) => Bytes::from(rmp_serde::to_vec_named(&req)?),
             Request::DownloadAdapter(req, _) => Bytes::from(rmp_serde::to_vec_named(&req)?),
+            Request::CopyKvPages(req) => Bytes::from(rmp_serde::to_vec_named(&req)?),
         };
         Ok(b)
     }
@@ -136,6 +140,10 @@ pub struct HandshakeResponse {
     pub tokenizer_special_tokens: HashMap<String, u32>,
     pub tokenizer_split_regex: String,
     pub tokenizer_escape_non_printable: bool,
+    /// The maximum number of tokens a single context may hold, or 0 if the backend does
+    /// not know it. Backends that predate the field leave it out.
+    #[serde(default)]
+    pub max_context_length: u32,
 }
 
 #[derive(Debug, Serialize, Deserialize)]
@@ -183,6 +191,12 @@ pub struct EmbedImageRequest {
     pub position_offset: u32,
 }
 
+#[derive(Debug, Serialize, Deserialize)]
+pub struct CopyKvPagesRequest {
+    pub src_kv_page_ptrs: Vec<u32>,
+    pub dst_kv_page_ptrs: Vec<u32>,
+}
+
 #[derive(Debug, Serialize, Deserialize)]
 pub struct InitializeAdapterRequest {
     pub adapter_ptr: u32,
@@ -46,12 +46,43 @@ pub enum ResourceError {
     IdPoolError(String),
 }
 
+/// How an export treats a name that is already taken.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ExportMode {
+    /// Keep the existing export and fail with `ExportNameExists`.
+    FailIfExists,
+    /// Release the existing export and replace it.
+    Overwrite,
+    /// Keep the existing export and succeed without exporting the new pointers.
+    Dedup,
+}
+
+/// Counters of how often exported KV prefixes are looked up and released.
+#[derive(Debug, Clone, Copy, Default)]
+pub struct ExportStats {
+    pub hits: u64,
+    pub misses: u64,
+    pub evictions: u64,
+}
+
+/// An export whose name has been released while live instances still hold imports of it.
+/// Its pointers are returned to the pool once the last holder is cleaned up.
+#[derive(Debug)]
+struct PendingRelease {
+    type_id: ResourceTypeId,
+    ptrs: Vec<ResourceId>,
+    holders: HashSet<InstanceId>,
+}
+
 /// Manages the state of all resources, instances, and exports.
 #[derive(Debug)]
 pub struct ResourceManager {
     res_pool: HashMap<ResourceTypeId, IdPool<u32>>,
     res_exported: HashMap<ResourceTypeId, HashMap<String, Vec<ResourceId>>>,
     res_allocated: HashMap<(ResourceTypeId, InstanceId), HashSet<ResourceId>>,
+    res_importers: HashMap<(ResourceTypeId, String), HashSet<InstanceId>>,
+    res_pending_release: Vec<PendingRelease>,
+    export_stats: ExportStats,
     inst_start_time: HashMap<InstanceId, Instant>,
 }
 
@@ -66,6 +97,9 @@ impl ResourceManager {
             res_pool,
             res_exported: HashMap::new(),
             res_allocated: HashMap::new(),
+            res_importers: HashMap::new(),
+            res_pending_release: Vec::new(),
+            export_stats: ExportStats::default(),
             inst_start_time: HashMap::new(),
         }
     }
@@ -86,7 +120,8 @@ impl ResourceManager {
         self.allocate(inst_id, type_id, count)
     }
 
-    fn available(&self, type_id: ResourceTypeId) -> Result<usize, ResourceError> {
+    /// Returns how many resources of `type_id` can be allocated without evicting anyone.
+    pub fn available(&self, type_id: ResourceTypeId) -> Result<usize, ResourceError> {
         let pool = self
             .res_pool
             .get(&type_id)
@@ -216,11 +251,69 @@ impl ResourceManager {
             }
         }
         self.inst_start_time.remove(&inst_id);
+
+        // The instance no longer holds any imported pointers.
+        self.res_importers.retain(|_, holders| {
+            holders.remove(&inst_id);
+            !holders.is_empty()
+        });
+
+        // Free released exports whose last holder was this instance.
+        let mut still_pending = Vec::new();
+        for mut pending in std::mem::take(&mut self.res_pending_release) {
+            pending.holders.remove(&inst_id);
+            if pending.holders.is_empty() {
+                self.release_ptrs(pending.type_id, pending.ptrs)?;
+            } else {
+                still_pending.push(pending);
+            }
+        }
+        self.res_pending_release = still_pending;
+        Ok(())
+    }
+
+    fn release_ptrs(
+        &mut self,
+        type_id: ResourceTypeId,
+        ptrs: Vec<ResourceId>,
+    ) -> Result<(), ResourceError> {
+        let pool = self
+            .res_pool
+            .get_mut(&type_id)
+            .ok_or(ResourceError::PoolNotFound { type_id })?;
+        for ptr in ptrs {
+            pool.release(ptr).unwrap();
+        }
         Ok(())
     }
 
     // --- export, import, release_exported, and get_all_exported methods ---
     // These are moved here from Model with minimal changes, now returning ResourceError
+    pub fn export_with_mode(
+        &mut self,
+        inst_id: InstanceId,
+        type_id: ResourceTypeId,
+        ptrs: Vec<ResourceId>,
+        name: String,
+        mode: ExportMode,
+    ) -> Result<(), ResourceError> {
+        let exists = self
+            .res_exported
+            .get(&type_id)
+            .is_some_and(|exports| exports.contains_key(&name));
+
+        if exists {
+            match mode {
+                ExportMode::FailIfExists => return Err(ResourceError::ExportNameExists { name }),
+                // The caller keeps its pointers; they are freed when it deallocates them.
+                ExportMode::Dedup => return Ok(()),
+                ExportMode::Overwrite => self.release_exported(type_id, name.clone())?,
+            }
+        }
+
+        self.export(inst_id, type_id, ptrs, name)
+    }
+
     pub fn export(
         &mut self,
         inst_id: InstanceId,
@@ -256,16 +349,31 @@ impl ResourceManager {
 
     pub fn import(
         &mut self,
+        inst_id: InstanceId,
         type_id: ResourceTypeId,
         name: String,
     ) -> Result<Vec<ResourceId>, ResourceError> {
-        self.res_exported
+        let Some(ptrs) = self
+            .res_exported
             .get(&type_id)
             .and_then(|exports| exports.get(&name))
             .cloned()
-            .ok_or(ResourceError::ExportNotFound { name })
+        else {
+            self.export_stats.misses += 1;
+            return Err(ResourceError::ExportNotFound { name });
+        };
+        self.export_stats.hits += 1;
+
+        self.res_importers
+            .entry((type_id, name))
+            .or_default()
+            .insert(inst_id);
+
+        Ok(ptrs)
     }
 
+    /// Removes an export. If live instances still hold imports of it, the pointers are
+    /// only returned to the pool after the last of those instances is cleaned up.
     pub fn release_exported(
         &mut self,
         type_id: ResourceTypeId,
@@ -276,17 +384,21 @@ impl ResourceManager {
             .get_mut(&type_id)
             .ok_or(ResourceError::PoolNotFound { type_id })?;
 
-        if let Some(ptrs_to_release) = type_exports.remove(&name) {
-            let pool = self
-                .res_pool
-                .get_mut(&type_id)
-                .ok_or(ResourceError::PoolNotFound { type_id })?;
-            for ptr in ptrs_to_release {
-                pool.release(ptr).unwrap();
+        let Some(ptrs_to_release) = type_exports.remove(&name) else {
+            return Err(ResourceError::ExportNotFound { name });
+        };
+        self.export_stats.evictions += 1;
+
+        match self.res_importers.remove(&(type_id, name)) {
+            Some(holders) => {
+                self.res_pending_release.push(PendingRelease {
+                    type_id,
+                    ptrs: ptrs_to_release,
+                    holders,
+                });
+                Ok(())
             }
-            Ok(())
-        } else {
-            Err(ResourceError::ExportNotFound { name })
+            None => self.release_ptrs(type_id, ptrs_to_release),
         }
     }
 
@@ -302,6 +414,14 @@ impl ResourceManager {
             .unwrap_or_default()
     }
 
+    pub fn export_stats(&self) -> ExportStats {
+        self.export_stats
+    }
+
+    pub fn reset_export_stats(&mut self) {
+        self.export_stats = ExportStats::default();
+    }
+
     /// Appends detailed statistics about the resource manager's state to a given HashMap.
     pub fn append_stats_to(&self, stats: &mut HashMap<String, String>) {
         // Report on each resource pool
@@ -334,5 +454,21 @@ impl ResourceManager {
                 exports.len().to_string(),
             );
         }
+        stats.insert(
+            "resource.pending_release_count".to_string(),
+            self.res_pending_release.len().to_string(),
+        );
+        stats.insert(
+            "resource.export_hits".to_string(),
+            self.export_stats.hits.to_string(),
+        );
+        stats.insert(
+            "resource.export_misses".to_string(),
+            self.export_stats.misses.to_string(),
+        );
+        stats.insert(
+            "resource.export_evictions".to_string(),
+            self.export_stats.evictions.to_string(),
+        );
     }
 }
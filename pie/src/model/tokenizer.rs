@@ -125,6 +125,17 @@ impl BytePairEncoder {
         Ok(String::from_utf8_lossy(&*decoded_bytes).to_string())
     }
 
+    /// Like `decode`, but leaves special tokens, such as role markers and
+    /// end-of-sequence, out of the string.
+    pub fn decode_skip_special(&self, tokens: &[Rank]) -> Result<String, DecodeError> {
+        let tokens = tokens
+            .iter()
+            .copied()
+            .filter(|&token| !self.is_special_token(token))
+            .collect::<Vec<_>>();
+        self.decode(&tokens)
+    }
+
     fn decode_bytes(&self, tokens: &[Rank]) -> Result<Vec<u8>, DecodeKeyError> {
         let mut ret = Vec::with_capacity(tokens.len() * 2);
         for &token in tokens {
@@ -246,6 +257,11 @@ impl BytePairEncoder {
         }
     }
 
+    /// Whether `token` is a special token, such as a role marker or end-of-sequence.
+    pub fn is_special_token(&self, token: Rank) -> bool {
+        self.special_tokens_decoder.contains_key(&token)
+    }
+
     pub fn special_tokens(&self) -> HashSet<&str> {
         self.special_tokens_encoder
             .keys()
@@ -380,3 +396,30 @@ pub fn unescape_non_printable(s: &str) -> Option<Vec<u8>> {
     }
     Some(out)
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn skipping_special_tokens_drops_a_visible_eos() {
+        let decoder = [(0, "Hi"), (1, " there"), (2, "!")]
+            .into_iter()
+            .map(|(rank, piece)| (rank, piece.as_bytes().to_vec()))
+            .collect();
+        let special = HashMap::from([("<|eot_id|>".to_string(), 3)]);
+        let tokenizer = BytePairEncoder::new(4, decoder, special, r"\S+|\s+", false);
+
+        // The EOS token detokenizes to its visible marker...
+        assert_eq!(
+            tokenizer.decode(&[0, 1, 2, 3]).unwrap(),
+            "Hi there!<|eot_id|>"
+        );
+        // ...which is left out when skipping special tokens, unlike ordinary ones.
+        assert_eq!(
+            tokenizer.decode_skip_special(&[0, 1, 2, 3]).unwrap(),
+            "Hi there!"
+        );
+        assert_eq!(tokenizer.decode_skip_special(&[3]).unwrap(), "");
+    }
+}